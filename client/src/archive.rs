@@ -0,0 +1,210 @@
+use serde::{ Serialize, Deserialize };
+use anyhow::{ Context, anyhow };
+use p256::ecdsa::signature::{RandomizedSigner, Verifier};
+use rand::{CryptoRng, RngCore};
+
+use super::crypto;
+use super::passphrase;
+use super::device::DeviceCertificate;
+
+// Version 1 archives didn't carry the signer's certificate.
+const VERSION: u8 = 2;
+// Signatures cover this followed by the contents, so that an archive's signature can't be passed off as anything else.
+const CONTEXT: &[u8] = b"web3.0-test peer archive";
+const PLAIN: u8 = 0;
+const SEALED: u8 = 1;
+
+// Every peer record, exactly as it was stored (so each one carries its own layout version), keyed by peer id.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Contents {
+	pub created: u32,
+	pub records: Vec<(String, String)>
+}
+
+// Archives come from anywhere: decoding fails on a badly encoded signer or signature, and open fails on a signer that isn't on the curve.  A device signs with its own key and includes its certificate, so that the archive can be tied back to its root identity.
+#[derive(Serialize, Deserialize)]
+struct Signed {
+	signer: crypto::PublicKey,
+	contents: Vec<u8>,
+	signature: crypto::Signature,
+	certificate: Option<DeviceCertificate>
+}
+type SignedV1 = (crypto::PublicKey, Vec<u8>, crypto::Signature);
+
+// The key that signed an archive, and the root identity that certified it if it was signed by a device.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signer {
+	pub key: crypto::PublicKey,
+	pub root_key: Option<crypto::PublicKey>
+}
+
+// What importing an archive did.  A conflict is a field that differed between the archive and what we had, where we kept our own value.  Peers that couldn't be saved are left as they were and listed in failed.
+#[derive(Serialize, Debug, Default)]
+pub struct ImportReport {
+	pub added: Vec<String>,
+	pub merged: Vec<String>,
+	pub conflicts: Vec<Conflict>,
+	pub failed: Vec<Failure>
+}
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Conflict {
+	pub peer_id: String,
+	pub field: String
+}
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Failure {
+	pub peer_id: String,
+	pub error: String
+}
+
+fn signing_input(contents: &[u8]) -> Vec<u8> {
+	[CONTEXT, contents].concat()
+}
+
+// Sign the contents with secret_key (passing its certificate if it's a device's key), and encrypt them as well if there's a passphrase.
+pub fn seal(contents: &Contents, secret_key: &crypto::SecretKey, certificate: Option<&DeviceCertificate>, passphrase: Option<&str>, mut rng: impl CryptoRng + RngCore) -> Result<String, anyhow::Error> {
+	if certificate.is_some_and(|certificate| certificate.device_key.compress() != secret_key.public_key().compress()) {
+		return Err(anyhow!("Device certificate is for a different key."));
+	}
+	let contents = bincode::serialize(contents).context("Serialization Failed.")?;
+	let signature = secret_key.signing_key().sign_with_rng(&mut rng, &signing_input(&contents)).into();
+	let signed = bincode::serialize(&Signed {
+		signer: secret_key.public_key(),
+		contents,
+		signature,
+		certificate: certificate.cloned()
+	}).context("Serialization Failed.")?;
+	let mut buffer = vec![VERSION];
+	if let Some(passphrase) = passphrase {
+		buffer.push(SEALED);
+		let sealed = passphrase::Sealed::seal(&signed, passphrase, CONTEXT)?;
+		buffer.extend_from_slice(&bincode::serialize(&sealed).context("Serialization Failed.")?);
+	} else {
+		buffer.push(PLAIN);
+		buffer.extend_from_slice(&signed);
+	}
+	Ok(base64::encode_config(&buffer, base64::URL_SAFE_NO_PAD))
+}
+// Check an archive's signature (and certificate, if any) and return its contents along with who signed it.  Whether to trust them is up to the caller.
+pub fn open(encoded: &str, passphrase: Option<&str>) -> Result<(Contents, Signer), anyhow::Error> {
+	let buffer = base64::decode_config(encoded.trim(), base64::URL_SAFE_NO_PAD).context("Archive isn't Base64 encoded.")?;
+	let (version, kind, buffer) = match buffer.as_slice() {
+		[version, kind, rest @ ..] => (*version, *kind, rest),
+		_ => return Err(anyhow!("Archive is empty."))
+	};
+	if version != VERSION && version != 1 {
+		return Err(anyhow!("Unsupported archive version: {}", version));
+	}
+	let signed = match (kind, passphrase) {
+		(PLAIN, _) => buffer.to_vec(),
+		(SEALED, Some(passphrase)) => {
+			let sealed: passphrase::Sealed = bincode::deserialize(buffer).context("Archive is corrupted.")?;
+			sealed.open(passphrase, CONTEXT)?.to_vec()
+		},
+		(SEALED, None) => return Err(anyhow!("Archive is encrypted - a passphrase is needed to open it.")),
+		_ => return Err(anyhow!("Archive is corrupted."))
+	};
+	let signed: Signed = if version == 1 {
+		let (signer, contents, signature): SignedV1 = bincode::deserialize(&signed).context("Archive is corrupted.")?;
+		Signed { signer, contents, signature, certificate: None }
+	} else {
+		bincode::deserialize(&signed).context("Archive is corrupted.")?
+	};
+	signed.signer.verifying_key()?.verify(&signing_input(&signed.contents), &signed.signature)
+		.map_err(|_| anyhow!("Archive's signature is invalid."))?;
+	let root_key = match signed.certificate {
+		Some(certificate) => {
+			if certificate.device_key.compress() != signed.signer.compress() {
+				return Err(anyhow!("Archive's device certificate is for a different key."));
+			}
+			certificate.verify()?;
+			Some(certificate.root_key)
+		},
+		None => None
+	};
+	let contents = bincode::deserialize(&signed.contents).context("Archive contents are corrupted.")?;
+	Ok((contents, Signer { key: signed.signer, root_key }))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn contents() -> Contents {
+		Contents {
+			created: 1_601_337_600,
+			records: vec![(String::from("A1b2"), String::from("v3.4:cGVlcg"))]
+		}
+	}
+
+	#[test]
+	fn signed() {
+		let key = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let archive = seal(&contents(), &key, None, None, rand::thread_rng()).unwrap();
+		let (opened, signer) = open(&archive, None).unwrap();
+		assert_eq!(opened, contents());
+		assert_eq!(signer, Signer { key: key.public_key(), root_key: None });
+
+		// Flip a bit in the signed contents:
+		let mut buffer = base64::decode_config(&archive, base64::URL_SAFE_NO_PAD).unwrap();
+		let last = buffer.len() - 70;
+		buffer[last] ^= 1;
+		assert!(open(&base64::encode_config(&buffer, base64::URL_SAFE_NO_PAD), None).is_err());
+	}
+	#[test]
+	fn corrupted_signer() {
+		let key = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let archive = seal(&contents(), &key, None, None, rand::thread_rng()).unwrap();
+		let buffer = base64::decode_config(&archive, base64::URL_SAFE_NO_PAD).unwrap();
+		// The signer follows the version, the kind and its own length.
+		let signer = 2 + 8;
+		for (offset, value) in [(0, 7), (1, 1), (64, 0xff)].iter() {
+			let mut buffer = buffer.clone();
+			buffer[signer + offset] ^= value;
+			assert!(open(&base64::encode_config(&buffer, base64::URL_SAFE_NO_PAD), None).is_err());
+		}
+		// A signer that's too short for a key:
+		let mut buffer = buffer.clone();
+		buffer[2] = 3;
+		assert!(open(&base64::encode_config(&buffer, base64::URL_SAFE_NO_PAD), None).is_err());
+	}
+	#[test]
+	fn encrypted() {
+		let key = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let archive = seal(&contents(), &key, None, Some("correct horse battery staple"), rand::thread_rng()).unwrap();
+		assert!(open(&archive, None).is_err());
+		assert!(open(&archive, Some("wrong passphrase")).is_err());
+		let (opened, signer) = open(&archive, Some("correct horse battery staple")).unwrap();
+		assert_eq!(opened, contents());
+		assert_eq!(signer.key, key.public_key());
+	}
+	#[test]
+	fn certified() {
+		let root = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let device = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let other = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let certificate = DeviceCertificate::issue(&root, device.public_key(), rand::thread_rng());
+		let archive = seal(&contents(), &device, Some(&certificate), None, rand::thread_rng()).unwrap();
+		let (opened, signer) = open(&archive, None).unwrap();
+		assert_eq!(opened, contents());
+		assert_eq!(signer, Signer { key: device.public_key(), root_key: Some(root.public_key()) });
+
+		// Someone else's certificate:
+		assert!(seal(&contents(), &other, Some(&certificate), None, rand::thread_rng()).is_err());
+		let mut forged = certificate.clone();
+		forged.root_key = other.public_key();
+		let archive = seal(&contents(), &device, Some(&forged), None, rand::thread_rng()).unwrap();
+		assert!(open(&archive, None).is_err());
+	}
+	#[test]
+	fn version_1() {
+		let key = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let encoded = bincode::serialize(&contents()).unwrap();
+		let signature: crypto::Signature = key.signing_key().sign_with_rng(rand::thread_rng(), &signing_input(&encoded)).into();
+		let mut buffer = vec![1, PLAIN];
+		buffer.extend_from_slice(&bincode::serialize(&(key.public_key(), encoded, signature)).unwrap());
+		let (opened, signer) = open(&base64::encode_config(&buffer, base64::URL_SAFE_NO_PAD), None).unwrap();
+		assert_eq!(opened, contents());
+		assert_eq!(signer, Signer { key: key.public_key(), root_key: None });
+	}
+}
@@ -0,0 +1,219 @@
+use serde::{ Serialize, Deserialize };
+
+use super::crypto;
+use super::web_push::{AuthToken, AuthLifetime, PushInfo, AUTH_SLOT, MAX_AUTH_LIFETIME};
+
+// When push coverage drops below this, it's time to ask the peer for more authorizations.
+pub const RUNNING_LOW: u32 = 24 * 60 * 60;
+// Peers are expected to keep 8-12 authorizations for each other (see signaling-protocol.md).  Anything past that is somebody filling up our storage.
+const MAX_STORED: usize = 12;
+
+// A peer's push authorizations.  Tokens are verified when they arrive, kept sorted by expiration with one token per expiration, and dropped once they've expired.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AuthStore {
+	tokens: Vec<AuthToken>,
+	// The latest that any dropped token was usable until, so that we still know how long a peer has been unreachable once its tokens are gone.
+	usable_until: u32
+}
+impl AuthStore {
+	// Keep the tokens that were signed by the peer for this push info and haven't expired.  A token we hold for the same expiration is only a duplicate if it's good for this push info as well, otherwise it's replaced.  Returns how many were added.
+	pub fn receive(&mut self, tokens: &[AuthToken], info: &PushInfo, signer: &crypto::PublicKey, lifetime: &AuthLifetime, now: u32) -> usize {
+		let horizon = now.saturating_add(MAX_AUTH_LIFETIME + MAX_STORED as u32 * AUTH_SLOT);
+		let verifies = |token: &AuthToken| token.claims(info)
+			.and_then(|claims| claims.verify(&token.signature, signer))
+			.is_ok();
+		let mut added = 0;
+		for token in tokens {
+			if lifetime.usable_until(token.expiration) <= now || token.expiration > horizon {
+				continue;
+			}
+			let held = self.tokens.iter().position(|held| held.expiration == token.expiration);
+			match held {
+				Some(i) if verifies(&self.tokens[i]) => continue,
+				_ if !verifies(token) => continue,
+				Some(i) => self.tokens[i] = token.clone(),
+				None => self.tokens.push(token.clone())
+			}
+			added += 1;
+		}
+		self.tokens.sort_by_key(|token| token.expiration);
+		self.tokens.truncate(MAX_STORED);
+		added
+	}
+	pub fn has_expired(&self, lifetime: &AuthLifetime, now: u32) -> bool {
+		self.tokens.iter().any(|token| lifetime.usable_until(token.expiration) <= now)
+	}
+	// Drop tokens that have expired.  Returns whether anything was dropped.
+	pub fn prune(&mut self, lifetime: &AuthLifetime, now: u32) -> bool {
+		let before = self.tokens.len();
+		let usable_until = &mut self.usable_until;
+		self.tokens.retain(|token| {
+			let until = lifetime.usable_until(token.expiration);
+			if until <= now {
+				*usable_until = (*usable_until).max(until);
+			}
+			until > now
+		});
+		self.tokens.len() != before
+	}
+	// Drop every token, because they can't be used after now (e.g. the push subscription is gone).
+	pub fn clear(&mut self, now: u32) {
+		self.tokens.clear();
+		self.usable_until = self.usable_until.max(now);
+	}
+	pub fn len(&self) -> usize {
+		self.tokens.len()
+	}
+	pub fn tokens(&self) -> &[AuthToken] {
+		&self.tokens
+	}
+	// The latest that any token we've held is (or was) usable until, or 0 if we've never held one.
+	pub fn usable_until(&self, lifetime: &AuthLifetime) -> u32 {
+		self.tokens.last()
+			.map(|token| lifetime.usable_until(token.expiration))
+			.unwrap_or(0)
+			.max(self.usable_until)
+	}
+	// For records from before usable_until was kept: treat the tokens as having been usable until then.
+	pub fn assume_usable_until(&mut self, until: u32) {
+		self.usable_until = self.usable_until.max(until);
+	}
+	// The token that expires soonest among those usable right now.
+	pub fn find(&self, info: &PushInfo, signer: &crypto::PublicKey, lifetime: &AuthLifetime, now: u32) -> Option<&AuthToken> {
+		self.tokens.iter().find(|token| {
+			token.fill_and_check(info, signer, lifetime, now).is_ok()
+		})
+	}
+	// Seconds from now that we can keep pushing without a gap, assuming the tokens were verified on receipt.
+	pub fn coverage(&self, lifetime: &AuthLifetime, now: u32) -> u32 {
+		let mut covered_until = now;
+		for token in &self.tokens {
+			if lifetime.usable_from(token.expiration) > covered_until {
+				break;
+			}
+			covered_until = covered_until.max(lifetime.usable_until(token.expiration));
+		}
+		covered_until - now
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::jwt;
+	use super::super::web_push::ContentEncoding;
+
+	const HOUR: u32 = 60 * 60;
+	const NOW: u32 = 1_601_337_600 + HOUR;
+
+	fn info() -> PushInfo {
+		let secret = p256::SecretKey::random(rand::thread_rng());
+		PushInfo {
+			endpoint: String::from("https://updates.push.services.mozilla.com/wpush/v2/gAAAAABfcDCt"),
+			auth: [7; 16],
+			public_key: p256::EncodedPoint::from_secret_key(&secret, false).into(),
+			encoding: ContentEncoding::Aes128Gcm
+		}
+	}
+	fn token(info: &PushInfo, signer: &crypto::SecretKey, expiration: u32) -> AuthToken {
+		let claims = jwt::Claims {
+			aud: jwt::audience(&info.endpoint).unwrap(),
+			exp: expiration,
+			sub: String::from("mailto:no-reply@example.com")
+		};
+		AuthToken {
+			signature: claims.sign(signer, rand::thread_rng()).unwrap(),
+			expiration: claims.exp,
+			subscriber: claims.sub
+		}
+	}
+	fn slots(info: &PushInfo, signer: &crypto::SecretKey, lifetime: &AuthLifetime, count: u32) -> Vec<AuthToken> {
+		(0..count).map(|slot| token(info, signer, lifetime.expiration(NOW, slot).unwrap())).collect()
+	}
+
+	#[test]
+	fn verified_on_receipt() {
+		let (info, lifetime) = (info(), AuthLifetime::default());
+		let signer = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let mut store = AuthStore::default();
+		let mut tokens = slots(&info, &signer, &lifetime, 2);
+		// Someone else's signature, an expired token, and a duplicate:
+		let other = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		tokens.push(token(&info, &other, lifetime.expiration(NOW, 2).unwrap()));
+		tokens.push(token(&info, &signer, NOW - HOUR));
+		tokens.push(tokens[0].clone());
+		assert_eq!(store.receive(&tokens, &info, &signer.public_key(), &lifetime, NOW), 2);
+		// For a different subscription:
+		let other = PushInfo { endpoint: String::from("https://fcm.googleapis.com/fcm/send/abc"), ..info.clone() };
+		assert_eq!(store.receive(&slots(&info, &signer, &lifetime, 3)[2..], &other, &signer.public_key(), &lifetime, NOW), 0);
+		assert_eq!(store.len(), 2);
+	}
+	#[test]
+	fn new_push_info() {
+		let (info, lifetime) = (info(), AuthLifetime::default());
+		let signer = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let mut store = AuthStore::default();
+		store.receive(&slots(&info, &signer, &lifetime, 3), &info, &signer.public_key(), &lifetime, NOW);
+		// The peer resubscribed and sent authorizations for the same slots:
+		let other = PushInfo { endpoint: String::from("https://fcm.googleapis.com/fcm/send/abc"), ..info.clone() };
+		let tokens = slots(&other, &signer, &lifetime, 3);
+		assert_eq!(store.receive(&tokens, &other, &signer.public_key(), &lifetime, NOW), 3);
+		assert_eq!(store.len(), 3);
+		assert_eq!(store.find(&other, &signer.public_key(), &lifetime, NOW), Some(&tokens[0]));
+		assert_eq!(store.receive(&tokens, &other, &signer.public_key(), &lifetime, NOW), 0);
+	}
+	#[test]
+	fn bounded() {
+		let (info, lifetime) = (info(), AuthLifetime::default());
+		let signer = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let mut store = AuthStore::default();
+		store.receive(&slots(&info, &signer, &lifetime, 20), &info, &signer.public_key(), &lifetime, NOW);
+		assert_eq!(store.len(), MAX_STORED);
+	}
+	#[test]
+	fn prune_and_find() {
+		let (info, lifetime) = (info(), AuthLifetime::default());
+		let signer = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let mut store = AuthStore::default();
+		let tokens = slots(&info, &signer, &lifetime, 3);
+		store.receive(&tokens, &info, &signer.public_key(), &lifetime, NOW);
+		assert_eq!(store.find(&info, &signer.public_key(), &lifetime, NOW), Some(&tokens[0]));
+
+		let later = lifetime.usable_until(tokens[0].expiration);
+		assert!(!store.has_expired(&lifetime, later - 1));
+		assert!(!store.prune(&lifetime, later - 1));
+		assert!(store.has_expired(&lifetime, later));
+		assert!(store.prune(&lifetime, later));
+		assert_eq!(store.len(), 2);
+		assert_eq!(store.find(&info, &signer.public_key(), &lifetime, later), Some(&tokens[1]));
+
+		// What was held is remembered once it's all gone.
+		let until = lifetime.usable_until(tokens[2].expiration);
+		assert_eq!(store.usable_until(&lifetime), until);
+		assert!(store.prune(&lifetime, until));
+		assert!(store.tokens().is_empty());
+		assert_eq!(store.usable_until(&lifetime), until);
+		store.clear(until + 1);
+		assert_eq!(store.usable_until(&lifetime), until + 1);
+	}
+	#[test]
+	fn coverage() {
+		let (info, lifetime) = (info(), AuthLifetime::default());
+		let signer = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let mut store = AuthStore::default();
+		assert_eq!(store.coverage(&lifetime, NOW), 0);
+
+		let tokens = slots(&info, &signer, &lifetime, 4);
+		store.receive(&tokens[..1], &info, &signer.public_key(), &lifetime, NOW);
+		let one = store.coverage(&lifetime, NOW);
+		assert_eq!(one, lifetime.usable_until(tokens[0].expiration) - NOW);
+		assert!(one < RUNNING_LOW);
+
+		// A gap in the slots stops the coverage at the gap.
+		store.receive(&tokens[2..], &info, &signer.public_key(), &lifetime, NOW);
+		assert_eq!(store.coverage(&lifetime, NOW), one);
+		store.receive(&tokens[1..2], &info, &signer.public_key(), &lifetime, NOW);
+		assert_eq!(store.coverage(&lifetime, NOW), one + 3 * AUTH_SLOT);
+		assert!(store.coverage(&lifetime, NOW) > 2 * RUNNING_LOW);
+	}
+}
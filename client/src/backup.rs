@@ -0,0 +1,71 @@
+use serde::{ Serialize, Deserialize };
+use anyhow::{ Context, anyhow };
+
+use super::passphrase;
+
+const VERSION: u8 = 1;
+const AAD: &[u8] = b"web3.0-test backup";
+
+// Stored entries exactly as they appear in storage, keyed by their storage key.
+#[derive(Serialize, Deserialize)]
+pub struct Backup {
+	pub self_peer: String,
+	pub peers: Vec<(String, String)>
+}
+impl Backup {
+	pub fn seal(&self, passphrase: &str) -> Result<String, anyhow::Error> {
+		let plaintext = bincode::serialize(self).context("Serialization Failed.")?;
+		let sealed = passphrase::Sealed::seal(&plaintext, passphrase, AAD)?;
+		Self::encode(&sealed)
+	}
+	fn encode(sealed: &passphrase::Sealed) -> Result<String, anyhow::Error> {
+		let mut buffer = vec![VERSION];
+		buffer.extend_from_slice(&bincode::serialize(sealed).context("Serialization Failed.")?);
+		Ok(base64::encode_config(&buffer, base64::URL_SAFE_NO_PAD))
+	}
+	pub fn open(encoded: &str, passphrase: &str) -> Result<Self, anyhow::Error> {
+		let buffer = base64::decode_config(encoded.trim(), base64::URL_SAFE_NO_PAD).context("Backup isn't Base64 encoded.")?;
+		let (version, buffer) = buffer.split_first().ok_or(anyhow!("Backup is empty."))?;
+		if *version != VERSION {
+			return Err(anyhow!("Unsupported backup version: {}", version));
+		}
+		let sealed: passphrase::Sealed = bincode::deserialize(buffer).context("Backup is corrupted.")?;
+		let plaintext = sealed.open(passphrase, AAD)?;
+		bincode::deserialize(&plaintext).context("Backup contents are corrupted.")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn seal_open() {
+		let backup = Backup {
+			self_peer: String::from("c2VsZl9wZWVy"),
+			peers: vec![(String::from("peer.A1b2"), String::from("cGVlcg"))]
+		};
+		let sealed = passphrase::Sealed::seal_with(
+			&bincode::serialize(&backup).unwrap(),
+			"correct horse battery staple",
+			AAD,
+			4,
+			[7; 16],
+			[9; 12]
+		).unwrap();
+		let encoded = Backup::encode(&sealed).unwrap();
+
+		let opened = Backup::open(&encoded, "correct horse battery staple").unwrap();
+		assert_eq!(opened.self_peer, backup.self_peer);
+		assert_eq!(opened.peers, backup.peers);
+
+		assert!(Backup::open(&encoded, "wrong passphrase").is_err());
+	}
+	#[test]
+	fn wrong_version() {
+		let sealed = passphrase::Sealed::seal_with(&[], "passphrase", AAD, 4, [7; 16], [9; 12]).unwrap();
+		let mut buffer = base64::decode_config(Backup::encode(&sealed).unwrap(), base64::URL_SAFE_NO_PAD).unwrap();
+		buffer[0] = VERSION + 1;
+		assert!(Backup::open(&base64::encode_config(&buffer, base64::URL_SAFE_NO_PAD), "passphrase").is_err());
+	}
+}
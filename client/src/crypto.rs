@@ -1,15 +1,16 @@
 use std::{
-	convert::From,
+	convert::{ From, TryFrom },
+	fmt,
 	ops::{ Deref, DerefMut }
 };
 use serde::{
 	Serialize,
 	Deserialize,
 	ser::Serializer,
-	de::Deserializer
+	de::{self, Deserializer}
 };
 
-use p256::{AffinePoint, EncodedPoint, NonZeroScalar, ProjectivePoint, PublicKey, Scalar, ecdsa::{
+use p256::{AffinePoint, EncodedPoint, NonZeroScalar, ProjectivePoint, Scalar, ecdsa::{
 		signature::{
 			Signature as _
 		},
@@ -19,7 +20,7 @@ use p256::{AffinePoint, EncodedPoint, NonZeroScalar, ProjectivePoint, PublicKey,
 		ff::PrimeField
 	}};
 use sha2::Digest;
-use ecdsa;
+use zeroize::Zeroize;
 use anyhow::{Context, anyhow};
 
 use super::rand::get_rng;
@@ -37,9 +38,17 @@ pub mod eip2098 {
 		Ok((signature, v))
 	}
 	pub fn encode_compact<O: Write>(recoverable: (p256::ecdsa::Signature, bool), output: &mut O) -> Result<(), anyhow::Error> {
-	
+		let (signature, v) = recoverable;
+		let bytes = signature.as_ref();
+		output.write_all(&bytes[..32])?;
+		let mut s = [0; 32];
+		s.copy_from_slice(&bytes[32..]);
+		s[0] |= (v as u8) << 7;
+		output.write_all(&s)?;
+		Ok(())
 	}
 }
+#[allow(non_snake_case)]
 pub fn recover_pub_key(signature: p256::ecdsa::Signature, is_odd: bool, message_hash: &Scalar) -> Result<p256::PublicKey, anyhow::Error> {
 	// STOLEN: from the recoverable implementation in k256
 	let r = signature.r();
@@ -63,7 +72,6 @@ pub fn recover_pub_key(signature: p256::ecdsa::Signature, is_odd: bool, message_
 }
 
 // Simple new-type wrapper
-#[derive(Debug)]
 pub struct Wrapper<T> (T);
 impl<T> Wrapper<T> {
 	pub fn unwrap(self) -> T {
@@ -108,14 +116,15 @@ impl<T: PartialEq> PartialEq for Wrapper<T> {
 	}
 }
 impl<T: Eq> Eq for Wrapper<T> {}
-// impl<T: std::fmt::Debug> std::fmt::Debug for Wrapper<T> {
-// 	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-// 		self.0.fmt(f)
-// 	}
-// }
+// Debug is implemented per alias below so that the secret key can't end up in a log.
 
 // Public Key
 pub type PublicKey = Wrapper<p256::EncodedPoint>;
+impl fmt::Debug for PublicKey {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_tuple("PublicKey").field(&base64::encode_config(self.compress().as_bytes(), base64::URL_SAFE_NO_PAD)).finish()
+	}
+}
 impl Serialize for PublicKey {
 	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
 		let bytes = self.as_ref().as_bytes().to_vec();
@@ -125,14 +134,40 @@ impl Serialize for PublicKey {
 impl<'de> Deserialize<'de> for PublicKey {
 	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
 		let bytes = Vec::<u8>::deserialize(deserializer)?;
-		// TODO: Handle invalid public keys.
-		Ok(PublicKey::from(p256::EncodedPoint::from_bytes(bytes).unwrap()))
+		// Only the encoding is checked here; verifying_key checks that it's a point on the curve.
+		p256::EncodedPoint::from_bytes(bytes).map(PublicKey::from)
+			.map_err(|_| de::Error::custom("Not an encoded point"))
 	}
 }
 
 
+impl PublicKey {
+	// Keys travel compressed in signaling messages but are everywhere else kept uncompressed.
+	pub fn from_compressed(bytes: &[u8]) -> Result<Self, anyhow::Error> {
+		let compressed = p256::EncodedPoint::from_bytes(bytes).map_err(|_| anyhow!("Not an encoded point"))?;
+		let decompressed: Option<p256::EncodedPoint> = compressed.decompress();
+		Ok(decompressed.context("Failed to decompress point")?.into())
+	}
+	pub fn verifying_key(&self) -> Result<p256::ecdsa::VerifyingKey, anyhow::Error> {
+		p256::ecdsa::VerifyingKey::from_encoded_point(self.as_ref()).map_err(|_| anyhow!("Public key couldn't be turned into a verifying key."))
+	}
+}
+
 // Secret Key
 pub type SecretKey = Wrapper<p256::SecretKey>;
+impl fmt::Debug for SecretKey {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str("SecretKey(<redacted>)")
+	}
+}
+impl SecretKey {
+	pub fn signing_key(&self) -> p256::ecdsa::SigningKey {
+		p256::ecdsa::SigningKey::from(self.as_ref().clone())
+	}
+	pub fn public_key(&self) -> PublicKey {
+		PublicKey::from(p256::EncodedPoint::from_secret_key(self.as_ref(), false))
+	}
+}
 impl Serialize for SecretKey {
 	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
 		let bytes = self.as_ref().to_bytes().to_vec();
@@ -142,13 +177,22 @@ impl Serialize for SecretKey {
 impl<'de> Deserialize<'de> for SecretKey {
 	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
 		let bytes = Vec::<u8>::deserialize(deserializer)?;
-		// TODO: Handle invalid public keys.
-		Ok(SecretKey::from(p256::SecretKey::from_bytes(bytes).unwrap()))
+		// from_bytes panics on anything but 32 bytes.
+		if bytes.len() != 32 {
+			return Err(de::Error::invalid_length(bytes.len(), &"32 bytes"));
+		}
+		p256::SecretKey::from_bytes(bytes).map(SecretKey::from)
+			.map_err(|_| de::Error::custom("Invalid secret key"))
 	}
 }
 
 // Signature
 pub type Signature = Wrapper<p256::ecdsa::Signature>;
+impl fmt::Debug for Signature {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_tuple("Signature").field(&base64::encode_config(self.as_bytes(), base64::URL_SAFE_NO_PAD)).finish()
+	}
+}
 impl Serialize for Signature {
 	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
 		let bytes = self.as_ref().as_bytes().to_vec();
@@ -158,13 +202,18 @@ impl Serialize for Signature {
 impl<'de> Deserialize<'de> for Signature {
 	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
 		let bytes = Vec::<u8>::deserialize(deserializer)?;
-		// TODO: Handle invalid public keys.
-		Ok(Signature::from(p256::ecdsa::Signature::from_bytes(&bytes).unwrap()))
+		p256::ecdsa::Signature::from_bytes(&bytes).map(Signature::from)
+			.map_err(|_| de::Error::custom("Invalid signature"))
 	}
 }
 
 // TODO: Recoverable Signature:
 pub type RecoverableSignature = Wrapper<(p256::ecdsa::Signature, bool)>;
+impl fmt::Debug for RecoverableSignature {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_tuple("RecoverableSignature").field(&base64::encode_config(&self.to_bytes()[..], base64::URL_SAFE_NO_PAD)).finish()
+	}
+}
 
 impl From<RecoverableSignature> for Signature {
 	fn from(rs: RecoverableSignature) -> Signature {
@@ -185,7 +234,7 @@ impl RecoverableSignature {
 	// STOLEN: This recoverable implementation was taken and edited from the recoverable implementation in the k256 crate.
 	#[allow(non_snake_case)]
 	pub fn try_sign_recoverable_prehashed(secret_scalar: &Scalar, ephemeral_scalar: NonZeroScalar, message_hash: &Scalar) -> Result<Self, anyhow::Error> {
-		let k_inverse: Scalar = Option::from(ephemeral_scalar.invert()).context("Failed to invert the ephemeral_scalar")?;
+		let mut k_inverse: Scalar = Option::from(ephemeral_scalar.invert()).context("Failed to invert the ephemeral_scalar")?;
 		let k = ephemeral_scalar;
 	
 		// Compute 𝐑 = 𝑘×𝑮
@@ -194,10 +243,11 @@ impl RecoverableSignature {
 	
 		// Lift x-coordinate of 𝐑 (element of base field) into a serialized big
 		// integer, then reduce it into an element of the scalar field
-		let r = Scalar::from_bytes_reduced(&R.x());
+		let r = Scalar::from_bytes_reduced(R.x().unwrap());
 	
 		// Compute `s` as a signature over `r` and `z`.
 		let mut s: Scalar = k_inverse * (message_hash + &(r * secret_scalar));
+		k_inverse.zeroize();
 	
 		if s.is_zero().into() {
 			return Err(anyhow!("S cannot be zero."));
@@ -208,13 +258,13 @@ impl RecoverableSignature {
 			s = -s;
 		}
 	
-		let signature = ecdsa::Signature::from_scalars(r, s).map_err(|_| anyhow!("Failed to create signature from r and s"))?;
+		let signature = p256::ecdsa::Signature::from_scalars(r, s).map_err(|_| anyhow!("Failed to create signature from r and s"))?;
 		
 		Ok(Self((signature, is_R_odd ^ is_s_high)))
 	}
 	pub fn try_sign_recoverable(sk: &SecretKey, bytes: &[u8]) -> Result<Self, anyhow::Error> {
 		let secret_scalar = sk.as_ref().secret_scalar();
-		let ephemeral_scalar = NonZeroScalar::random(get_rng());
+		let ephemeral_scalar = NonZeroScalar::random(get_rng()?);
 		let message_hash = Scalar::from_digest(sha2::Sha256::new().chain(bytes));
 		Self::try_sign_recoverable_prehashed(secret_scalar, ephemeral_scalar, &message_hash)
 	}
@@ -243,13 +293,11 @@ impl RecoverableSignature {
 		self.recover(&Scalar::from_digest(sha2::Sha256::new().chain(bytes)))
 	}
 	pub fn from_bytes(signature_bytes: &[u8]) -> Result<Self, anyhow::Error> {
-		let mut bytes = [0; 64];
-		bytes.copy_from_slice(signature_bytes);
+		let mut bytes = <[u8; 64]>::try_from(signature_bytes).map_err(|_| anyhow!("Signature must be 64 bytes."))?;
 		let prev = bytes[32];
 		let is_odd = prev >= 0b10000000;
 		bytes[32] = prev & 0b01111111;
 		let sig = p256::ecdsa::Signature::from_bytes(&bytes).map_err(|_| anyhow!("Signature creation failed."))?;
-		bytes[32] = prev;
 		Ok(Self::from((sig, is_odd)))
 	}
 	pub fn to_bytes(&self) -> [u8; 64] {
@@ -257,7 +305,7 @@ impl RecoverableSignature {
 		bytes.copy_from_slice(self.as_ref().0.as_bytes());
 		assert!(bytes[32] < 0b10000000);
 		if self.as_ref().1 {
-			bytes[32] = bytes[32] | 0b10000000;
+			bytes[32] |= 0b10000000;
 		}
 		bytes
 	}
@@ -300,12 +348,26 @@ mod tests {
 			&message_prehashed
 		).unwrap();
 
-		let mut sig_bytes = signature.to_bytes();
-		let new_signature = RecoverableSignature::from_bytes(&mut sig_bytes).unwrap();
+		let sig_bytes = signature.to_bytes();
+		let new_signature = RecoverableSignature::from_bytes(&sig_bytes).unwrap();
 	
 		assert_eq!(
 			signature,
 			new_signature
 		);
 	}
-}
\ No newline at end of file
+	#[test]
+	fn signature_length() {
+		assert!(RecoverableSignature::from_bytes(&[9; 63]).is_err());
+		assert!(RecoverableSignature::from_bytes(&[9; 65]).is_err());
+	}
+	#[test]
+	fn invalid_bytes() {
+		// Stored and received data is untrusted, so bad bytes have to be an error rather than a panic.
+		let garbage = bincode::serialize(&vec![9u8; 7]).unwrap();
+		assert!(bincode::deserialize::<PublicKey>(&garbage).is_err());
+		assert!(bincode::deserialize::<SecretKey>(&garbage).is_err());
+		assert!(bincode::deserialize::<Signature>(&garbage).is_err());
+		assert!(bincode::deserialize::<SecretKey>(&bincode::serialize(&vec![0u8; 32]).unwrap()).is_err());
+	}
+}
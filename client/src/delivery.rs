@@ -0,0 +1,190 @@
+use anyhow::anyhow;
+use wasm_bindgen::{JsValue, JsCast};
+use wasm_bindgen_futures::JsFuture;
+
+use super::web_push::PushRequest;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Outcome {
+	Delivered,
+	// Rate limited or the push service had a problem: try again later.
+	Retry,
+	// The subscription has expired or been unsubscribed.
+	Gone,
+	Failed(u16)
+}
+pub fn classify(status: u16) -> Outcome {
+	match status {
+		200..=299 => Outcome::Delivered,
+		404 | 410 => Outcome::Gone,
+		429 | 500..=599 => Outcome::Retry,
+		_ => Outcome::Failed(status)
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+	pub max_attempts: u32,
+	// Milliseconds
+	pub base_delay: u32,
+	pub max_delay: u32
+}
+impl Default for RetryPolicy {
+	fn default() -> Self {
+		Self {
+			max_attempts: 4,
+			base_delay: 500,
+			max_delay: 30_000
+		}
+	}
+}
+impl RetryPolicy {
+	// How long to wait before the next attempt, or None if we should give up.  The first attempt is attempt 0.
+	pub fn delay(&self, attempt: u32, retry_after: Option<u32>) -> Option<u32> {
+		if attempt + 1 >= self.max_attempts {
+			return None;
+		}
+		match retry_after {
+			// Retrying before the push service asked us to would just get us rate limited again.
+			Some(retry_after) if retry_after > self.max_delay => None,
+			Some(retry_after) => Some(retry_after),
+			None => Some(self.base_delay.saturating_mul(1 << attempt.min(16)).min(self.max_delay))
+		}
+	}
+}
+
+// Retry-After is either a number of seconds or an HTTP date.  Returns milliseconds.
+pub fn parse_retry_after(value: &str, now: f64) -> Option<u32> {
+	let value = value.trim();
+	if let Ok(seconds) = value.parse::<u32>() {
+		Some(seconds.saturating_mul(1000))
+	} else {
+		let date = js_sys::Date::parse(value);
+		if date.is_nan() {
+			None
+		} else {
+			Some((date - now).max(0.0) as u32)
+		}
+	}
+}
+
+// Pushes are also sent from the service worker, which has no window.
+enum Scope {
+	Window(web_sys::Window),
+	Worker(web_sys::WorkerGlobalScope)
+}
+impl Scope {
+	fn get() -> Result<Self, anyhow::Error> {
+		let global = js_sys::global();
+		if let Some(window) = global.dyn_ref::<web_sys::Window>() {
+			Ok(Scope::Window(window.clone()))
+		} else if let Some(worker) = global.dyn_ref::<web_sys::WorkerGlobalScope>() {
+			Ok(Scope::Worker(worker.clone()))
+		} else {
+			Err(anyhow!("No Window or Worker global scope."))
+		}
+	}
+	fn set_timeout(&self, callback: &js_sys::Function, ms: i32) -> Result<i32, JsValue> {
+		match self {
+			Scope::Window(window) => window.set_timeout_with_callback_and_timeout_and_arguments_0(callback, ms),
+			Scope::Worker(worker) => worker.set_timeout_with_callback_and_timeout_and_arguments_0(callback, ms)
+		}
+	}
+	fn fetch(&self, url: &str, init: &web_sys::RequestInit) -> js_sys::Promise {
+		match self {
+			Scope::Window(window) => window.fetch_with_str_and_init(url, init),
+			Scope::Worker(worker) => worker.fetch_with_str_and_init(url, init)
+		}
+	}
+}
+
+async fn sleep(ms: u32) -> Result<(), anyhow::Error> {
+	let scope = Scope::get()?;
+	let mut result = Ok(0);
+	let promise = js_sys::Promise::new(&mut |resolve, _| {
+		result = scope.set_timeout(&resolve, ms as i32);
+	});
+	result.map_err(|_| anyhow!("Failed to set a timeout"))?;
+	JsFuture::from(promise).await.map_err(|_| anyhow!("Timeout failed"))?;
+	Ok(())
+}
+
+async fn fetch(url: &str, request: &PushRequest) -> Result<web_sys::Response, JsValue> {
+	let scope = Scope::get().map_err(|e| JsValue::from(format!("{:?}", e)))?;
+	let init = request.to_request_init().map_err(|e| JsValue::from(format!("{:?}", e)))?;
+	let response = JsFuture::from(scope.fetch(url, &init)).await?;
+	response.dyn_into()
+}
+
+// Send a push request, trying the endpoint directly and then through each proxy (a prefix for the endpoint's url) if the fetch itself fails, usually because of CORS.
+pub async fn deliver(request: &PushRequest, proxies: &[String], policy: &RetryPolicy) -> Result<Outcome, anyhow::Error> {
+	let routes: Vec<String> = std::iter::once(request.url.clone())
+		.chain(proxies.iter().map(|proxy| format!("{}{}", proxy, request.url)))
+		.collect();
+	// Once a route works, keep using it for retries.
+	let mut route = 0;
+	let mut attempt = 0;
+	loop {
+		let response = loop {
+			match fetch(&routes[route], request).await {
+				Ok(response) => break response,
+				Err(e) if route + 1 < routes.len() => {
+					web_sys::console::warn_1(&e);
+					route += 1;
+				},
+				Err(_) => return Err(anyhow!("Push request failed on every route"))
+			}
+		};
+		let status = response.status();
+		match classify(status) {
+			Outcome::Retry => {
+				let retry_after = response.headers().get("retry-after").ok().flatten()
+					.and_then(|value| parse_retry_after(&value, js_sys::Date::now()));
+				if let Some(delay) = policy.delay(attempt, retry_after) {
+					sleep(delay).await?;
+					attempt += 1;
+				} else {
+					return Ok(Outcome::Failed(status));
+				}
+			},
+			outcome => return Ok(outcome)
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn statuses() {
+		assert_eq!(classify(201), Outcome::Delivered);
+		assert_eq!(classify(404), Outcome::Gone);
+		assert_eq!(classify(410), Outcome::Gone);
+		assert_eq!(classify(429), Outcome::Retry);
+		assert_eq!(classify(503), Outcome::Retry);
+		assert_eq!(classify(400), Outcome::Failed(400));
+		assert_eq!(classify(413), Outcome::Failed(413));
+	}
+	#[test]
+	fn backoff() {
+		let policy = RetryPolicy::default();
+		assert_eq!(policy.delay(0, None), Some(500));
+		assert_eq!(policy.delay(1, None), Some(1000));
+		assert_eq!(policy.delay(2, None), Some(2000));
+		// Out of attempts:
+		assert_eq!(policy.delay(3, None), None);
+
+		let policy = RetryPolicy { max_attempts: 20, ..RetryPolicy::default() };
+		assert_eq!(policy.delay(10, None), Some(30_000));
+		assert_eq!(policy.delay(18, None), Some(30_000));
+	}
+	#[test]
+	fn retry_after() {
+		let policy = RetryPolicy::default();
+		assert_eq!(parse_retry_after(" 5 ", 0.0), Some(5000));
+		assert_eq!(policy.delay(0, Some(5000)), Some(5000));
+		// Longer than we're willing to wait:
+		assert_eq!(policy.delay(0, Some(120_000)), None);
+	}
+}
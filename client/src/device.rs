@@ -0,0 +1,86 @@
+use serde::{ Serialize, Deserialize };
+use anyhow::{ Context, anyhow };
+use p256::ecdsa::signature::{RandomizedSigner, Verifier};
+use rand::{CryptoRng, RngCore};
+use std::convert::TryFrom;
+
+use super::crypto;
+
+pub const CERTIFICATE_LENGTH: usize = 33 + 33 + 64;
+
+// A root identity's statement that device_key speaks for it.  Each device signs its own messages and carries its certificate so that peers can tie it back to the root.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DeviceCertificate {
+	pub root_key: crypto::PublicKey,
+	pub device_key: crypto::PublicKey,
+	pub signature: crypto::Signature
+}
+impl DeviceCertificate {
+	fn statement(root_key: &crypto::PublicKey, device_key: &crypto::PublicKey) -> Vec<u8> {
+		let mut statement = Vec::new();
+		statement.extend_from_slice("web3.0-test device".as_bytes());
+		statement.push(0);
+		statement.extend_from_slice(root_key.compress().as_bytes());
+		statement.extend_from_slice(device_key.compress().as_bytes());
+		statement
+	}
+	pub fn issue(root: &crypto::SecretKey, device_key: crypto::PublicKey, mut rng: impl CryptoRng + RngCore) -> Self {
+		let root_key = root.public_key();
+		let signature = root.signing_key().sign_with_rng(&mut rng, &Self::statement(&root_key, &device_key)).into();
+		Self { root_key, device_key, signature }
+	}
+	pub fn verify(&self) -> Result<(), anyhow::Error> {
+		self.root_key.verifying_key()?
+			.verify(&Self::statement(&self.root_key, &self.device_key), &self.signature)
+			.map_err(|_| anyhow!("Device certificate wasn't signed by the root key"))
+	}
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut bytes = Vec::with_capacity(CERTIFICATE_LENGTH);
+		bytes.extend_from_slice(self.root_key.compress().as_bytes());
+		bytes.extend_from_slice(self.device_key.compress().as_bytes());
+		bytes.extend_from_slice(self.signature.as_ref().as_ref());
+		bytes
+	}
+	pub fn from_bytes(bytes: &[u8]) -> Result<Self, anyhow::Error> {
+		if bytes.len() != CERTIFICATE_LENGTH {
+			return Err(anyhow!("Device certificate has the wrong length"));
+		}
+		let (root_key, bytes) = bytes.split_at(33);
+		let root_key = crypto::PublicKey::from_compressed(root_key).context("Root key invalid")?;
+		let (device_key, signature) = bytes.split_at(33);
+		let device_key = crypto::PublicKey::from_compressed(device_key).context("Device key invalid")?;
+		let signature = p256::ecdsa::Signature::try_from(signature).map_err(|_| anyhow!("Certificate signature was malformed"))?.into();
+		Ok(Self { root_key, device_key, signature })
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn issue_verify() {
+		let root = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let device = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let certificate = DeviceCertificate::issue(&root, device.public_key(), rand::thread_rng());
+		assert!(certificate.verify().is_ok());
+	}
+	#[test]
+	fn forged_root() {
+		let root = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let device = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let mut certificate = DeviceCertificate::issue(&root, device.public_key(), rand::thread_rng());
+		let other = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		certificate.root_key = other.public_key();
+		assert!(certificate.verify().is_err());
+	}
+	#[test]
+	fn to_from_bytes() {
+		let root = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let device = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let certificate = DeviceCertificate::issue(&root, device.public_key(), rand::thread_rng());
+		let bytes = certificate.to_bytes();
+		assert_eq!(bytes.len(), CERTIFICATE_LENGTH);
+		assert_eq!(certificate, DeviceCertificate::from_bytes(&bytes).unwrap());
+	}
+}
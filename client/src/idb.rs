@@ -0,0 +1,248 @@
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use anyhow::{ Context, anyhow };
+use js_sys::{Array, Function, Promise};
+use web_sys::{BroadcastChannel, IdbDatabase, IdbFactory, IdbRequest, IdbTransaction, IdbTransactionMode, MessageEvent};
+use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+
+use super::persist::{Storage, ChangedElsewhere};
+
+const DATABASE: &str = "web3.0-test";
+const VERSION: u32 = 1;
+// Entries are kept exactly as they would be in localStorage: encoded strings by key.
+const STORE: &str = "persist";
+// Carries the key of each entry that we change, so that other contexts can update their copy of it.
+const CHANNEL: &str = "persist-changes";
+
+// The same IndexedDB is reachable from pages and from the service worker, which doesn't have localStorage.
+fn factory() -> Result<IdbFactory, anyhow::Error> {
+	js_sys::Reflect::get(&js_sys::global(), &JsValue::from("indexedDB"))
+		.ok()
+		.and_then(|factory| factory.dyn_into::<IdbFactory>().ok())
+		.context("IndexedDB isn't available here.")
+}
+// A promise that settles once the request has succeeded or failed.
+async fn finished(request: &IdbRequest) -> Result<JsValue, anyhow::Error> {
+	let promise = Promise::new(&mut |resolve, reject| {
+		request.set_onsuccess(Some(&resolve));
+		request.set_onerror(Some(&reject));
+	});
+	JsFuture::from(promise).await.map_err(|e| anyhow!("IndexedDB request failed: {:?}", e))?;
+	request.result().map_err(|_| anyhow!("IndexedDB request didn't have a result."))
+}
+async fn read_entry(db: &IdbDatabase, key: &str) -> Result<Option<String>, anyhow::Error> {
+	let store = db.transaction_with_str(STORE)
+		.and_then(|transaction| transaction.object_store(STORE))
+		.map_err(|_| anyhow!("Failed to start a read transaction."))?;
+	let request = store.get(&JsValue::from(key)).map_err(|_| anyhow!("Failed to read a stored entry."))?;
+	Ok(finished(&request).await?.as_string())
+}
+// A promise that settles once the transaction has committed or been aborted.
+fn committed(transaction: &IdbTransaction) -> Promise {
+	Promise::new(&mut |resolve, reject| {
+		transaction.set_oncomplete(Some(&resolve));
+		transaction.set_onerror(Some(&reject));
+		transaction.set_onabort(Some(&reject));
+	})
+}
+
+// IndexedDB storage for Persist.  IndexedDB can only be used asynchronously, so everything is read into memory when the database is opened, and Storage reads are served from there.  Writes update that copy straight away and are committed in the background, so set and remove succeeding doesn't mean the change is durable: saved waits for the latest write to a key and reports whether it failed, and flush does the same for every write.  A write that fails puts back whatever the database holds for that key.
+// Other contexts (pages or the service worker) sharing the database are told about our writes over a BroadcastChannel and read the entries again.  That happens in the background too, so our copy can be behind: replace checks the entry inside the same transaction that writes it, and aborts if another context has written it since.
+#[derive(Debug, Clone)]
+pub struct IndexedDb {
+	db: IdbDatabase,
+	entries: Rc<RefCell<BTreeMap<String, String>>>,
+	channel: BroadcastChannel,
+	// Only held so that the channel's handler lives as long as we do.
+	_on_message: Rc<Closure<dyn FnMut(MessageEvent)>>,
+	// Told the key of each entry another context changes, once our copy of it is up to date.
+	on_change: Rc<RefCell<Option<Function>>>,
+	// IndexedDB commits our readwrite transactions in the order they were created, so the last one to commit means they all have.
+	last_write: Rc<RefCell<Option<Promise>>>,
+	// The commit of our latest write to each key, which rejects if it failed.
+	pending: Rc<RefCell<BTreeMap<String, Promise>>>,
+	failed: Rc<Cell<bool>>,
+	// Counts our writes to each key, so that rolling back a failed one doesn't clobber a write that came after it.
+	writes: Rc<RefCell<BTreeMap<String, u64>>>
+}
+impl IndexedDb {
+	pub async fn open() -> Result<Self, anyhow::Error> {
+		let request = factory()?.open_with_u32(DATABASE, VERSION)
+			.map_err(|_| anyhow!("Failed to open the database."))?;
+		let upgrading = request.clone();
+		let on_upgrade = Closure::once_into_js(move |_: JsValue| {
+			if let Ok(db) = upgrading.result().and_then(|db| db.dyn_into::<IdbDatabase>()) {
+				if !db.object_store_names().contains(STORE) {
+					let _ = db.create_object_store(STORE);
+				}
+			}
+		});
+		request.set_onupgradeneeded(Some(on_upgrade.unchecked_ref()));
+		let db = finished(&request).await?
+			.dyn_into::<IdbDatabase>().map_err(|_| anyhow!("Opening the database didn't produce a database."))?;
+
+		let entries = Rc::new(RefCell::new(BTreeMap::new()));
+		let on_change = Rc::new(RefCell::new(None::<Function>));
+		let channel = BroadcastChannel::new(CHANNEL).map_err(|_| anyhow!("Failed to open the change channel."))?;
+		let on_message = {
+			let (db, entries, on_change) = (db.clone(), entries.clone(), on_change.clone());
+			Closure::wrap(Box::new(move |event: MessageEvent| {
+				if let Some(key) = event.data().as_string() {
+					let (db, entries, on_change) = (db.clone(), entries.clone(), on_change.clone());
+					wasm_bindgen_futures::spawn_local(async move {
+						match read_entry(&db, &key).await {
+							Ok(Some(value)) => { entries.borrow_mut().insert(key.clone(), value); },
+							Ok(None) => { entries.borrow_mut().remove(&key); },
+							Err(e) => return web_sys::console::error_1(&JsValue::from(format!("Failed to read a changed entry: {:?}", e)))
+						}
+						// Cloned so that the handler can replace itself.
+						let handler = on_change.borrow().clone();
+						if let Some(handler) = handler {
+							let _ = handler.call1(&JsValue::NULL, &JsValue::from(key));
+						}
+					});
+				}
+			}) as Box<dyn FnMut(MessageEvent)>)
+		};
+		channel.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+
+		let storage = Self {
+			db,
+			entries,
+			channel,
+			_on_message: Rc::new(on_message),
+			on_change,
+			last_write: Rc::new(RefCell::new(None)),
+			pending: Rc::new(RefCell::new(BTreeMap::new())),
+			failed: Rc::new(Cell::new(false)),
+			writes: Rc::new(RefCell::new(BTreeMap::new()))
+		};
+		storage.reload().await?;
+		Ok(storage)
+	}
+	// Call handler with the key of each entry that another context changes, or with None to stop.
+	pub fn set_change_handler(&self, handler: Option<Function>) {
+		*self.on_change.borrow_mut() = handler;
+	}
+	// Read every entry again, picking up changes made by other contexts.
+	pub async fn reload(&self) -> Result<(), anyhow::Error> {
+		let store = self.db.transaction_with_str(STORE)
+			.and_then(|transaction| transaction.object_store(STORE))
+			.map_err(|_| anyhow!("Failed to start a read transaction."))?;
+		let keys = store.get_all_keys().map_err(|_| anyhow!("Failed to read the stored keys."))?;
+		let values = store.get_all().map_err(|_| anyhow!("Failed to read the stored values."))?;
+		let keys = Array::from(&finished(&keys).await?);
+		let values = Array::from(&finished(&values).await?);
+		// Both come back in key order.
+		let entries = keys.iter().zip(values.iter())
+			.filter_map(|(key, value)| Some((key.as_string()?, value.as_string()?)))
+			.collect();
+		*self.entries.borrow_mut() = entries;
+		Ok(())
+	}
+	// Wait until every write so far has been committed.  Fails if any of them couldn't be.
+	pub async fn flush(&self) -> Result<(), anyhow::Error> {
+		let last_write = self.last_write.borrow().clone();
+		if let Some(last_write) = last_write {
+			// Failures are recorded where each write is watched, so the outcome here doesn't matter.
+			let _ = JsFuture::from(last_write).await;
+		}
+		if self.failed.replace(false) {
+			Err(anyhow!("Some changes couldn't be saved to IndexedDB."))
+		} else {
+			Ok(())
+		}
+	}
+	// Wait until our latest write to key has been committed.  Fails if it couldn't be, and then the entry goes back to what the database holds.
+	pub async fn saved(&self, key: &str) -> Result<(), anyhow::Error> {
+		let pending = self.pending.borrow().get(key).cloned();
+		match pending {
+			Some(pending) => JsFuture::from(pending).await
+				.map(|_| ())
+				.map_err(|e| anyhow!("Failed to save {} to IndexedDB: {:?}", key, e)),
+			None => Ok(())
+		}
+	}
+	// Queue a transaction that sets the entry (or removes it, for None) if condition accepts what the database holds for it, and update our copy to match.
+	fn write(&self, key: &str, value: Option<&str>, condition: impl FnOnce(Option<String>) -> bool + 'static) -> Result<(), anyhow::Error> {
+		let transaction = self.db.transaction_with_str_and_mode(STORE, IdbTransactionMode::Readwrite)
+			.map_err(|_| anyhow!("Failed to start a write transaction."))?;
+		let store = transaction.object_store(STORE).map_err(|_| anyhow!("Database is missing its object store."))?;
+		let request = store.get(&JsValue::from(key)).map_err(|_| anyhow!("Failed to queue the write."))?;
+		let on_read = {
+			let (transaction, request, key, value) = (transaction.clone(), request.clone(), JsValue::from(key), value.map(JsValue::from));
+			Closure::once_into_js(move |_: JsValue| {
+				let current = request.result().ok().and_then(|current| current.as_string());
+				let queued = condition(current) && match value {
+					Some(value) => store.put_with_key(&value, &key),
+					None => store.delete(&key)
+				}.is_ok();
+				if !queued {
+					let _ = transaction.abort();
+				}
+			})
+		};
+		request.set_onsuccess(Some(on_read.unchecked_ref()));
+		let promise = committed(&transaction);
+		*self.last_write.borrow_mut() = Some(promise.clone());
+		self.pending.borrow_mut().insert(key.into(), promise.clone());
+		let write = {
+			let mut writes = self.writes.borrow_mut();
+			let count = writes.entry(key.into()).or_insert(0);
+			*count += 1;
+			*count
+		};
+		match value {
+			Some(value) => self.entries.borrow_mut().insert(key.into(), value.into()),
+			None => self.entries.borrow_mut().remove(key)
+		};
+
+		let (db, channel, entries, failed, writes, key) = (self.db.clone(), self.channel.clone(), self.entries.clone(), self.failed.clone(), self.writes.clone(), String::from(key));
+		wasm_bindgen_futures::spawn_local(async move {
+			match JsFuture::from(promise).await {
+				// Only once it's committed, so that other contexts reading the entry see our change.
+				Ok(_) => { let _ = channel.post_message(&JsValue::from(key)); },
+				Err(e) => {
+					web_sys::console::error_2(&JsValue::from("Failed to write to IndexedDB: "), &e);
+					failed.set(true);
+					// Go back to what the database holds, unless we've written since (that write decides the entry now).
+					let current = read_entry(&db, &key).await;
+					if writes.borrow().get(&key) == Some(&write) {
+						match current {
+							Ok(Some(value)) => { entries.borrow_mut().insert(key, value); },
+							Ok(None) => { entries.borrow_mut().remove(&key); },
+							Err(e) => web_sys::console::error_1(&JsValue::from(format!("Failed to read back a failed write: {:?}", e)))
+						}
+					}
+				}
+			}
+		});
+		Ok(())
+	}
+}
+impl Storage for IndexedDb {
+	fn get(&self, key: &str) -> Result<Option<String>, anyhow::Error> {
+		Ok(self.entries.borrow().get(key).cloned())
+	}
+	// Not durable until saved says so (see above).
+	fn set(&self, key: &str, value: &str) -> Result<(), anyhow::Error> {
+		self.write(key, Some(value), |_| true)
+	}
+	fn remove(&self, key: &str) -> Result<(), anyhow::Error> {
+		self.write(key, None, |_| true)
+	}
+	// Checked against our copy now, and against the database when the transaction runs.
+	fn replace(&self, key: &str, expected: Option<&str>, value: &str) -> Result<(), anyhow::Error> {
+		if self.get(key)?.as_deref() != expected {
+			return Err(ChangedElsewhere.into());
+		}
+		let expected = expected.map(String::from);
+		self.write(key, Some(value), move |current| current == expected)
+	}
+	fn keys(&self) -> Result<Vec<String>, anyhow::Error> {
+		Ok(self.entries.borrow().keys().cloned().collect())
+	}
+}
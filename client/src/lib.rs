@@ -1,13 +1,25 @@
 // #![allow(unused_variables, unused_imports, dead_code)]
 use wasm_bindgen::prelude::*;
 mod peer;
+mod peer_index;
 mod persist;
-mod crypto;
+mod idb;
+// Shared with the native push service stand-in.
+pub mod crypto;
 mod signaling;
 mod rand;
 mod self_peer;
-mod web_push;
-mod signaling_v2;
+pub mod web_push;
+mod passphrase;
+mod succession;
+mod device;
+mod backup;
+mod archive;
+mod maintenance;
+mod safety_number;
+mod delivery;
+mod authorizations;
+pub mod jwt;
 
 use shared::*;
 
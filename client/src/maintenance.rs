@@ -0,0 +1,77 @@
+use serde::{ Serialize, Deserialize };
+
+const WEEK: u32 = 7 * 24 * 60 * 60;
+
+// What happens to peers that have been unreachable for too long.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Disposal {
+	// Handed back in a signed archive that import_peers can restore, and removed from storage once the archive has been kept (see remove_archived).
+	Archive,
+	Delete
+}
+
+// The policy is an object like {"max_age": 2419200, "disposal": "Archive"}.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Policy {
+	// Seconds since a peer last had a usable push authorization before it's collected.
+	pub max_age: u32,
+	pub disposal: Disposal
+}
+impl Default for Policy {
+	fn default() -> Self {
+		Self {
+			max_age: 8 * WEEK,
+			disposal: Disposal::Archive
+		}
+	}
+}
+impl Policy {
+	// A peer we can't tell the age of (it never sent us authorizations, or its record is from before that was kept) isn't collected yet: maintain counts its age from the first pass that sees it.
+	pub fn collects(&self, last_reachable: Option<u32>, now: u32) -> bool {
+		match last_reachable {
+			Some(last_reachable) => last_reachable.saturating_add(self.max_age) <= now,
+			None => false
+		}
+	}
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PeerUsage {
+	pub peer_id: String,
+	// Bytes taken up by the record (key included), before and after the pass rewrites it without its expired authorizations.
+	pub bytes: usize,
+	pub compacted_bytes: usize,
+	pub expired_authorizations: usize,
+	// When we could last push to the peer or any of its devices.
+	pub last_reachable: Option<u32>,
+	pub collected: bool
+}
+
+// What a maintenance pass did, or with dry_run, what it would do.
+#[derive(Serialize, Debug, Default)]
+pub struct Report {
+	pub dry_run: bool,
+	pub peers: Vec<PeerUsage>,
+	// Across every peer, before the pass and what's left after it (once remove_archived has run, with Archive).
+	pub total_bytes: usize,
+	pub compacted_bytes: usize,
+	// The collected peers, when the policy archives them.  They're still stored until this is passed to remove_archived.
+	pub archive: Option<String>,
+	// The collected peers that were deleted, when the policy deletes them.  Peers that changed after they were surveyed are kept.
+	pub removed: Vec<String>
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn collects() {
+		let policy = Policy { max_age: WEEK, disposal: Disposal::Delete };
+		let now = 1_601_337_600;
+		assert!(!policy.collects(None, now));
+		assert!(!policy.collects(Some(now - WEEK + 1), now));
+		assert!(policy.collects(Some(now - WEEK), now));
+		assert!(!policy.collects(Some(u32::MAX), now));
+	}
+}
@@ -0,0 +1,109 @@
+use serde::{ Serialize, Deserialize };
+use anyhow::anyhow;
+use aes_gcm::Aes256Gcm;
+use aes_gcm::aead::{Aead, NewAead, Payload, generic_array::GenericArray};
+use scrypt::{scrypt, ScryptParams};
+use zeroize::Zeroizing;
+
+use super::rand::{get_salt, get_nonce};
+
+// scrypt cost parameters used when sealing something new.  They're stored alongside the ciphertext so that they can be raised later without breaking old records.
+#[cfg(not(test))]
+const LOG_N: u8 = 15;
+// Cheap enough for tests of everything that seals a key, which run in debug builds.
+#[cfg(test)]
+const LOG_N: u8 = 4;
+const R: u32 = 8;
+const P: u32 = 1;
+// The most work we'll do to open something.  The parameters come from storage or a backup file, so anything beyond this would let a corrupted record hang or exhaust the browser.
+const MAX_LOG_N: u8 = 20;
+
+// Bytes encrypted under a key derived from a user's passphrase.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Sealed {
+	log_n: u8,
+	r: u32,
+	p: u32,
+	salt: [u8; 16],
+	nonce: [u8; 12],
+	ciphertext: Vec<u8>
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], log_n: u8, r: u32, p: u32) -> Result<Zeroizing<[u8; 32]>, anyhow::Error> {
+	let params = ScryptParams::new(log_n, r, p).map_err(|_| anyhow!("Invalid scrypt parameters"))?;
+	let mut key = Zeroizing::new([0; 32]);
+	scrypt(passphrase.as_bytes(), salt, &params, key.as_mut()).map_err(|_| anyhow!("Failed to derive a key from the passphrase"))?;
+	Ok(key)
+}
+
+impl Sealed {
+	pub fn seal(plaintext: &[u8], passphrase: &str, aad: &[u8]) -> Result<Self, anyhow::Error> {
+		Self::seal_with(plaintext, passphrase, aad, LOG_N, get_salt()?, get_nonce()?)
+	}
+	pub fn seal_with(plaintext: &[u8], passphrase: &str, aad: &[u8], log_n: u8, salt: [u8; 16], nonce: [u8; 12]) -> Result<Self, anyhow::Error> {
+		let key = derive_key(passphrase, &salt, log_n, R, P)?;
+		// from_slice rather than into(), which would copy the key out of its Zeroizing.
+		let ciphertext = Aes256Gcm::new(GenericArray::from_slice(key.as_ref()))
+			.encrypt(&nonce.into(), Payload { msg: plaintext, aad })
+			.map_err(|_| anyhow!("Encryption failed"))?;
+		Ok(Self {
+			log_n, r: R, p: P,
+			salt, nonce,
+			ciphertext
+		})
+	}
+	// The plaintext is zeroed when dropped since it's usually a secret key.
+	pub fn open(&self, passphrase: &str, aad: &[u8]) -> Result<Zeroizing<Vec<u8>>, anyhow::Error> {
+		if self.log_n > MAX_LOG_N || self.r != R || self.p != P {
+			return Err(anyhow!("Unsupported scrypt parameters"));
+		}
+		let key = derive_key(passphrase, &self.salt, self.log_n, self.r, self.p)?;
+		Aes256Gcm::new(GenericArray::from_slice(key.as_ref()))
+			.decrypt(&self.nonce.into(), Payload { msg: &self.ciphertext, aad })
+			.map(Zeroizing::new)
+			.map_err(|_| anyhow!("Wrong passphrase or corrupted data"))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use rand::RngCore;
+
+	fn seal_for_test(plaintext: &[u8], passphrase: &str, aad: &[u8]) -> Sealed {
+		let mut salt = [0; 16];
+		rand::thread_rng().fill_bytes(&mut salt);
+		let mut nonce = [0; 12];
+		rand::thread_rng().fill_bytes(&mut nonce);
+		// Keep the work factor low so that the tests run quickly.
+		Sealed::seal_with(plaintext, passphrase, aad, 4, salt, nonce).unwrap()
+	}
+
+	#[test]
+	fn seal_open() {
+		let sealed = seal_for_test("Hello World!".as_bytes(), "correct horse battery staple", &[1, 2, 3]);
+		assert_eq!(
+			sealed.open("correct horse battery staple", &[1, 2, 3]).unwrap().as_slice(),
+			"Hello World!".as_bytes()
+		);
+	}
+	#[test]
+	fn wrong_passphrase() {
+		let sealed = seal_for_test("Hello World!".as_bytes(), "correct horse battery staple", &[]);
+		assert!(sealed.open("incorrect horse battery staple", &[]).is_err());
+	}
+	#[test]
+	fn wrong_aad() {
+		let sealed = seal_for_test("Hello World!".as_bytes(), "correct horse battery staple", &[1, 2, 3]);
+		assert!(sealed.open("correct horse battery staple", &[3, 2, 1]).is_err());
+	}
+	#[test]
+	fn oversized_parameters() {
+		// Deriving a key with any of these would take gigabytes of memory.
+		let sealed = seal_for_test("Hello World!".as_bytes(), "correct horse battery staple", &[]);
+		for (log_n, r, p) in [(MAX_LOG_N + 4, R, P), (LOG_N, 1 << 20, P), (LOG_N, R, 1 << 20)].iter() {
+			let sealed = Sealed { log_n: *log_n, r: *r, p: *p, ..sealed.clone() };
+			assert!(sealed.open("correct horse battery staple", &[]).is_err());
+		}
+	}
+}
@@ -1,104 +1,460 @@
 use wasm_bindgen::prelude::*;
-use anyhow::anyhow;
+use anyhow::{ Context, anyhow };
 use serde::{ 
 	Serialize,
 	Deserialize,
-	ser::Serializer,
-	de::Deserializer
+	ser::Serializer
 };
-use signaling::SignalingFormat;
+use signaling::{SignalingFormat, SignalingMessage};
 use js_sys::Function;
 use std::collections::HashMap;
+use std::rc::Rc;
+use std::cell::RefCell;
 
 use shared::*;
 
 use super::signaling;
 use super::web_push;
-use super::persist::Persist;
+use super::persist::{self, Persist, Backend, Storage, Versioned, Migration, read_layout};
 use super::crypto;
-use super::persist;
+use super::peer_index;
+use super::delivery;
+use super::authorizations::{AuthStore, RUNNING_LOW};
+use super::archive::{ImportReport, Conflict, Failure};
+use super::maintenance::{self, PeerUsage};
+use super::self_peer::SelfPeer;
 
 pub fn peer_tag(public_key: &crypto::PublicKey) -> String {
 	base64::encode_config(public_key.compress().as_bytes(), base64::URL_SAFE_NO_PAD)
 }
+// The ids of the peers kept in this storage.
+pub fn peer_ids(storage: &Backend) -> Result<Vec<String>, anyhow::Error> {
+	peer_index::list::<PeerPersist>(storage)
+}
+pub fn rebuild_index(storage: &Backend) -> Result<usize, anyhow::Error> {
+	peer_index::rebuild::<PeerPersist>(storage).map(|(_, repaired)| repaired)
+}
+// The peer's record goes by its key rather than the index, which can be behind it (e.g. after a write to one of them failed), so the index entry is fixed up to match.
+fn find_record(storage: &Backend, peer_id: &str) -> Result<Option<Persist<PeerPersist>>, anyhow::Error> {
+	let persist = Persist::open_existing(storage.clone(), &peer_index::record_key(peer_id))?;
+	if persist.is_some() {
+		peer_index::insert::<PeerPersist>(storage, peer_id)?;
+	} else {
+		peer_index::remove::<PeerPersist>(storage, peer_id)?;
+	}
+	Ok(persist)
+}
+// The record goes in before the index entry, so the index never names a peer that isn't there.
+fn open_record(storage: &Backend, public_key: crypto::PublicKey) -> Result<Persist<PeerPersist>, anyhow::Error> {
+	let peer_id = peer_tag(&public_key);
+	let persist = Persist::open(
+		storage.clone(),
+		&peer_index::record_key(&peer_id),
+		|| PeerPersist::new(public_key)
+	)?;
+	peer_index::insert::<PeerPersist>(storage, &peer_id)?;
+	Ok(persist)
+}
+// Deliveries still in flight share the record, and any change they make afterwards fails rather than bringing it back.
+fn delete_record(persist: &Persist<PeerPersist>) -> Result<(), anyhow::Error> {
+	persist.delete()?;
+	peer_index::remove::<PeerPersist>(persist.storage(), &peer_tag(&persist.public_key))
+}
+// Move a peer's record over to the key that it has rotated to.  Authorizations signed by the old key are useless for the new one, so they're left behind.
+fn migrate_record(old: &Persist<PeerPersist>, public_key: crypto::PublicKey) -> Result<Persist<PeerPersist>, anyhow::Error> {
+	let mut persist = open_record(old.storage(), public_key)?;
+	persist.make_change(|persist| {
+		persist.info = old.info.clone();
+		persist.devices.extend(old.devices.iter().cloned());
+		// The new key hasn't been verified, whatever the state of the old one.
+		persist.verified = None;
+		for (key, value) in old.extra.iter() {
+			persist.extra.entry(key.clone()).or_insert_with(|| value.clone());
+		}
+	})?;
+	delete_record(old)?;
+	Ok(persist)
+}
+// Every indexed peer's record as it's stored, by peer id.
+pub fn export_records(storage: &Backend) -> Result<Vec<(String, String)>, anyhow::Error> {
+	let mut records = Vec::new();
+	for peer_id in peer_ids(storage)? {
+		if let Some(encoded) = storage.get(&peer_index::record_key(&peer_id))? {
+			records.push((peer_id, encoded));
+		}
+	}
+	Ok(records)
+}
+// Merge exported records into the peers in this storage.  Everything is decoded and checked before anything is written, so a corrupted archive is refused as a whole.  Each peer is then saved on its own: one that fails is listed in the report's failed and the rest are still imported.
+pub fn import_records(storage: &Backend, records: Vec<(String, String)>, lifetime: &web_push::AuthLifetime, now: u32) -> Result<ImportReport, anyhow::Error> {
+	let records = records.into_iter()
+		.map(|(peer_id, encoded)| {
+			let data = persist::decode::<PeerPersist>(&encoded).with_context(|| format!("Record for peer {} is corrupted.", peer_id))?;
+			if peer_tag(&data.public_key) != peer_id {
+				return Err(anyhow!("Record for peer {} holds a different peer's key.", peer_id));
+			}
+			Ok((peer_id, data))
+		})
+		.collect::<Result<Vec<_>, anyhow::Error>>()?;
+	let mut report = ImportReport::default();
+	for (peer_id, theirs) in records {
+		match import_record(storage, &peer_id, theirs, lifetime, now) {
+			Ok((added, conflicts)) => {
+				let list = if added { &mut report.added } else { &mut report.merged };
+				list.push(peer_id.clone());
+				report.conflicts.extend(conflicts.into_iter().map(|field| Conflict { peer_id: peer_id.clone(), field }));
+			},
+			Err(e) => report.failed.push(Failure { peer_id, error: format!("{:#}", e) })
+		}
+	}
+	Ok(report)
+}
+// Returns whether the peer was new, and the conflicting fields.
+fn import_record(storage: &Backend, peer_id: &str, theirs: PeerPersist, lifetime: &web_push::AuthLifetime, now: u32) -> Result<(bool, Vec<String>), anyhow::Error> {
+	let key = peer_index::record_key(peer_id);
+	if let Some(mut existing) = Persist::<PeerPersist>::open_existing(storage.clone(), &key)? {
+		let conflicts = existing.make_change(|data| data.merge(theirs.clone(), lifetime, now))?;
+		// In case it had dropped out of the index.
+		peer_index::insert::<PeerPersist>(storage, peer_id)?;
+		Ok((false, conflicts))
+	} else {
+		// Merged into an empty record so that their authorizations are checked like any others.
+		let mut data = PeerPersist::new(theirs.public_key);
+		let conflicts = data.merge(theirs, lifetime, now);
+		// The record goes in before the index entry, so the index never names a peer that isn't there.
+		Persist::open(storage.clone(), &key, || data)?;
+		peer_index::insert::<PeerPersist>(storage, peer_id)?;
+		Ok((true, conflicts))
+	}
+}
+// Report the storage each peer takes up, drop expired authorizations, and collect peers that have been unreachable for longer than the policy allows.  Collected peers are handed to archive before they're removed, unless the policy deletes them.  With dry_run nothing is written.
+pub fn maintain(storage: &Backend, policy: &maintenance::Policy, lifetime: &web_push::AuthLifetime, now: u32, dry_run: bool, archive: impl FnOnce(Vec<(String, String)>) -> Result<String, anyhow::Error>) -> Result<maintenance::Report, anyhow::Error> {
+	let mut report = maintenance::Report { dry_run, ..Default::default() };
+	let mut collected = Vec::new();
+	for peer_id in peer_ids(storage)? {
+		let key = peer_index::record_key(&peer_id);
+		let encoded = match storage.get(&key)? {
+			Some(encoded) => encoded,
+			None => continue
+		};
+		let mut data = persist::decode::<PeerPersist>(&encoded).with_context(|| format!("Record for peer {} is corrupted.", peer_id))?;
+		let last_reachable = data.last_reachable(lifetime);
+		let expired_authorizations = data.compact(lifetime, now);
+		let bytes = key.len() + encoded.len();
+		// The same rewrite as below, which saves at the next revision.
+		let compacted_bytes = if expired_authorizations > 0 || last_reachable.is_none() {
+			if data.last_reachable(lifetime).is_none() {
+				data.authorizations.assume_usable_until(now);
+			}
+			key.len() + persist::encode_at(&data, persist::revision(&encoded) + 1)?.len()
+		} else {
+			bytes
+		};
+		let usage = PeerUsage {
+			compacted_bytes,
+			collected: policy.collects(last_reachable, now),
+			peer_id,
+			bytes,
+			expired_authorizations,
+			last_reachable
+		};
+		report.total_bytes += usage.bytes;
+		if usage.collected {
+			collected.push((usage.peer_id.clone(), encoded));
+		} else {
+			report.compacted_bytes += usage.compacted_bytes;
+		}
+		report.peers.push(usage);
+	}
+	if dry_run {
+		return Ok(report);
+	}
+
+	match policy.disposal {
+		// The archive would be the only copy, so the peers stay until it's been kept somewhere and handed to remove_archived.
+		maintenance::Disposal::Archive if !collected.is_empty() => report.archive = Some(archive(collected)?),
+		maintenance::Disposal::Archive => {},
+		// Only if they're still as they were surveyed, the same as for an archive.
+		maintenance::Disposal::Delete => report.removed = remove_archived(storage, &collected)?
+	}
+	for usage in report.peers.iter().filter(|usage| !usage.collected) {
+		// Peers that we can't tell the age of start counting from now.
+		if usage.expired_authorizations > 0 || usage.last_reachable.is_none() {
+			if let Some(mut persist) = Persist::<PeerPersist>::open_existing(storage.clone(), &peer_index::record_key(&usage.peer_id))? {
+				persist.make_change(|data| {
+					data.compact(lifetime, now);
+					if data.last_reachable(lifetime).is_none() {
+						data.authorizations.assume_usable_until(now);
+					}
+				})?;
+			}
+		}
+	}
+	Ok(report)
+}
 
-#[derive(Serialize, Deserialize, Debug)]
+// Remove the peers in a maintenance archive, once it's been kept somewhere.  Peers whose record changed after it was archived are left alone.  Returns the ids of those removed.
+pub fn remove_archived(storage: &Backend, records: &[(String, String)]) -> Result<Vec<String>, anyhow::Error> {
+	let mut removed = Vec::new();
+	for (peer_id, encoded) in records {
+		let key = peer_index::record_key(peer_id);
+		if storage.get(&key)?.as_ref() == Some(encoded) {
+			storage.remove(&key)?;
+			peer_index::remove::<PeerPersist>(storage, peer_id)?;
+			removed.push(peer_id.clone());
+		}
+	}
+	Ok(removed)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct DevicePersist {
+	public_key: crypto::PublicKey,
+	info: Option<web_push::PushInfo>,
+	authorizations: AuthStore
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct PeerPersist {
 	public_key: crypto::PublicKey,
 	info: Option<web_push::PushInfo>,
-	authorizations: Vec<web_push::AuthToken>,
-	extra: HashMap<String, String>
+	authorizations: AuthStore,
+	extra: HashMap<String, String>,
+	// Devices certified by this peer's key.  The fields above belong to the device holding the key itself.
+	devices: Vec<DevicePersist>,
+	// The key whose safety number the user confirmed.  Verification only counts while it matches public_key.
+	verified: Option<crypto::PublicKey>
+}
+// Earlier layouts of PeerPersist, as tuples of their fields (bincode lays both out the same way).  Until v4, authorizations were laid out as just a list of tokens.
+type DeviceLayoutV1 = (crypto::PublicKey, Option<web_push::LegacyPushInfo>, Vec<web_push::AuthToken>);
+type LayoutV0 = (crypto::PublicKey, Option<web_push::LegacyPushInfo>, Vec<web_push::AuthToken>, HashMap<String, String>);
+type LayoutV1 = (LayoutV0, Vec<DeviceLayoutV1>);
+type LayoutV2 = (LayoutV1, Option<crypto::PublicKey>);
+type DeviceLayoutV3 = (crypto::PublicKey, Option<web_push::PushInfo>, Vec<web_push::AuthToken>);
+type LayoutV3 = (crypto::PublicKey, Option<web_push::PushInfo>, Vec<web_push::AuthToken>, HashMap<String, String>, Vec<DeviceLayoutV3>, Option<crypto::PublicKey>);
+impl Versioned for PeerPersist {
+	const MIGRATIONS: &'static [Migration] = &[
+		// 0 -> 1: Devices.
+		|bytes| Ok(bincode::serialize(&(read_layout::<LayoutV0>(bytes)?, Vec::<DeviceLayoutV1>::new()))?),
+		// 1 -> 2: Safety number verification.
+		|bytes| Ok(bincode::serialize(&(read_layout::<LayoutV1>(bytes)?, None::<crypto::PublicKey>))?),
+		// 2 -> 3: Push info records its content encoding.
+		|bytes| {
+			let (((public_key, info, authorizations, extra), devices), verified): LayoutV2 = read_layout(bytes)?;
+			let devices: Vec<_> = devices.into_iter()
+				.map(|(public_key, info, authorizations)| (public_key, info.map(web_push::PushInfo::from), authorizations))
+				.collect();
+			Ok(bincode::serialize(&(public_key, info.map(web_push::PushInfo::from), authorizations, extra, devices, verified))?)
+		},
+		// 3 -> 4: Authorizations remember how long they were usable for.  Tokens dropped before now are forgotten, so that starts out unknown (0).
+		|bytes| {
+			let (public_key, info, authorizations, extra, devices, verified): LayoutV3 = read_layout(bytes)?;
+			let devices: Vec<_> = devices.into_iter()
+				.map(|(public_key, info, authorizations)| (public_key, info, (authorizations, 0u32)))
+				.collect();
+			Ok(bincode::serialize(&(public_key, info, (authorizations, 0u32), extra, devices, verified))?)
+		}
+	];
+}
+// Take their push info if we don't have any, and keep those of their authorizations that verify against the info we end up with.  Returns whether both had info and it differed.
+fn merge_push(info: &mut Option<web_push::PushInfo>, authorizations: &mut AuthStore, their_info: Option<web_push::PushInfo>, their_authorizations: &AuthStore, signer: &crypto::PublicKey, lifetime: &web_push::AuthLifetime, now: u32) -> bool {
+	let conflict = matches!((info.as_ref(), their_info.as_ref()), (Some(ours), Some(theirs)) if ours != theirs);
+	if info.is_none() {
+		*info = their_info;
+	}
+	if let Some(info) = info.as_ref() {
+		authorizations.receive(their_authorizations.tokens(), info, signer, lifetime, now);
+	}
+	authorizations.prune(lifetime, now);
+	conflict
+}
+
+// Take the push info a peer sent us, if any, and the authorizations that verify against it.  Authorizations for a different subscription are no use, so they're dropped when it changes.
+fn receive_push(info: &mut Option<web_push::PushInfo>, authorizations: &mut AuthStore, new_info: Option<web_push::PushInfo>, tokens: &[web_push::AuthToken], signer: &crypto::PublicKey, lifetime: &web_push::AuthLifetime, now: u32) {
+	if let Some(new_info) = new_info {
+		if matches!(info.as_ref(), Some(old_info) if !old_info.same_subscription(&new_info)) {
+			authorizations.clear(now);
+		}
+		*info = Some(new_info);
+	}
+	if let Some(info) = info.as_ref() {
+		authorizations.receive(tokens, info, signer, lifetime, now);
+	}
+	authorizations.prune(lifetime, now);
+}
+// receive_push for the peer itself, or for one of its devices.  The new info, the new authorizations and pruning the old ones all go out in one write, which is made again on top of anything another context saves first.
+fn receive_signaling<S: Storage>(persist: &mut Persist<PeerPersist, S>, device_key: Option<&crypto::PublicKey>, new_info: Option<web_push::PushInfo>, tokens: &[web_push::AuthToken], lifetime: &web_push::AuthLifetime, now: u32) -> Result<(), anyhow::Error> {
+	persist.make_change(|data| {
+		let (info, authorizations, signer) = match device_key {
+			Some(device_key) => {
+				let device = data.device_mut(device_key);
+				(&mut device.info, &mut device.authorizations, *device_key)
+			},
+			None => (&mut data.info, &mut data.authorizations, data.public_key)
+		};
+		receive_push(info, authorizations, new_info.clone(), tokens, &signer, lifetime, now);
+	})
+}
+
+impl PeerPersist {
+	fn new(public_key: crypto::PublicKey) -> Self {
+		Self {
+			public_key,
+			info: None,
+			authorizations: AuthStore::default(),
+			extra: HashMap::new(),
+			devices: Vec::new(),
+			verified: None
+		}
+	}
+	// Fold in another copy of this peer's record.  Where both have a value and they differ, ours is kept and the field is returned as a conflict.
+	fn merge(&mut self, theirs: PeerPersist, lifetime: &web_push::AuthLifetime, now: u32) -> Vec<String> {
+		let mut conflicts = Vec::new();
+		let signer = self.public_key;
+		if merge_push(&mut self.info, &mut self.authorizations, theirs.info, &theirs.authorizations, &signer, lifetime, now) {
+			conflicts.push(String::from("info"));
+		}
+		for (key, value) in theirs.extra {
+			match self.extra.get(&key) {
+				Some(ours) if *ours != value => conflicts.push(format!("extra.{}", key)),
+				Some(_) => {},
+				None => { self.extra.insert(key, value); }
+			}
+		}
+		for their_device in theirs.devices {
+			let device = self.device_mut(&their_device.public_key);
+			if merge_push(&mut device.info, &mut device.authorizations, their_device.info, &their_device.authorizations, &their_device.public_key, lifetime, now) {
+				conflicts.push(format!("devices.{}.info", peer_tag(&their_device.public_key)));
+			}
+		}
+		// Their verification only carries over if it was of the same key.
+		if self.verified.is_none() && theirs.verified.as_ref() == Some(&self.public_key) {
+			self.verified = theirs.verified;
+		}
+		conflicts
+	}
+	// When we could last push to this peer or any of its devices, if we ever could.
+	fn last_reachable(&self, lifetime: &web_push::AuthLifetime) -> Option<u32> {
+		let until = self.devices.iter()
+			.map(|device| device.authorizations.usable_until(lifetime))
+			.fold(self.authorizations.usable_until(lifetime), u32::max);
+		if until == 0 { None } else { Some(until) }
+	}
+	// Drop expired authorizations for the peer and all of its devices.  Returns how many were dropped.
+	fn compact(&mut self, lifetime: &web_push::AuthLifetime, now: u32) -> usize {
+		let held = |data: &Self| data.authorizations.len() + data.devices.iter().map(|device| device.authorizations.len()).sum::<usize>();
+		let before = held(self);
+		self.authorizations.prune(lifetime, now);
+		for device in self.devices.iter_mut() {
+			device.authorizations.prune(lifetime, now);
+		}
+		before - held(self)
+	}
+	fn device(&self, public_key: &crypto::PublicKey) -> Option<&DevicePersist> {
+		self.devices.iter().find(|device| device.public_key.compress() == public_key.compress())
+	}
+	fn device_mut(&mut self, public_key: &crypto::PublicKey) -> &mut DevicePersist {
+		let index = self.devices.iter().position(|device| device.public_key.compress() == public_key.compress());
+		let index = index.unwrap_or_else(|| {
+			self.devices.push(DevicePersist {
+				public_key: *public_key,
+				info: None,
+				authorizations: AuthStore::default()
+			});
+			self.devices.len() - 1
+		});
+		&mut self.devices[index]
+	}
+}
+
+fn prepare_push(info: Option<&web_push::PushInfo>, authorizations: &AuthStore, signer: &crypto::PublicKey, lifetime: &web_push::AuthLifetime, data: &[u8], options: &web_push::PushOptions) -> Result<web_push::PushRequest, anyhow::Error> {
+	let info = info.ok_or(anyhow!("Peer doesn't have push info"))?;
+	let now = (js_sys::Date::now() / 1000.0) as u32;
+	let auth = authorizations.find(info, signer, lifetime, now).ok_or(anyhow!("Peer doesn't have a valid push authorization"))?;
+	web_push::push(info, signer, auth, lifetime, now, data, options)
 }
 
 #[wasm_bindgen]
 #[derive(Debug)]
 pub struct Peer {
-	persist: Persist<PeerPersist>,
+	// Shared with any deliveries that are still in flight.
+	persist: Rc<RefCell<Persist<PeerPersist>>>,
 	sdp_handler: JsValue,
 	ice_handler: JsValue,
-	signaling_queue: Option<SignalingFormat>
+	// Called with the remaining coverage in seconds (and the device id, for a device) whenever it's found to be below RUNNING_LOW.
+	auth_low_handler: JsValue
 }
 impl Serialize for Peer {
 	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
 		let encoded = base64::encode_config(
-			self.persist.public_key.compress().as_bytes(),
+			self.persist.borrow().public_key.compress().as_bytes(),
 			base64::URL_SAFE_NO_PAD
 		);
 		encoded.serialize(serializer)
 	}
 }
-impl<'de> Deserialize<'de> for Peer {
-	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
-		let encoded = String::deserialize(deserializer)?;
-		// TODO: Handle invalid base64
-		let bytes = base64::decode_config(encoded, base64::URL_SAFE_NO_PAD).unwrap();
-		let public_key = p256::EncodedPoint::from_bytes(bytes).unwrap().into();
-		Ok(Peer::new(public_key).unwrap())
-	}
-}
 
 #[wasm_bindgen]
-pub struct PushRequestInfo(String, web_sys::RequestInit);
+pub struct PushRequestInfo(web_push::PushRequest);
 #[wasm_bindgen]
 impl PushRequestInfo {
 	pub fn url(&self) -> String {
-		self.0.clone()
+		self.0.url.clone()
 	}
-	pub fn request_init(&self) -> web_sys::RequestInit {
-		self.1.clone()
+	pub fn request_init(&self) -> Result<web_sys::RequestInit, JsValue> {
+		self.0.to_request_init().to_js_error()
 	}
 }
-impl From<(String, web_sys::RequestInit)> for PushRequestInfo {
-	fn from(data: (String, web_sys::RequestInit)) -> Self {
-		Self(data.0, data.1)
+impl From<web_push::PushRequest> for PushRequestInfo {
+	fn from(request: web_push::PushRequest) -> Self {
+		Self(request)
 	}
 }
 
 #[wasm_bindgen]
 impl Peer {
-	pub fn get_all_peer_keys() -> Result<js_sys::Array, JsValue> {
-		let ls = persist::get_local_storage().to_js_error()?;
-		// TODO: Use map_while
-		let mut was_err = false;
-		let arr = (0..).map(|i| {
-				let ret = ls.key(i);
-				was_err |= ret.is_err();
-				ret
-			})
-			.take_while(|r| {
-				r.as_ref().unwrap_or(&None).is_some()
-			})
-			.map(|r| r.unwrap().unwrap())
+	// The ids of every peer stored alongside self_peer.
+	pub fn list(self_peer: &SelfPeer) -> Result<js_sys::Array, JsValue> {
+		Ok(peer_ids(self_peer.storage()).to_js_error()?.into_iter()
 			.map(JsValue::from)
-			.collect();
-		if !was_err {
-			Ok(arr)
-		} else {
-			Err(anyhow!("Failed to get a key.")).to_js_error()
+			.collect())
+	}
+	pub fn count(self_peer: &SelfPeer) -> Result<u32, JsValue> {
+		Ok(peer_index::count::<PeerPersist>(self_peer.storage()).to_js_error()? as u32)
+	}
+	// Look a peer up by its peer_id.
+	pub fn find(self_peer: &SelfPeer, peer_id: String) -> Result<Option<Peer>, JsValue> {
+		Ok(find_record(self_peer.storage(), &peer_id).to_js_error()?.map(Peer::from_persist))
+	}
+	// Delete a peer by its peer_id.  Returns whether there was such a peer.
+	pub fn delete_by_id(self_peer: &SelfPeer, peer_id: String) -> Result<bool, JsValue> {
+		match Peer::find(self_peer, peer_id)? {
+			Some(peer) => peer.delete().map(|_| true),
+			None => Ok(false)
 		}
 	}
+	// Rebuild the peer index from the records in storage, indexing peers that were missing from it and dropping ids without a readable record.  Returns how many ids were added or dropped.
+	pub fn rebuild_index(self_peer: &SelfPeer) -> Result<u32, JsValue> {
+		Ok(rebuild_index(self_peer.storage()).to_js_error()? as u32)
+	}
+	// Load a peer by its storage key, from wherever self_peer is stored.
+	pub fn load(self_peer: &SelfPeer, key: String) -> Result<Option<Peer>, JsValue> {
+		Ok(Persist::open_existing(self_peer.storage().clone(), &key).to_js_error()?.map(Peer::from_persist))
+	}
 	pub fn peer_id(&self) -> String {
-		peer_tag(&self.persist.public_key)
+		peer_tag(&self.persist.borrow().public_key)
+	}
+	// Resolves once the latest change to this peer has been saved, and rejects if it couldn't be.  Changes to localStorage are saved immediately.
+	pub fn saved(&self) -> js_sys::Promise {
+		let saved = self.persist.borrow().saved();
+		wasm_bindgen_futures::future_to_promise(async move {
+			saved.await.to_js_error()?;
+			Ok(JsValue::undefined())
+		})
+	}
+	// Pick up changes saved by other tabs or the service worker.  Returns whether there were any.
+	pub fn reload(&self) -> Result<bool, JsValue> {
+		self.persist.borrow_mut().refresh().to_js_error()
 	}
 	pub fn set_sdp_handler(&mut self, callback: JsValue) {
 		self.sdp_handler = callback;
@@ -106,35 +462,131 @@ impl Peer {
 	pub fn set_ice_handler(&mut self, callback: JsValue) {
 		self.ice_handler = callback;
 	}
-	fn find_auth(&self) -> Option<&web_push::AuthToken> {
-		self.persist.info.as_ref().and_then(|info| {
-			self.persist.authorizations.iter().find(|auth| {
-				auth.fill_and_check(info, &self.persist.public_key).is_ok()
-			})
-		})
+	pub fn set_auth_low_handler(&mut self, callback: JsValue) {
+		self.auth_low_handler = callback;
+	}
+	// Seconds that we can keep pushing to the peer (or one of its devices) before its authorizations run out.
+	pub fn auth_coverage(&self, self_peer: &SelfPeer, device_id: Option<String>) -> Result<u32, JsValue> {
+		self.reload()?;
+		let persist = self.persist.borrow();
+		let authorizations = match device_id {
+			Some(device_id) => &persist.devices.iter()
+				.find(|device| peer_tag(&device.public_key) == device_id)
+				.ok_or(anyhow!("Peer doesn't have a device with that id")).to_js_error()?
+				.authorizations,
+			None => &persist.authorizations
+		};
+		Ok(authorizations.coverage(&self_peer.auth_lifetime(), (js_sys::Date::now() / 1000.0) as u32))
 	}
-	pub fn prepare_raw(&self, data: String) -> Result<PushRequestInfo, JsValue> {
-		let auth = self.find_auth().ok_or(anyhow!("Peer doesn't have a valid push authorization")).to_js_error()?;
-		Ok(PushRequestInfo::from(web_push::push(
-			self.persist.info.as_ref()
-				.ok_or(anyhow!("Peer doesn't have push info"))
-				.to_js_error()?,
-			&self.persist.public_key,
-			auth,
+	pub fn prepare_raw(&self, self_peer: &SelfPeer, data: String) -> Result<PushRequestInfo, JsValue> {
+		let options = web_push::PushOptions {
+			padding: self_peer.padding(),
+			..web_push::PushOptions::default()
+		};
+		let persist = self.persist.borrow();
+		prepare_push(
+			persist.info.as_ref(),
+			&persist.authorizations,
+			&persist.public_key,
+			&self_peer.auth_lifetime(),
 			data.as_bytes(),
-			None,
-			0
-		).to_js_error()?))
-	}
-	pub fn apply_signaling_message(&mut self, message: signaling::ParsedMessage) -> Result<(), JsValue> {
-		if let Some(info) = message.message.info() {
-			self.persist.make_change(|persist| {
-				persist.info = Some(info);
-			}).to_js_error()?;
-		}
-		self.persist.make_change(|persist| {
-			persist.authorizations.extend_from_slice(&message.message.auths());
-		}).to_js_error()?;
+			&options
+		).map(PushRequestInfo::from).to_js_error()
+	}
+	pub fn device_ids(&self) -> js_sys::Array {
+		self.persist.borrow().devices.iter()
+			.map(|device| JsValue::from(peer_tag(&device.public_key)))
+			.collect()
+	}
+	pub fn prepare_raw_for_device(&self, self_peer: &SelfPeer, device_id: String, data: String) -> Result<PushRequestInfo, JsValue> {
+		let options = web_push::PushOptions {
+			padding: self_peer.padding(),
+			..web_push::PushOptions::default()
+		};
+		let persist = self.persist.borrow();
+		let device = persist.devices.iter()
+			.find(|device| peer_tag(&device.public_key) == device_id)
+			.ok_or(anyhow!("Peer doesn't have a device with that id")).to_js_error()?;
+		prepare_push(
+			device.info.as_ref(),
+			&device.authorizations,
+			&device.public_key,
+			&self_peer.auth_lifetime(),
+			data.as_bytes(),
+			&options
+		).map(PushRequestInfo::from).to_js_error()
+	}
+	// Encrypt and deliver a message to the peer.  Proxies are url prefixes to try, in order, if the push service can't be reached directly.  The promise rejects if the message couldn't be delivered, and a dead subscription is forgotten along the way.
+	pub fn send(&self, self_peer: &SelfPeer, data: String, proxies: Box<[JsValue]>) -> Result<js_sys::Promise, JsValue> {
+		let options = web_push::PushOptions {
+			padding: self_peer.padding(),
+			..web_push::PushOptions::default()
+		};
+		self.check_authorizations(&self_peer.auth_lifetime(), None)?;
+		let request = {
+			let persist = self.persist.borrow();
+			prepare_push(
+				persist.info.as_ref(),
+				&persist.authorizations,
+				&persist.public_key,
+				&self_peer.auth_lifetime(),
+				data.as_bytes(),
+				&options
+			).to_js_error()?
+		};
+		Ok(self.deliver(request, &proxies, None))
+	}
+	// Like send, but the message's kind decides its TTL and urgency, and it replaces older messages of the same kind from this session that are still queued.
+	pub fn send_signaling(&self, self_peer: &SelfPeer, signaling: SignalingMessage, session: String, proxies: Box<[JsValue]>) -> Result<js_sys::Promise, JsValue> {
+		let (data, options) = self_peer.prepare_signaling(signaling, &session).to_js_error()?;
+		self.check_authorizations(&self_peer.auth_lifetime(), None)?;
+		let request = {
+			let persist = self.persist.borrow();
+			prepare_push(
+				persist.info.as_ref(),
+				&persist.authorizations,
+				&persist.public_key,
+				&self_peer.auth_lifetime(),
+				data.as_bytes(),
+				&options
+			).to_js_error()?
+		};
+		Ok(self.deliver(request, &proxies, None))
+	}
+	pub fn send_to_device(&self, self_peer: &SelfPeer, device_id: String, data: String, proxies: Box<[JsValue]>) -> Result<js_sys::Promise, JsValue> {
+		let options = web_push::PushOptions {
+			padding: self_peer.padding(),
+			..web_push::PushOptions::default()
+		};
+		let device_key = self.persist.borrow().devices.iter()
+			.find(|device| peer_tag(&device.public_key) == device_id)
+			.map(|device| device.public_key)
+			.ok_or(anyhow!("Peer doesn't have a device with that id")).to_js_error()?;
+		self.check_authorizations(&self_peer.auth_lifetime(), Some(&device_key))?;
+		let request = {
+			let persist = self.persist.borrow();
+			let device = persist.device(&device_key).ok_or(anyhow!("Peer doesn't have a device with that id")).to_js_error()?;
+			prepare_push(
+				device.info.as_ref(),
+				&device.authorizations,
+				&device.public_key,
+				&self_peer.auth_lifetime(),
+				data.as_bytes(),
+				&options
+			).to_js_error()?
+		};
+		Ok(self.deliver(request, &proxies, Some(device_key)))
+	}
+	// Push authorizations in the message are only kept if they verify against the sender's key and push info.  The handlers are called straight away, and the promise resolves once the push info and authorizations have been saved (rejecting if they couldn't be).
+	pub fn apply_signaling_message(&mut self, self_peer: &SelfPeer, message: signaling::ParsedMessage) -> Result<js_sys::Promise, JsValue> {
+		self.apply(self_peer, message)?;
+		Ok(self.saved())
+	}
+	fn apply(&mut self, self_peer: &SelfPeer, message: signaling::ParsedMessage) -> Result<(), JsValue> {
+		let lifetime = self_peer.auth_lifetime();
+		let now = (js_sys::Date::now() / 1000.0) as u32;
+		receive_signaling(&mut *self.persist.borrow_mut(), message.device_key.as_ref(), message.message.info(), &message.message.auths(), &lifetime, now).to_js_error()?;
+		self.report_coverage(&lifetime, message.device_key.as_ref(), now)?;
 		if self.sdp_handler.is_function() {
 			if let Some((kind, sdp)) = message.message.sdp() {
 				Function::from(self.sdp_handler.clone()).call2(&JsValue::null(), &JsValue::from(kind), &JsValue::from(sdp))?;
@@ -148,55 +600,486 @@ impl Peer {
 		}
 		Ok(())
 	}
-	pub fn new_from_signaling_message(message: signaling::ParsedMessage) -> Result<Peer, JsValue> {
-		let mut new_peer = Peer::new(message.public_key.clone()).to_js_error()?;
-		new_peer.apply_signaling_message(message)?;
-		Ok(new_peer)
-	}
-	pub fn new_from_key(key: String) -> Result<Option<Peer>, JsValue> {
-		Ok(if let Some(persist) = Persist::new_no_create(&key).to_js_error()? {
-			Some(Peer {
-				persist,
-				sdp_handler: JsValue::null(),
-				ice_handler: JsValue::null(),
-				signaling_queue: None
-			})
+	// Call saved on the new peer to know that what the message carried was kept.
+	pub fn new_from_signaling_message(self_peer: &SelfPeer, message: signaling::ParsedMessage) -> Result<Peer, JsValue> {
+		let mut new_peer = if let SignalingFormat::Succession(ref succession, ..) = message.message {
+			if let Some(old_peer) = Peer::load(self_peer, format!("peer.{}", peer_tag(&succession.old_key)))? {
+				old_peer.migrate(message.public_key).to_js_error()?
+			} else {
+				Peer::open(self_peer.storage().clone(), message.public_key).to_js_error()?
+			}
 		} else {
-			None
-		})
+			Peer::open(self_peer.storage().clone(), message.public_key).to_js_error()?
+		};
+		new_peer.apply(self_peer, message)?;
+		Ok(new_peer)
 	}
 	pub fn delete(self) -> Result<(), JsValue> {
-		self.persist.delete().to_js_error()
+		delete_record(&self.persist.borrow()).to_js_error()
 	}
 	pub fn set_extra(&mut self, key: String, value: String) -> Result<(), JsValue> {
-		self.persist.make_change(|persist| {
-			persist.extra.insert(key, value);
+		self.persist.borrow_mut().make_change(|persist| {
+			persist.extra.insert(key.clone(), value.clone());
 		}).to_js_error()
 	}
 	pub fn get_extra(&mut self, key: String) -> Option<String> {
-		self.persist.extra.get(&key).cloned()
+		self.persist.borrow().extra.get(&key).cloned()
+	}
+	pub fn is_verified(&self) -> bool {
+		let persist = self.persist.borrow();
+		persist.verified.as_ref() == Some(&persist.public_key)
+	}
+	pub fn set_verified(&mut self, verified: bool) -> Result<(), JsValue> {
+		self.persist.borrow_mut().make_change(|persist| {
+			persist.verified = if verified { Some(persist.public_key) } else { None };
+		}).to_js_error()
 	}
 }
 impl Peer {
-	pub fn new(public_key: crypto::PublicKey) -> Result<Self, anyhow::Error> {
-		Ok(Self {
-			persist: Persist::new(
-				&format!("peer.{}", peer_tag(&public_key)),
-				|| {
-					PeerPersist {
-						public_key,
-						info: None,
-						authorizations: Vec::new(),
-						extra: HashMap::new()
-					}
-				}
-			)?,
+	// Load the peer with this key from storage, creating a record for it if there isn't one.
+	pub fn open(storage: Backend, public_key: crypto::PublicKey) -> Result<Self, anyhow::Error> {
+		Ok(Self::from_persist(open_record(&storage, public_key)?))
+	}
+	fn from_persist(persist: Persist<PeerPersist>) -> Self {
+		Self {
+			persist: Rc::new(RefCell::new(persist)),
 			sdp_handler: JsValue::null(),
 			ice_handler: JsValue::null(),
-			signaling_queue: None
+			auth_low_handler: JsValue::null()
+		}
+	}
+	// The handlers carry over to the peer under its new key.
+	fn migrate(self, public_key: crypto::PublicKey) -> Result<Self, anyhow::Error> {
+		let persist = migrate_record(&self.persist.borrow(), public_key)?;
+		Ok(Self {
+			sdp_handler: self.sdp_handler,
+			ice_handler: self.ice_handler,
+			..Self::from_persist(persist)
+		})
+	}
+	pub fn public_key(&self) -> crypto::PublicKey {
+		self.persist.borrow().public_key
+	}
+	fn deliver(&self, request: web_push::PushRequest, proxies: &[JsValue], device_key: Option<crypto::PublicKey>) -> js_sys::Promise {
+		let persist = self.persist.clone();
+		let proxies: Vec<String> = proxies.iter().filter_map(JsValue::as_string).collect();
+		wasm_bindgen_futures::future_to_promise(async move {
+			let outcome = delivery::deliver(&request, &proxies, &delivery::RetryPolicy::default()).await.to_js_error()?;
+			match outcome {
+				delivery::Outcome::Delivered => Ok(JsValue::undefined()),
+				delivery::Outcome::Gone => {
+					// The subscription is dead, so its authorizations are useless too.
+					let now = (js_sys::Date::now() / 1000.0) as u32;
+					persist.borrow_mut().make_change(|persist| {
+						if let Some(ref device_key) = device_key {
+							let device = persist.device_mut(device_key);
+							device.info = None;
+							device.authorizations.clear(now);
+						} else {
+							persist.info = None;
+							persist.authorizations.clear(now);
+						}
+					}).to_js_error()?;
+					Err(anyhow!("Push subscription is gone")).to_js_error()
+				},
+				outcome => Err(anyhow!("Push delivery failed: {:?}", outcome)).to_js_error()
+			}
 		})
 	}
+	// Drop expired authorizations and let the app know if coverage is running low, so that it can ask the peer for more.
+	fn check_authorizations(&self, lifetime: &web_push::AuthLifetime, device_key: Option<&crypto::PublicKey>) -> Result<(), JsValue> {
+		let now = (js_sys::Date::now() / 1000.0) as u32;
+		// Another tab may have received authorizations (or used up the ones we know about).
+		self.reload()?;
+		let mut persist = self.persist.borrow_mut();
+		let authorizations = match device_key {
+			Some(device_key) => match persist.device(device_key) {
+				Some(device) => &device.authorizations,
+				None => return Ok(())
+			},
+			None => &persist.authorizations
+		};
+		if authorizations.has_expired(lifetime, now) {
+			persist.make_change(|persist| match device_key {
+				Some(device_key) => persist.device_mut(device_key).authorizations.prune(lifetime, now),
+				None => persist.authorizations.prune(lifetime, now)
+			}).to_js_error()?;
+		}
+		drop(persist);
+		self.report_coverage(lifetime, device_key, now)
+	}
+	fn report_coverage(&self, lifetime: &web_push::AuthLifetime, device_key: Option<&crypto::PublicKey>, now: u32) -> Result<(), JsValue> {
+		let coverage = {
+			let persist = self.persist.borrow();
+			match device_key {
+				Some(device_key) => match persist.device(device_key) {
+					Some(device) => device.authorizations.coverage(lifetime, now),
+					None => return Ok(())
+				},
+				None => persist.authorizations.coverage(lifetime, now)
+			}
+		};
+		if coverage < RUNNING_LOW && self.auth_low_handler.is_function() {
+			let device_id = device_key.map(|key| JsValue::from(peer_tag(key))).unwrap_or(JsValue::null());
+			Function::from(self.auth_low_handler.clone()).call2(&JsValue::null(), &JsValue::from(coverage), &device_id)?;
+		}
+		Ok(())
+	}
 	pub fn pk_magnitude(&self) -> p256::Scalar {
-		p256::Scalar::from_bytes_reduced(self.persist.public_key.compress().x())
+		p256::Scalar::from_bytes_reduced(self.persist.borrow().public_key.compress().x().unwrap())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::persist::MemoryStorage;
+	use super::super::jwt;
+	use super::super::succession::Succession;
+
+	const NOW: u32 = 1_601_337_600;
+
+	fn secret(b: u8) -> crypto::SecretKey {
+		crypto::SecretKey::from(p256::SecretKey::from_bytes([b; 32]).unwrap())
 	}
-}
\ No newline at end of file
+	fn info() -> web_push::PushInfo {
+		web_push::PushInfo {
+			endpoint: String::from("https://updates.push.services.mozilla.com/wpush/v2/import"),
+			auth: [7; 16],
+			public_key: p256::EncodedPoint::from_secret_key(&secret(4), false).into(),
+			encoding: web_push::ContentEncoding::Aes128Gcm
+		}
+	}
+	// Authorizations for consecutive slots starting at now.
+	fn tokens(signer: &crypto::SecretKey, lifetime: &web_push::AuthLifetime, now: u32, slots: std::ops::Range<u32>) -> Vec<web_push::AuthToken> {
+		slots.map(|slot| {
+			let claims = jwt::Claims {
+				aud: jwt::audience(&info().endpoint).unwrap(),
+				exp: lifetime.expiration(now, slot).unwrap(),
+				sub: String::from("mailto:no-reply@example.com")
+			};
+			web_push::AuthToken {
+				signature: claims.sign(signer, rand::thread_rng()).unwrap(),
+				expiration: claims.exp,
+				subscriber: claims.sub
+			}
+		}).collect()
+	}
+
+	// A peer as it was stored at each version, using the struct definitions from history: key [2; 32], push info for ".../fixture2", one authorization and an extra "name".  From v1 it has a device with key [3; 32] and push info for ".../fixture3", and from v2 it's verified.
+	const FIXTURES: [&str; 4] = [
+		"QQAAAAAAAAAEVQ9HEAPz35fD31Bqx5f2ch+xoft7j2+D0iRJimXIjiQTYJPXAS5QmnNxXL0LAKPMD/S1wBs/+hlqsfsycDa45gE7AAAAAAAAAGh0dHBzOi8vdXBkYXRlcy5wdXNoLnNlcnZpY2VzLm1vemlsbGEuY29tL3dwdXNoL3YyL2ZpeHR1cmUyAgICAgICAgICAgICAgICAkEAAAAAAAAABEKtlx0DfRqyvt/OPxf5fbrRIF6nzHWgT/WC3cpWoL84H9ul5SQtUc52DyfAPhI3nYNy2cHJz5uqsdVYsRfHuQEBAAAAAAAAABoAAAAAAAAAbWFpbHRvOmZpeHR1cmVAZXhhbXBsZS5jb20AeXJfQAAAAAAAAAABAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAQAAAAAAAAAEAAAAAAAAAG5hbWUFAAAAAAAAAEFsaWNl",
+		"QQAAAAAAAAAEVQ9HEAPz35fD31Bqx5f2ch+xoft7j2+D0iRJimXIjiQTYJPXAS5QmnNxXL0LAKPMD/S1wBs/+hlqsfsycDa45gE7AAAAAAAAAGh0dHBzOi8vdXBkYXRlcy5wdXNoLnNlcnZpY2VzLm1vemlsbGEuY29tL3dwdXNoL3YyL2ZpeHR1cmUyAgICAgICAgICAgICAgICAkEAAAAAAAAABEKtlx0DfRqyvt/OPxf5fbrRIF6nzHWgT/WC3cpWoL84H9ul5SQtUc52DyfAPhI3nYNy2cHJz5uqsdVYsRfHuQEBAAAAAAAAABoAAAAAAAAAbWFpbHRvOmZpeHR1cmVAZXhhbXBsZS5jb20AeXJfQAAAAAAAAAABAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAQAAAAAAAAAEAAAAAAAAAG5hbWUFAAAAAAAAAEFsaWNlAQAAAAAAAABBAAAAAAAAAARZGrdx67z9bZy5CU0QZSit0aadRMLB9ifwiexYucYa359Oar8NBFwMaTo8aK18l8pyvmTe9KJv7NJj3ZipJ4DwATsAAAAAAAAAaHR0cHM6Ly91cGRhdGVzLnB1c2guc2VydmljZXMubW96aWxsYS5jb20vd3B1c2gvdjIvZml4dHVyZTMDAwMDAwMDAwMDAwMDAwMDQQAAAAAAAAAE9/hX9RYgIdTpH2WENm5pt326eQEGBaxcUHtROJUfwkKq14bouPBf19xNL1zeE7iNjm79FCTEzdemZSwjgJVQiwAAAAAAAAAA",
+		"QQAAAAAAAAAEVQ9HEAPz35fD31Bqx5f2ch+xoft7j2+D0iRJimXIjiQTYJPXAS5QmnNxXL0LAKPMD/S1wBs/+hlqsfsycDa45gE7AAAAAAAAAGh0dHBzOi8vdXBkYXRlcy5wdXNoLnNlcnZpY2VzLm1vemlsbGEuY29tL3dwdXNoL3YyL2ZpeHR1cmUyAgICAgICAgICAgICAgICAkEAAAAAAAAABEKtlx0DfRqyvt/OPxf5fbrRIF6nzHWgT/WC3cpWoL84H9ul5SQtUc52DyfAPhI3nYNy2cHJz5uqsdVYsRfHuQEBAAAAAAAAABoAAAAAAAAAbWFpbHRvOmZpeHR1cmVAZXhhbXBsZS5jb20AeXJfQAAAAAAAAAABAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAQAAAAAAAAAEAAAAAAAAAG5hbWUFAAAAAAAAAEFsaWNlAQAAAAAAAABBAAAAAAAAAARZGrdx67z9bZy5CU0QZSit0aadRMLB9ifwiexYucYa359Oar8NBFwMaTo8aK18l8pyvmTe9KJv7NJj3ZipJ4DwATsAAAAAAAAAaHR0cHM6Ly91cGRhdGVzLnB1c2guc2VydmljZXMubW96aWxsYS5jb20vd3B1c2gvdjIvZml4dHVyZTMDAwMDAwMDAwMDAwMDAwMDQQAAAAAAAAAE9/hX9RYgIdTpH2WENm5pt326eQEGBaxcUHtROJUfwkKq14bouPBf19xNL1zeE7iNjm79FCTEzdemZSwjgJVQiwAAAAAAAAAAAUEAAAAAAAAABFUPRxAD89+Xw99QaseX9nIfsaH7e49vg9IkSYplyI4kE2CT1wEuUJpzcVy9CwCjzA/0tcAbP/oZarH7MnA2uOY=",
+		"QQAAAAAAAAAEVQ9HEAPz35fD31Bqx5f2ch+xoft7j2+D0iRJimXIjiQTYJPXAS5QmnNxXL0LAKPMD/S1wBs/+hlqsfsycDa45gE7AAAAAAAAAGh0dHBzOi8vdXBkYXRlcy5wdXNoLnNlcnZpY2VzLm1vemlsbGEuY29tL3dwdXNoL3YyL2ZpeHR1cmUyAgICAgICAgICAgICAgICAkEAAAAAAAAABEKtlx0DfRqyvt/OPxf5fbrRIF6nzHWgT/WC3cpWoL84H9ul5SQtUc52DyfAPhI3nYNy2cHJz5uqsdVYsRfHuQEBAAAAAQAAAAAAAAAaAAAAAAAAAG1haWx0bzpmaXh0dXJlQGV4YW1wbGUuY29tAHlyX0AAAAAAAAAAAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQECAgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgEAAAAAAAAABAAAAAAAAABuYW1lBQAAAAAAAABBbGljZQEAAAAAAAAAQQAAAAAAAAAEWRq3ceu8/W2cuQlNEGUordGmnUTCwfYn8InsWLnGGt+fTmq/DQRcDGk6PGitfJfKcr5k3vSib+zSY92YqSeA8AE7AAAAAAAAAGh0dHBzOi8vdXBkYXRlcy5wdXNoLnNlcnZpY2VzLm1vemlsbGEuY29tL3dwdXNoL3YyL2ZpeHR1cmUzAwMDAwMDAwMDAwMDAwMDA0EAAAAAAAAABPf4V/UWICHU6R9lhDZuabd9unkBBgWsXFB7UTiVH8JCqteG6LjwX9fcTS9c3hO4jY5u/RQkxM3XpmUsI4CVUIsBAAAAAAAAAAAAAAABQQAAAAAAAAAEVQ9HEAPz35fD31Bqx5f2ch+xoft7j2+D0iRJimXIjiQTYJPXAS5QmnNxXL0LAKPMD/S1wBs/+hlqsfsycDa45g=="
+	];
+	#[test]
+	fn indexed() {
+		let storage = Backend::Memory(MemoryStorage::default());
+		let key = |b| crypto::SecretKey::from(p256::SecretKey::from_bytes([b; 32]).unwrap()).public_key();
+		let first = Peer::open(storage.clone(), key(1)).unwrap();
+		let second = Peer::open(storage.clone(), key(2)).unwrap();
+		let mut expected = vec![first.peer_id(), second.peer_id()];
+		expected.sort();
+		assert_eq!(peer_ids(&storage).unwrap(), expected);
+		// Opening a known peer doesn't add it twice.
+		Peer::open(storage.clone(), key(1)).unwrap();
+		assert_eq!(peer_ids(&storage).unwrap().len(), 2);
+
+		// Even with a delivery in flight:
+		let removed = first.peer_id();
+		let in_flight = first.persist.clone();
+		delete_record(&first.persist.borrow()).unwrap();
+		assert_eq!(peer_ids(&storage).unwrap(), vec![second.peer_id()]);
+		assert!(storage.get(&peer_index::record_key(&removed)).unwrap().is_none());
+		// Which can't bring it back when it finishes.
+		assert!(in_flight.borrow_mut().make_change(|data| data.verified = None).is_err());
+		assert!(storage.get(&peer_index::record_key(&removed)).unwrap().is_none());
+	}
+	#[test]
+	fn migrates_on_succession() {
+		let (old, new, device, lifetime) = (secret(2), secret(3), secret(5), web_push::AuthLifetime::default());
+		let storage = Backend::Memory(MemoryStorage::default());
+		let mut persist = open_record(&storage, old.public_key()).unwrap();
+		persist.make_change(|data| {
+			data.info = Some(info());
+			data.authorizations.receive(&tokens(&old, &lifetime, NOW, 0..2), &info(), &old.public_key(), &lifetime, NOW);
+			data.device_mut(&device.public_key()).info = Some(info());
+			data.extra.insert(String::from("name"), String::from("Alice"));
+			data.verified = Some(old.public_key());
+		}).unwrap();
+
+		let succession = Succession::create(&old, &new, rand::thread_rng());
+		succession.verify().unwrap();
+		let migrated = migrate_record(&persist, succession.new_key).unwrap();
+		assert_eq!(migrated.public_key, new.public_key());
+		assert_eq!(migrated.verified, None);
+		assert_eq!(migrated.info, Some(info()));
+		assert_eq!(migrated.device(&device.public_key()).unwrap().info, Some(info()));
+		assert_eq!(migrated.extra.get("name").map(String::as_str), Some("Alice"));
+		// Signed by the old key, so no use any more.
+		assert!(migrated.authorizations.tokens().is_empty());
+		// The old record is gone, and the index follows.
+		assert!(storage.get(&peer_index::record_key(&peer_tag(&old.public_key()))).unwrap().is_none());
+		assert_eq!(peer_ids(&storage).unwrap(), vec![peer_tag(&new.public_key())]);
+	}
+	#[test]
+	fn found_without_the_index() {
+		let storage = Backend::Memory(MemoryStorage::default());
+		let (indexed, unindexed) = (secret(2).public_key(), secret(3).public_key());
+		let indexed = Peer::open(storage.clone(), indexed).unwrap();
+		// A record whose index entry never made it, and an entry whose record is gone:
+		let record = persist::encode(&PeerPersist::new(unindexed)).unwrap();
+		storage.set(&peer_index::record_key(&peer_tag(&unindexed)), &record).unwrap();
+		storage.remove(&peer_index::record_key(&indexed.peer_id())).unwrap();
+
+		assert_eq!(find_record(&storage, &peer_tag(&unindexed)).unwrap().unwrap().public_key, unindexed);
+		assert!(find_record(&storage, &indexed.peer_id()).unwrap().is_none());
+		assert_eq!(peer_ids(&storage).unwrap(), vec![peer_tag(&unindexed)]);
+	}
+	#[test]
+	fn import_merges() {
+		let lifetime = web_push::AuthLifetime::default();
+		let (signer, other) = (secret(2), secret(3));
+		let info = info();
+		let tokens = tokens(&signer, &lifetime, NOW, 0..2);
+
+		let storage = Backend::Memory(MemoryStorage::default());
+		let ours = Peer::open(storage.clone(), signer.public_key()).unwrap();
+		ours.persist.borrow_mut().make_change(|data| {
+			data.info = Some(info.clone());
+			data.authorizations.receive(&tokens[..1], &info, &signer.public_key(), &lifetime, NOW);
+			data.extra.insert(String::from("name"), String::from("Alice"));
+		}).unwrap();
+
+		let mut theirs = PeerPersist::new(signer.public_key());
+		theirs.info = Some(info.clone());
+		theirs.authorizations.receive(&tokens, &info, &signer.public_key(), &lifetime, NOW);
+		theirs.extra.insert(String::from("name"), String::from("Al"));
+		theirs.extra.insert(String::from("city"), String::from("Paris"));
+		let records = vec![
+			(ours.peer_id(), persist::encode(&theirs).unwrap()),
+			(peer_tag(&other.public_key()), persist::encode(&PeerPersist::new(other.public_key())).unwrap())
+		];
+
+		// A record filed under the wrong peer is refused before anything is written.
+		let mislabeled = vec![records[0].clone(), (records[0].0.clone(), records[1].1.clone())];
+		assert!(import_records(&storage, mislabeled, &lifetime, NOW).is_err());
+		assert_eq!(peer_ids(&storage).unwrap().len(), 1);
+
+		let report = import_records(&storage, records.clone(), &lifetime, NOW).unwrap();
+		assert_eq!(report.added, vec![peer_tag(&other.public_key())]);
+		assert_eq!(report.merged, vec![ours.peer_id()]);
+		assert_eq!(report.conflicts, vec![Conflict { peer_id: ours.peer_id(), field: String::from("extra.name") }]);
+		ours.persist.borrow_mut().refresh().unwrap();
+		{
+			let data = ours.persist.borrow();
+			assert_eq!(data.authorizations.len(), 2);
+			assert_eq!(data.extra.get("name").map(String::as_str), Some("Alice"));
+			assert_eq!(data.extra.get("city").map(String::as_str), Some("Paris"));
+		}
+		assert_eq!(peer_ids(&storage).unwrap().len(), 2);
+
+		// Importing the same archive again doesn't duplicate anything.
+		import_records(&storage, records.clone(), &lifetime, NOW).unwrap();
+		ours.persist.borrow_mut().refresh().unwrap();
+		assert_eq!(ours.persist.borrow().authorizations.len(), 2);
+
+		// A peer that can't be merged doesn't stop the others.
+		storage.set(&peer_index::record_key(&peer_tag(&other.public_key())), "corrupted").unwrap();
+		let report = import_records(&storage, records, &lifetime, NOW).unwrap();
+		assert_eq!(report.merged, vec![ours.peer_id()]);
+		assert_eq!(report.failed.len(), 1);
+		assert_eq!(report.failed[0].peer_id, peer_tag(&other.public_key()));
+	}
+	#[test]
+	fn maintenance() {
+		const WEEK: u32 = 7 * 24 * 60 * 60;
+		let lifetime = web_push::AuthLifetime::default();
+		let storage = Backend::Memory(MemoryStorage::default());
+		let (a, b, c) = (secret(2), secret(3), secret(5));
+		let open = |key: &crypto::SecretKey, change: &dyn Fn(&mut PeerPersist)| {
+			let peer = Peer::open(storage.clone(), key.public_key()).unwrap();
+			peer.persist.borrow_mut().make_change(|data| change(data)).unwrap();
+			peer.peer_id()
+		};
+		// b was last reachable a week before now, a still is (and has an expired authorization left over), and c never was.
+		let b_tokens = tokens(&b, &lifetime, NOW, 0..1);
+		let b_until = lifetime.usable_until(b_tokens[0].expiration);
+		let now = b_until + WEEK;
+		let a_tokens = [tokens(&a, &lifetime, NOW, 0..1), tokens(&a, &lifetime, now, 0..2)].concat();
+		let a_id = open(&a, &|data| {
+			data.info = Some(info());
+			data.authorizations.receive(&a_tokens[..1], &info(), &a.public_key(), &lifetime, NOW);
+			data.authorizations.receive(&a_tokens[1..], &info(), &a.public_key(), &lifetime, now);
+		});
+		let b_id = open(&b, &|data| {
+			data.info = Some(info());
+			data.authorizations.receive(&b_tokens, &info(), &b.public_key(), &lifetime, NOW);
+		});
+		let c_id = open(&c, &|_| {});
+		let snapshot = |peer_id: &str| storage.get(&peer_index::record_key(peer_id)).unwrap();
+		let before = snapshot(&a_id);
+
+		let policy = maintenance::Policy { max_age: WEEK, disposal: maintenance::Disposal::Archive };
+		let archived = |records: Vec<(String, String)>| Ok(records.into_iter().map(|(peer_id, _)| peer_id).collect::<Vec<_>>().join(","));
+		let report = maintain(&storage, &policy, &lifetime, now, true, archived).unwrap();
+		let usage = |report: &maintenance::Report, peer_id: &str| report.peers.iter().find(|usage| usage.peer_id == peer_id).cloned().unwrap();
+		assert_eq!(report.peers.len(), 3);
+		assert_eq!(usage(&report, &a_id).expired_authorizations, 1);
+		assert!(usage(&report, &a_id).compacted_bytes < usage(&report, &a_id).bytes);
+		assert!(!usage(&report, &a_id).collected);
+		assert_eq!(usage(&report, &b_id).last_reachable, Some(b_until));
+		assert!(usage(&report, &b_id).collected);
+		assert_eq!(usage(&report, &c_id).last_reachable, None);
+		assert!(!usage(&report, &c_id).collected);
+		// A dry run doesn't change anything.
+		assert_eq!(report.archive, None);
+		assert_eq!(snapshot(&a_id), before);
+		assert_eq!(peer_ids(&storage).unwrap().len(), 3);
+
+		let survey = report;
+		let report = maintain(&storage, &policy, &lifetime, now, false, archived).unwrap();
+		assert_eq!(report.archive, Some(b_id.clone()));
+		// The survey's sizes are what the rewritten records take up.
+		for peer_id in &[&a_id, &c_id] {
+			assert_eq!(usage(&survey, peer_id).compacted_bytes, peer_index::record_key(peer_id).len() + snapshot(peer_id).unwrap().len());
+		}
+		// b stays until the archive has been kept, and then only if it hasn't changed since.
+		let b_record = snapshot(&b_id).unwrap();
+		assert_eq!(peer_ids(&storage).unwrap().len(), 3);
+		let archived_records = [(b_id.clone(), b_record), (a_id.clone(), before.clone().unwrap())];
+		assert_eq!(remove_archived(&storage, &archived_records).unwrap(), vec![b_id.clone()]);
+		assert!(snapshot(&b_id).is_none());
+		let mut expected = vec![a_id.clone(), c_id.clone()];
+		expected.sort();
+		assert_eq!(peer_ids(&storage).unwrap(), expected);
+		let a_data = persist::decode::<PeerPersist>(&snapshot(&a_id).unwrap()).unwrap();
+		assert_eq!(a_data.authorizations.len(), 2);
+		// c's age is counted from the first pass that saw it.
+		let c_data = persist::decode::<PeerPersist>(&snapshot(&c_id).unwrap()).unwrap();
+		assert_eq!(c_data.last_reachable(&lifetime), Some(now));
+
+		// Nothing's left to compact, and c isn't collected until a week after that.
+		let report = maintain(&storage, &policy, &lifetime, now, false, archived).unwrap();
+		assert_eq!(report.total_bytes, report.compacted_bytes);
+		let report = maintain(&storage, &policy, &lifetime, now + WEEK, true, archived).unwrap();
+		assert!(usage(&report, &c_id).collected);
+		let policy = maintenance::Policy { disposal: maintenance::Disposal::Delete, ..policy };
+		let report = maintain(&storage, &policy, &lifetime, now + WEEK, false, archived).unwrap();
+		assert_eq!(report.removed, vec![c_id.clone()]);
+		assert!(snapshot(&c_id).is_none());
+		assert_eq!(peer_ids(&storage).unwrap(), vec![a_id.clone()]);
+	}
+	#[test]
+	fn historical_layouts() {
+		let public_key = crypto::SecretKey::from(p256::SecretKey::from_bytes([2; 32]).unwrap()).public_key();
+		let (peer_id, lifetime) = (peer_tag(&public_key), web_push::AuthLifetime::default());
+		let key = peer_index::record_key(&peer_id);
+		for (version, fixture) in FIXTURES.iter().enumerate() {
+			let storage = Backend::Memory(MemoryStorage::default());
+			storage.set(&key, fixture).unwrap();
+			assert_eq!(rebuild_index(&storage).unwrap(), 1);
+			let data = Persist::<PeerPersist>::open_existing(storage.clone(), &key).unwrap().unwrap();
+			let encoding = if version < 3 { web_push::ContentEncoding::AesGcm } else { web_push::ContentEncoding::Aes128Gcm };
+			assert_eq!(data.public_key, public_key);
+			assert!(data.info.as_ref().unwrap().endpoint.ends_with("/fixture2"));
+			assert_eq!(data.info.as_ref().unwrap().encoding, encoding);
+			assert_eq!(data.authorizations.len(), 1);
+			assert_eq!(data.extra.get("name").map(String::as_str), Some("Alice"));
+			if version < 1 {
+				assert!(data.devices.is_empty());
+			} else {
+				let device = &data.devices[0];
+				assert!(device.info.as_ref().unwrap().endpoint.ends_with("/fixture3"));
+				assert_eq!(device.info.as_ref().unwrap().encoding, encoding);
+				assert!(device.authorizations.tokens().is_empty());
+			}
+			assert_eq!(data.verified.is_some(), version >= 2);
+			// It's been written back in the current layout.
+			assert!(storage.get(&key).unwrap().unwrap().starts_with(&format!("v{}.", PeerPersist::MIGRATIONS.len())));
+
+			// Archived records are migrated on import too.
+			let imported = Backend::Memory(MemoryStorage::default());
+			assert_eq!(import_records(&imported, vec![(peer_id.clone(), fixture.to_string())], &lifetime, NOW).unwrap().added, vec![peer_id.clone()]);
+			let data = Persist::<PeerPersist>::open_existing(imported, &key).unwrap().unwrap();
+			assert_eq!(data.info.as_ref().unwrap().encoding, encoding);
+			assert_eq!(data.extra.get("name").map(String::as_str), Some("Alice"));
+		}
+	}
+	// Lets another context's write land just before our next save.
+	#[derive(Clone, Default)]
+	struct Racing {
+		inner: MemoryStorage,
+		theirs: Rc<RefCell<Option<String>>>
+	}
+	impl Storage for Racing {
+		fn get(&self, key: &str) -> Result<Option<String>, anyhow::Error> {
+			self.inner.get(key)
+		}
+		fn set(&self, key: &str, value: &str) -> Result<(), anyhow::Error> {
+			self.inner.set(key, value)
+		}
+		fn remove(&self, key: &str) -> Result<(), anyhow::Error> {
+			self.inner.remove(key)
+		}
+		fn keys(&self) -> Result<Vec<String>, anyhow::Error> {
+			self.inner.keys()
+		}
+		fn replace(&self, key: &str, expected: Option<&str>, value: &str) -> Result<(), anyhow::Error> {
+			if let Some(theirs) = self.theirs.borrow_mut().take() {
+				self.inner.set(key, &theirs)?;
+			}
+			self.inner.replace(key, expected, value)
+		}
+	}
+	#[test]
+	fn received_during_another_write() {
+		let (signer, device, lifetime) = (secret(2), secret(3), web_push::AuthLifetime::default());
+		let storage = Racing::default();
+		let key = peer_index::record_key(&peer_tag(&signer.public_key()));
+		let mut persist = Persist::open(storage.clone(), &key, || PeerPersist::new(signer.public_key())).unwrap();
+		// Another tab names the peer while we're receiving from it, and then from its device:
+		let mut theirs = PeerPersist::new(signer.public_key());
+		theirs.extra.insert(String::from("name"), String::from("Alice"));
+		*storage.theirs.borrow_mut() = Some(persist::encode_at(&theirs, 1).unwrap());
+		receive_signaling(&mut persist, None, Some(info()), &tokens(&signer, &lifetime, NOW, 0..2), &lifetime, NOW).unwrap();
+		theirs.info = persist.info.clone();
+		theirs.authorizations = persist.authorizations.clone();
+		theirs.extra.insert(String::from("city"), String::from("Paris"));
+		*storage.theirs.borrow_mut() = Some(persist::encode_at(&theirs, persist::revision(&storage.get(&key).unwrap().unwrap()) + 1).unwrap());
+		receive_signaling(&mut persist, Some(&device.public_key()), Some(info()), &tokens(&device, &lifetime, NOW, 0..1), &lifetime, NOW).unwrap();
+
+		// Both of their changes and everything we received made it.
+		let data = Persist::<PeerPersist, _>::open_existing(storage, &key).unwrap().unwrap();
+		assert_eq!(data.extra.get("name").map(String::as_str), Some("Alice"));
+		assert_eq!(data.extra.get("city").map(String::as_str), Some("Paris"));
+		assert_eq!(data.info, Some(info()));
+		assert_eq!(data.authorizations.len(), 2);
+		assert_eq!(data.device(&device.public_key()).unwrap().authorizations.len(), 1);
+	}
+	#[test]
+	fn resubscribed() {
+		let (signer, lifetime) = (secret(1), web_push::AuthLifetime::default());
+		let (mut held_info, mut authorizations) = (None, AuthStore::default());
+		let old = tokens(&signer, &lifetime, NOW, 0..3);
+		receive_push(&mut held_info, &mut authorizations, Some(info()), &old, &signer.public_key(), &lifetime, NOW);
+		assert_eq!(authorizations.len(), 3);
+		// The same info again keeps what we have.
+		receive_push(&mut held_info, &mut authorizations, Some(info()), &[], &signer.public_key(), &lifetime, NOW);
+		assert_eq!(authorizations.len(), 3);
+
+		// New keys, followed by authorizations for the same slots:
+		let new_info = web_push::PushInfo {
+			public_key: p256::EncodedPoint::from_secret_key(&secret(5), false).into(),
+			..info()
+		};
+		receive_push(&mut held_info, &mut authorizations, Some(new_info.clone()), &[], &signer.public_key(), &lifetime, NOW);
+		assert!(authorizations.tokens().is_empty());
+		let new = tokens(&signer, &lifetime, NOW, 0..3);
+		receive_push(&mut held_info, &mut authorizations, None, &new, &signer.public_key(), &lifetime, NOW);
+		assert_eq!(authorizations.len(), 3);
+		assert_eq!(authorizations.find(&new_info, &signer.public_key(), &lifetime, NOW), Some(&new[0]));
+		assert_eq!(held_info, Some(new_info));
+	}
+}
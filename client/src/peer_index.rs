@@ -0,0 +1,99 @@
+use serde::{ Serialize, Deserialize };
+use std::collections::BTreeSet;
+
+use super::persist::{self, Persist, Backend, Storage, Versioned, Migration};
+
+const KEY: &str = "peer_index";
+pub const PREFIX: &str = "peer.";
+
+// The ids (peer_tag) of every peer that has a record, so that finding peers doesn't mean sorting through everything else the origin stores.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PeerIndex {
+	peers: BTreeSet<String>
+}
+impl Versioned for PeerIndex {
+	const MIGRATIONS: &'static [Migration] = &[];
+}
+
+pub fn record_key(peer_id: &str) -> String {
+	format!("{}{}", PREFIX, peer_id)
+}
+// The index is built from what's in storage the first time it's needed.
+fn open<T: Versioned>(storage: &Backend) -> Result<Persist<PeerIndex>, anyhow::Error> {
+	match Persist::open_existing(storage.clone(), KEY)? {
+		Some(index) => Ok(index),
+		None => rebuild::<T>(storage).map(|(index, _)| index)
+	}
+}
+// Index every record under PREFIX that decodes as a T, and drop ids whose record is missing or unreadable.  Returns the index and how many ids were added or dropped.
+pub fn rebuild<T: Versioned>(storage: &Backend) -> Result<(Persist<PeerIndex>, usize), anyhow::Error> {
+	let peers: BTreeSet<String> = storage.keys()?.into_iter()
+		.filter_map(|key| key.strip_prefix(PREFIX).map(String::from))
+		.filter(|peer_id| match storage.get(&record_key(peer_id)) {
+			Ok(Some(encoded)) => persist::decode::<T>(&encoded).is_ok(),
+			_ => false
+		})
+		.collect();
+	let mut index = Persist::open(storage.clone(), KEY, PeerIndex::default)?;
+	let repaired = index.peers.symmetric_difference(&peers).count();
+	if repaired > 0 {
+		index.make_change(|index| index.peers = peers.clone())?;
+	}
+	Ok((index, repaired))
+}
+pub fn list<T: Versioned>(storage: &Backend) -> Result<Vec<String>, anyhow::Error> {
+	Ok(open::<T>(storage)?.peers.iter().cloned().collect())
+}
+pub fn count<T: Versioned>(storage: &Backend) -> Result<usize, anyhow::Error> {
+	Ok(open::<T>(storage)?.peers.len())
+}
+pub fn insert<T: Versioned>(storage: &Backend, peer_id: &str) -> Result<(), anyhow::Error> {
+	let mut index = open::<T>(storage)?;
+	// Peers are opened for every message they send, so only write when something changed.
+	if !index.peers.contains(peer_id) {
+		index.make_change(|index| index.peers.insert(peer_id.into()))?;
+	}
+	Ok(())
+}
+pub fn remove<T: Versioned>(storage: &Backend, peer_id: &str) -> Result<(), anyhow::Error> {
+	let mut index = open::<T>(storage)?;
+	if index.peers.contains(peer_id) {
+		index.make_change(|index| index.peers.remove(peer_id))?;
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::persist::MemoryStorage;
+
+	#[derive(Serialize, Deserialize, Debug, Clone)]
+	struct Record(u32);
+	impl Versioned for Record {
+		const MIGRATIONS: &'static [Migration] = &[];
+	}
+
+	#[test]
+	fn index() {
+		let memory = MemoryStorage::default();
+		let storage = Backend::Memory(memory.clone());
+		memory.set("self_peer", "not a peer").unwrap();
+		memory.set("peer.a", &persist::encode(&Record(1)).unwrap()).unwrap();
+		memory.set("peer.corrupt", "!!").unwrap();
+
+		// Built on first use, with only the real peers:
+		assert_eq!(list::<Record>(&storage).unwrap(), vec![String::from("a")]);
+		insert::<Record>(&storage, "b").unwrap();
+		assert_eq!(list::<Record>(&storage).unwrap(), vec![String::from("a"), String::from("b")]);
+		assert_eq!(count::<Record>(&storage).unwrap(), 2);
+		remove::<Record>(&storage, "a").unwrap();
+		assert_eq!(list::<Record>(&storage).unwrap(), vec![String::from("b")]);
+
+		// "a" still has a record and "b" never did, so a rebuild swaps them back.
+		let (index, repaired) = rebuild::<Record>(&storage).unwrap();
+		assert_eq!(repaired, 2);
+		assert_eq!(index.peers.iter().collect::<Vec<_>>(), vec!["a"]);
+		assert_eq!(rebuild::<Record>(&storage).unwrap().1, 0);
+	}
+}
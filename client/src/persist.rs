@@ -1,69 +1,720 @@
 use serde::{Serialize, de::DeserializeOwned};
-use web_sys;
 use anyhow::{ Context, anyhow };
-use base64;
-use std::ops::Deref;
+use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::borrow::Cow;
+use bincode::Options;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
 
+use super::idb::IndexedDb;
+
+// Somewhere to keep encoded values by key.  Values are the base64 strings produced by encode, so every backend stores exactly what localStorage always has.
+pub trait Storage {
+	fn get(&self, key: &str) -> Result<Option<String>, anyhow::Error>;
+	// Succeeding means the value is saved, except on IndexedDB where it's only been queued (see Backend::saved).
+	fn set(&self, key: &str, value: &str) -> Result<(), anyhow::Error>;
+	fn remove(&self, key: &str) -> Result<(), anyhow::Error>;
+	fn keys(&self) -> Result<Vec<String>, anyhow::Error>;
+	// Set the value only if the entry still holds expected (None meaning there isn't one), failing with ChangedElsewhere if it doesn't.  Backends that can make the check and the write one step should.
+	fn replace(&self, key: &str, expected: Option<&str>, value: &str) -> Result<(), anyhow::Error> {
+		if self.get(key)?.as_deref() != expected {
+			return Err(ChangedElsewhere.into());
+		}
+		self.set(key, value)
+	}
+}
+
+// A save that lost a race with another context's.  make_change tries again on top of theirs.
 #[derive(Debug)]
-pub struct Persist<T> {
-	key: String,
-	value: T
+pub struct ChangedElsewhere;
+impl std::fmt::Display for ChangedElsewhere {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "Record was changed elsewhere - the change wasn't saved.")
+	}
 }
+impl std::error::Error for ChangedElsewhere {}
+// How many times make_change runs a change before giving up on getting it saved.
+const CHANGE_ATTEMPTS: usize = 3;
 
 pub fn get_local_storage() -> Result<web_sys::Storage, anyhow::Error> {
 	let window = web_sys::window().context("No Window Object.")?;
 	window.local_storage().map_err(|_| anyhow!("Error retreiving local storage."))?.context("Tried to get local storage but got None.")
 }
-impl<T: Serialize + DeserializeOwned> Persist<T> {
-	fn save(&self) -> Result<(), anyhow::Error> {
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LocalStorage;
+impl Storage for LocalStorage {
+	fn get(&self, key: &str) -> Result<Option<String>, anyhow::Error> {
 		let lc = get_local_storage()?;
-		let serialized = bincode::serialize(&self.value).context("Serialization Failed.")?;
-		let encoded = base64::encode(serialized);
-		lc.set_item(&self.key, &encoded).map_err(|_| anyhow!("Failed to set the value back to local storage"))
-	}
-	pub fn new(key: &str, create: impl FnOnce() -> T) -> Result<Self, anyhow::Error> {
-		Self::new_no_create(key)?.map(|peer| Ok(peer)).unwrap_or_else(|| {
-			let value = create();
-			let peer = Self {
-				key: key.into(),
-				value
-			};
-			peer.save()?; // Save the peer after creation.
-			Ok(peer)
-		})
+		lc.get_item(key).map_err(|_| anyhow!("Error getting the item by key."))
+	}
+	fn set(&self, key: &str, value: &str) -> Result<(), anyhow::Error> {
+		let lc = get_local_storage()?;
+		lc.set_item(key, value).map_err(|_| anyhow!("Failed to set the value back to local storage"))
 	}
-	pub fn new_no_create(key: &str) -> Result<Option<Self>, anyhow::Error> {
+	fn remove(&self, key: &str) -> Result<(), anyhow::Error> {
 		let lc = get_local_storage()?;
-		if let Some(str) = lc.get_item(key).map_err(|_| anyhow!("Error getting the item by key."))? {
-			let buff = base64::decode(str).context("Base64 decoding failed.")?;
-			let value = bincode::deserialize(&buff)?;
-			Ok(Some(Self {
-				key: key.into(),
-				value
-			}))
+		lc.remove_item(key).map_err(|_| anyhow!("Failed to remove local storage entry."))
+	}
+	fn keys(&self) -> Result<Vec<String>, anyhow::Error> {
+		let lc = get_local_storage()?;
+		let length = lc.length().map_err(|_| anyhow!("Failed to get the number of stored items."))?;
+		(0..length).map(|i| {
+			lc.key(i).map_err(|_| anyhow!("Failed to get a key."))?.context("Key index out of range.")
+		}).collect()
+	}
+}
+
+// For tests, and anywhere state shouldn't outlive the page.  Clones share the same entries.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage(Rc<RefCell<BTreeMap<String, String>>>);
+impl Storage for MemoryStorage {
+	fn get(&self, key: &str) -> Result<Option<String>, anyhow::Error> {
+		Ok(self.0.borrow().get(key).cloned())
+	}
+	fn set(&self, key: &str, value: &str) -> Result<(), anyhow::Error> {
+		self.0.borrow_mut().insert(key.into(), value.into());
+		Ok(())
+	}
+	fn remove(&self, key: &str) -> Result<(), anyhow::Error> {
+		self.0.borrow_mut().remove(key);
+		Ok(())
+	}
+	fn keys(&self) -> Result<Vec<String>, anyhow::Error> {
+		Ok(self.0.borrow().keys().cloned().collect())
+	}
+}
+
+// One file per key in a directory, for native tests.  Anything in a key other than [A-Za-z0-9._-] is %-escaped in the file name.
+#[cfg(all(test, not(target_arch = "wasm32")))]
+#[derive(Debug, Clone)]
+pub struct FileStorage {
+	dir: std::path::PathBuf
+}
+#[cfg(all(test, not(target_arch = "wasm32")))]
+impl FileStorage {
+	pub fn new(dir: impl Into<std::path::PathBuf>) -> Result<Self, anyhow::Error> {
+		let dir = dir.into();
+		std::fs::create_dir_all(&dir).context("Failed to create the storage directory.")?;
+		Ok(Self { dir })
+	}
+	fn file_name(key: &str) -> String {
+		key.bytes().map(|b| match b {
+			b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'.' | b'_' | b'-' => (b as char).to_string(),
+			_ => format!("%{:02X}", b)
+		}).collect()
+	}
+	fn key(file_name: &str) -> Option<String> {
+		let mut bytes = Vec::new();
+		let mut rest = file_name.as_bytes();
+		while let Some((first, tail)) = rest.split_first() {
+			if *first == b'%' {
+				let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+				bytes.push(u8::from_str_radix(hex, 16).ok()?);
+				rest = &tail[2..];
+			} else {
+				bytes.push(*first);
+				rest = tail;
+			}
+		}
+		String::from_utf8(bytes).ok()
+	}
+	fn path(&self, key: &str) -> std::path::PathBuf {
+		// A leading '.' would make "." and ".." possible.
+		match key.strip_prefix('.') {
+			Some(rest) => self.dir.join(format!("%2E{}", Self::file_name(rest))),
+			None => self.dir.join(Self::file_name(key))
+		}
+	}
+}
+#[cfg(all(test, not(target_arch = "wasm32")))]
+impl Storage for FileStorage {
+	fn get(&self, key: &str) -> Result<Option<String>, anyhow::Error> {
+		match std::fs::read_to_string(self.path(key)) {
+			Ok(value) => Ok(Some(value)),
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+			Err(e) => Err(e).context("Failed to read a stored entry.")
+		}
+	}
+	fn set(&self, key: &str, value: &str) -> Result<(), anyhow::Error> {
+		// Write then rename so that a crash never leaves half a value behind.
+		let path = self.path(key);
+		let mut temp = path.clone().into_os_string();
+		temp.push(".tmp%");
+		std::fs::write(&temp, value).context("Failed to write a stored entry.")?;
+		std::fs::rename(&temp, &path).context("Failed to write a stored entry.")
+	}
+	fn remove(&self, key: &str) -> Result<(), anyhow::Error> {
+		match std::fs::remove_file(self.path(key)) {
+			Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e).context("Failed to remove a stored entry."),
+			_ => Ok(())
+		}
+	}
+	fn keys(&self) -> Result<Vec<String>, anyhow::Error> {
+		let mut keys = Vec::new();
+		for entry in std::fs::read_dir(&self.dir).context("Failed to list the storage directory.")? {
+			let name = entry.context("Failed to list the storage directory.")?.file_name();
+			// Skip anything that we didn't write, like a leftover temporary file.
+			if let Some(key) = name.to_str().filter(|name| !name.ends_with("tmp%")).and_then(Self::key) {
+				keys.push(key);
+			}
+		}
+		keys.sort();
+		Ok(keys)
+	}
+}
+
+// Whichever storage the app picked at runtime: localStorage on a page, IndexedDB when the service worker needs to see the same records.
+#[derive(Debug, Clone)]
+pub enum Backend {
+	Local(LocalStorage),
+	IndexedDb(IndexedDb),
+	Memory(MemoryStorage)
+}
+impl Default for Backend {
+	fn default() -> Self {
+		Backend::Local(LocalStorage)
+	}
+}
+impl Backend {
+	fn storage(&self) -> &dyn Storage {
+		match self {
+			Backend::Local(storage) => storage,
+			Backend::IndexedDb(storage) => storage,
+			Backend::Memory(storage) => storage
+		}
+	}
+	// Wait until our latest write to key is durable, failing if it couldn't be saved.  Only IndexedDB commits in the background, so the others are done straight away.
+	pub async fn saved(&self, key: &str) -> Result<(), anyhow::Error> {
+		match self {
+			Backend::IndexedDb(storage) => storage.saved(key).await,
+			Backend::Local(_) | Backend::Memory(_) => Ok(())
+		}
+	}
+	// Call handler with the key of each entry that another context changes, until the Watch is dropped.  Memory storage isn't shared, so it never calls it.
+	pub fn watch(&self, handler: js_sys::Function) -> Result<Watch, anyhow::Error> {
+		match self {
+			Backend::Local(_) => {
+				let window = web_sys::window().context("No Window Object.")?;
+				// localStorage only fires storage events in the other tabs, never the one that made the change.
+				let listener = Closure::wrap(Box::new(move |event: web_sys::StorageEvent| {
+					let key = event.key().map(JsValue::from).unwrap_or(JsValue::NULL);
+					let _ = handler.call1(&JsValue::NULL, &key);
+				}) as Box<dyn FnMut(web_sys::StorageEvent)>);
+				window.add_event_listener_with_callback("storage", listener.as_ref().unchecked_ref())
+					.map_err(|_| anyhow!("Failed to listen for storage events."))?;
+				Ok(Watch(Watching::Local(window, listener)))
+			},
+			Backend::IndexedDb(storage) => {
+				storage.set_change_handler(Some(handler));
+				Ok(Watch(Watching::IndexedDb(storage.clone())))
+			},
+			Backend::Memory(_) => Ok(Watch(Watching::Nothing))
+		}
+	}
+}
+#[derive(Debug)]
+pub struct Watch(Watching);
+#[derive(Debug)]
+enum Watching {
+	Local(web_sys::Window, Closure<dyn FnMut(web_sys::StorageEvent)>),
+	IndexedDb(IndexedDb),
+	Nothing
+}
+impl Drop for Watch {
+	fn drop(&mut self) {
+		match &self.0 {
+			Watching::Local(window, listener) => {
+				let _ = window.remove_event_listener_with_callback("storage", listener.as_ref().unchecked_ref());
+			},
+			Watching::IndexedDb(storage) => storage.set_change_handler(None),
+			Watching::Nothing => {}
+		}
+	}
+}
+impl Storage for Backend {
+	fn get(&self, key: &str) -> Result<Option<String>, anyhow::Error> {
+		self.storage().get(key)
+	}
+	fn set(&self, key: &str, value: &str) -> Result<(), anyhow::Error> {
+		self.storage().set(key, value)
+	}
+	fn remove(&self, key: &str) -> Result<(), anyhow::Error> {
+		self.storage().remove(key)
+	}
+	fn keys(&self) -> Result<Vec<String>, anyhow::Error> {
+		self.storage().keys()
+	}
+	fn replace(&self, key: &str, expected: Option<&str>, value: &str) -> Result<(), anyhow::Error> {
+		self.storage().replace(key, expected, value)
+	}
+}
+
+// Reads a record in one layout and writes it out in the next.  It must fail if the bytes aren't in the layout that it expects.
+pub type Migration = fn(&[u8]) -> Result<Vec<u8>, anyhow::Error>;
+// A stored record whose layout can change.  Records are tagged with the version they were written at, and older ones are passed through the migrations when they're loaded.
+pub trait Versioned: Serialize + DeserializeOwned {
+	// MIGRATIONS[n] upgrades version n to version n + 1, so the current version is MIGRATIONS.len().  Only ever append to this.
+	const MIGRATIONS: &'static [Migration];
+}
+// Like bincode::deserialize, but leftover bytes are an error so that a record can't be mistaken for an older, shorter layout.
+pub fn read_layout<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, anyhow::Error> {
+	bincode::DefaultOptions::new()
+		.with_fixint_encoding()
+		.reject_trailing_bytes()
+		.deserialize(bytes)
+		.context("Record didn't match the expected layout.")
+}
+fn upgrade<T: Versioned>(version: usize, bytes: &[u8]) -> Result<T, anyhow::Error> {
+	let mut bytes = Cow::Borrowed(bytes);
+	for migration in &T::MIGRATIONS[version..] {
+		bytes = Cow::Owned(migration(&bytes)?);
+	}
+	read_layout(&bytes)
+}
+
+// Stored as "v<version>.<revision>:<base64 bincode>", where the revision counts the saves made through Persist (it's left off, meaning 0, outside of storage).  ':' isn't in the base64 alphabet, so untagged records from before versioning can be told apart.
+pub fn encode<T: Versioned>(value: &T) -> Result<String, anyhow::Error> {
+	let serialized = bincode::serialize(value).context("Serialization Failed.")?;
+	Ok(format!("v{}:{}", T::MIGRATIONS.len(), base64::encode(serialized)))
+}
+pub fn encode_at<T: Versioned>(value: &T, revision: u64) -> Result<String, anyhow::Error> {
+	let serialized = bincode::serialize(value).context("Serialization Failed.")?;
+	Ok(format!("v{}.{}:{}", T::MIGRATIONS.len(), revision, base64::encode(serialized)))
+}
+// The version, revision and body of a tagged record.
+fn parse_tag(encoded: &str) -> Option<(usize, u64, &str)> {
+	let mut parts = encoded.strip_prefix('v')?.splitn(2, ':');
+	let (tag, body) = (parts.next()?, parts.next()?);
+	let mut tag = tag.splitn(2, '.');
+	let version = tag.next()?.parse().ok()?;
+	let revision = match tag.next() {
+		Some(revision) => revision.parse().ok()?,
+		None => 0
+	};
+	Some((version, revision, body))
+}
+pub fn revision(encoded: &str) -> u64 {
+	parse_tag(encoded).map(|(_, revision, _)| revision).unwrap_or(0)
+}
+pub fn decode<T: Versioned>(encoded: &str) -> Result<T, anyhow::Error> {
+	decode_versioned(encoded).map(|(value, _)| value)
+}
+// Also returns whether the record was in an older layout and should be written back.
+fn decode_versioned<T: Versioned>(encoded: &str) -> Result<(T, bool), anyhow::Error> {
+	let current = T::MIGRATIONS.len();
+	if let Some((version, _, rest)) = parse_tag(encoded) {
+		if version > current {
+			return Err(anyhow!("Record was written by a newer version (v{}, we only understand up to v{}).", version, current));
+		}
+		let buff = base64::decode(rest).context("Base64 decoding failed.")?;
+		return Ok((upgrade(version, &buff)?, version != current));
+	}
+	// Untagged, so it's from before versioning and could be in any layout.  Newer layouts only ever grew, so the newest one that fits exactly is the right one.
+	let buff = base64::decode(encoded).context("Base64 decoding failed.")?;
+	for version in (0..=current).rev() {
+		if let Ok(value) = upgrade(version, &buff) {
+			return Ok((value, true));
+		}
+	}
+	Err(anyhow!("Record isn't in any known layout."))
+}
+
+// Other tabs (and the service worker) can change the same records.  A Persist remembers the record as it last read or wrote it, so it can tell when what it holds is out of date: it catches up before each change, and only saves over the record it last saw (see Storage::replace).  Every save bumps the record's revision.
+#[derive(Debug)]
+pub struct Persist<T, S: Storage = Backend> {
+	key: String,
+	value: T,
+	storage: S,
+	// What value came from, or empty if it hasn't been saved.
+	stored: Stored
+}
+// Holds secrets for some records, so only the revision is shown.
+#[derive(Default)]
+struct Stored(String);
+impl std::fmt::Debug for Stored {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "Stored(revision {})", revision(&self.0))
+	}
+}
+impl<T: Versioned, S: Storage> Persist<T, S> {
+	// Save an encoded value, provided the record is still what we last saw.
+	fn save(&mut self, encoded: String) -> Result<(), anyhow::Error> {
+		let expected = Some(self.stored.0.as_str()).filter(|stored| !stored.is_empty());
+		self.storage.replace(&self.key, expected, &encoded)?;
+		self.stored = Stored(encoded);
+		Ok(())
+	}
+	pub fn open(storage: S, key: &str, create: impl FnOnce() -> T) -> Result<Self, anyhow::Error> {
+		if let Some(str) = storage.get(key)? {
+			return Self::load(storage, key, &str);
+		}
+		let mut peer = Self {
+			key: key.into(),
+			value: create(),
+			storage,
+			stored: Stored::default()
+		};
+		peer.save(encode_at(&peer.value, 0)?)?; // Save the peer after creation.
+		Ok(peer)
+	}
+	pub fn open_existing(storage: S, key: &str) -> Result<Option<Self>, anyhow::Error> {
+		if let Some(str) = storage.get(key)? {
+			Ok(Some(Self::load(storage, key, &str)?))
 		} else {
 			Ok(None)
 		}
 	}
-	pub fn make_change<R>(&mut self, func: impl FnOnce(&mut T) -> R) -> Result<R, anyhow::Error> {
-		let result = func(&mut self.value);
-		self.save()?;
-		Ok(result)
+	fn load(storage: S, key: &str, encoded: &str) -> Result<Self, anyhow::Error> {
+		let (value, upgraded) = decode_versioned(encoded)?;
+		let mut persist = Self {
+			key: key.into(),
+			value,
+			storage,
+			stored: Stored(encoded.into())
+		};
+		// Write the upgrade back so that the migrations only run once.
+		if upgraded {
+			persist.save(encode_at(&persist.value, revision(encoded))?)?;
+		}
+		Ok(persist)
 	}
-	#[allow(dead_code)]
-	pub fn delete(self) -> Result<(), anyhow::Error>{
-		let lc = get_local_storage()?;
-		lc.remove_item(&self.key).map_err(|_| anyhow!("Failed to remove local storage entry."))
+	pub fn storage(&self) -> &S {
+		&self.storage
+	}
+	// Catch up with changes saved elsewhere.  Returns whether there were any.
+	pub fn refresh(&mut self) -> Result<bool, anyhow::Error> {
+		let encoded = self.storage.get(&self.key)?.context("Record was deleted elsewhere.")?;
+		if encoded == self.stored.0 {
+			return Ok(false);
+		}
+		self.value = decode(&encoded)?;
+		self.stored = Stored(encoded);
+		Ok(true)
+	}
+	// Anything else holding the record (e.g. another tab) finds it gone the next time it refreshes.
+	pub fn delete(&self) -> Result<(), anyhow::Error>{
+		self.storage.remove(&self.key)
+	}
+}
+impl<T: Versioned + Clone, S: Storage> Persist<T, S> {
+	// The change is made on top of the latest saved value, and only takes effect if it's saved.  If another context saves first, func is run again on top of what they saved, up to CHANGE_ATTEMPTS times, so it shouldn't do anything besides change the value.  If saving fails, the value is left as it was.  On IndexedDB the save is only committed later: wait on saved before treating the change as kept (if the commit fails, the next refresh goes back to what was saved).
+	pub fn make_change<R>(&mut self, mut func: impl FnMut(&mut T) -> R) -> Result<R, anyhow::Error> {
+		let mut attempts = 1;
+		loop {
+			let mut batch = self.batch()?;
+			let result = func(&mut batch);
+			match batch.commit() {
+				Ok(()) => return Ok(result),
+				Err(e) if e.is::<ChangedElsewhere>() && attempts < CHANGE_ATTEMPTS => attempts += 1,
+				Err(e) => return Err(e)
+			}
+		}
+	}
+	// Stage any number of changes to be saved with a single write.
+	pub fn batch(&mut self) -> Result<Batch<'_, T, S>, anyhow::Error> {
+		self.refresh()?;
+		Ok(Batch {
+			staged: self.value.clone(),
+			persist: self
+		})
 	}
 }
-impl<T> AsRef<T> for Persist<T> {
+
+// Changes staged on a copy of a Persist's value.  Nothing is saved or visible through the Persist until commit succeeds, and dropping a batch discards it.
+pub struct Batch<'a, T: Versioned + Clone, S: Storage> {
+	persist: &'a mut Persist<T, S>,
+	staged: T
+}
+impl<'a, T: Versioned + Clone, S: Storage> Batch<'a, T, S> {
+	// Fails with ChangedElsewhere, without saving, if the record was changed elsewhere since the batch started.  The Persist is brought up to date so that the change can be made again.
+	pub fn commit(self) -> Result<(), anyhow::Error> {
+		let Batch { persist, staged } = self;
+		if persist.refresh()? {
+			return Err(ChangedElsewhere.into());
+		}
+		persist.save(encode_at(&staged, revision(&persist.stored.0) + 1)?)?;
+		persist.value = staged;
+		Ok(())
+	}
+}
+impl<'a, T: Versioned + Clone, S: Storage> Deref for Batch<'a, T, S> {
+	type Target = T;
+	fn deref(&self) -> &Self::Target {
+		&self.staged
+	}
+}
+impl<'a, T: Versioned + Clone, S: Storage> DerefMut for Batch<'a, T, S> {
+	fn deref_mut(&mut self) -> &mut Self::Target {
+		&mut self.staged
+	}
+}
+impl<T> Persist<T> {
+	// Resolves once our latest save of the record is durable, and fails if it couldn't be saved (see Backend::saved).
+	pub fn saved(&self) -> impl std::future::Future<Output = Result<(), anyhow::Error>> {
+		let (storage, key) = (self.storage.clone(), self.key.clone());
+		async move { storage.saved(&key).await }
+	}
+	// A value that isn't backed by storage.
+	#[cfg(test)]
+	pub fn detached(key: &str, value: T) -> Self {
+		Self {
+			key: key.into(),
+			value,
+			storage: Backend::default(),
+			stored: Stored::default()
+		}
+	}
+}
+impl<T, S: Storage> AsRef<T> for Persist<T, S> {
 	fn as_ref(&self) -> &T {
 		&self.value
 	}
 }
-impl<T> Deref for Persist<T> {
+impl<T, S: Storage> Deref for Persist<T, S> {
 	type Target = T;
 	fn deref(&self) -> &Self::Target {
 		&self.value
 	}
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde::Deserialize;
+
+	#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+	struct Record {
+		name: String,
+		count: u32
+	}
+	impl Versioned for Record {
+		const MIGRATIONS: &'static [Migration] = &[
+			// 0 -> 1: Records used to be just a name.
+			|bytes| {
+				let name: String = read_layout(bytes)?;
+				Ok(bincode::serialize(&(name, 0u32))?)
+			}
+		];
+	}
+
+	fn exercise(storage: impl Storage + Clone) {
+		let mut record = Persist::open(storage.clone(), "peer.abc", || Record { name: String::from("first"), count: 0 }).unwrap();
+		record.make_change(|record| record.count += 1).unwrap();
+		assert!(Persist::<Record, _>::open_existing(storage.clone(), "peer.missing").unwrap().is_none());
+
+		// A second handle sees what the first saved, and the create callback isn't used.
+		let reopened = Persist::open(storage.clone(), "peer.abc", || Record { name: String::from("second"), count: 0 }).unwrap();
+		assert_eq!(*reopened, Record { name: String::from("first"), count: 1 });
+		assert_eq!(storage.keys().unwrap(), vec![String::from("peer.abc")]);
+
+		reopened.delete().unwrap();
+		assert!(storage.keys().unwrap().is_empty());
+		assert!(storage.get("peer.abc").unwrap().is_none());
+	}
+
+	// Counts writes, and fails them on request.
+	#[derive(Clone, Default)]
+	struct Flaky {
+		inner: MemoryStorage,
+		failing: Rc<std::cell::Cell<bool>>,
+		writes: Rc<std::cell::Cell<u32>>
+	}
+	impl Storage for Flaky {
+		fn get(&self, key: &str) -> Result<Option<String>, anyhow::Error> {
+			self.inner.get(key)
+		}
+		fn set(&self, key: &str, value: &str) -> Result<(), anyhow::Error> {
+			if self.failing.get() {
+				return Err(anyhow!("QuotaExceededError"));
+			}
+			self.writes.set(self.writes.get() + 1);
+			self.inner.set(key, value)
+		}
+		fn remove(&self, key: &str) -> Result<(), anyhow::Error> {
+			self.inner.remove(key)
+		}
+		fn keys(&self) -> Result<Vec<String>, anyhow::Error> {
+			self.inner.keys()
+		}
+	}
+
+	#[test]
+	fn failed_saves_roll_back() {
+		let storage = Flaky::default();
+		let mut record = Persist::open(storage.clone(), "peer.abc", || Record { name: String::from("first"), count: 0 }).unwrap();
+		storage.failing.set(true);
+		assert!(record.make_change(|record| record.count += 1).is_err());
+		assert_eq!(record.count, 0);
+		let mut batch = record.batch().unwrap();
+		batch.count = 5;
+		assert!(batch.commit().is_err());
+		assert_eq!(record.count, 0);
+
+		storage.failing.set(false);
+		assert_eq!(Persist::<Record, _>::open_existing(storage.clone(), "peer.abc").unwrap().unwrap().count, 0);
+	}
+	#[test]
+	fn batches() {
+		let storage = Flaky::default();
+		let mut record = Persist::open(storage.clone(), "peer.abc", || Record { name: String::from("first"), count: 0 }).unwrap();
+		let before = storage.writes.get();
+		let mut batch = record.batch().unwrap();
+		batch.count += 1;
+		batch.name.push_str(" and second");
+		batch.count += 1;
+		// Nothing shows until it's committed.
+		drop(batch);
+		assert_eq!(record.count, 0);
+
+		let mut batch = record.batch().unwrap();
+		batch.count += 2;
+		batch.name.push_str(" and second");
+		batch.commit().unwrap();
+		assert_eq!(*record, Record { name: String::from("first and second"), count: 2 });
+		assert_eq!(storage.writes.get(), before + 1);
+	}
+	#[test]
+	fn changes_from_elsewhere() {
+		let storage = MemoryStorage::default();
+		let mut first = Persist::open(storage.clone(), "peer.abc", || Record { name: String::from("first"), count: 0 }).unwrap();
+		let mut second = Persist::<Record, _>::open_existing(storage.clone(), "peer.abc").unwrap().unwrap();
+
+		// Each change is made on top of the other's.
+		first.make_change(|record| record.count += 1).unwrap();
+		second.make_change(|record| record.name.push_str(" and second")).unwrap();
+		first.make_change(|record| record.count += 1).unwrap();
+		assert_eq!(*first, Record { name: String::from("first and second"), count: 2 });
+		assert!(second.refresh().unwrap());
+		assert_eq!(*second, *first);
+		assert!(!second.refresh().unwrap());
+
+		// A write that lands while a batch is open isn't overwritten.
+		let mut batch = first.batch().unwrap();
+		batch.count = 10;
+		second.make_change(|record| record.count = 20).unwrap();
+		assert!(batch.commit().is_err());
+		assert_eq!(first.count, 20);
+		assert_eq!(decode::<Record>(&storage.get("peer.abc").unwrap().unwrap()).unwrap().count, 20);
+		assert_eq!(revision(&storage.get("peer.abc").unwrap().unwrap()), 4);
+
+		// A write that lands while a change is being made is kept, and the change is made again on top of it.
+		let mut runs = 0;
+		first.make_change(|record| {
+			runs += 1;
+			if runs == 1 {
+				second.make_change(|record| record.name = String::from("second")).unwrap();
+			}
+			record.count += 1;
+		}).unwrap();
+		assert_eq!(runs, 2);
+		assert_eq!(*first, Record { name: String::from("second"), count: 21 });
+		// But only so many times.
+		let mut runs = 0;
+		assert!(first.make_change(|record| {
+			runs += 1;
+			second.make_change(|record| record.count += 1).unwrap();
+			record.count = 0;
+		}).is_err());
+		assert_eq!(runs, CHANGE_ATTEMPTS);
+		assert_eq!(decode::<Record>(&storage.get("peer.abc").unwrap().unwrap()).unwrap().count, 21 + CHANGE_ATTEMPTS as u32);
+	}
+	// Reads come from a copy that's only caught up on request, like IndexedDb's, but replace checks what's really stored.
+	#[derive(Clone, Default)]
+	struct Cached {
+		stored: MemoryStorage,
+		copy: MemoryStorage
+	}
+	impl Storage for Cached {
+		fn get(&self, key: &str) -> Result<Option<String>, anyhow::Error> {
+			self.copy.get(key)
+		}
+		fn set(&self, key: &str, value: &str) -> Result<(), anyhow::Error> {
+			self.stored.set(key, value)?;
+			self.copy.set(key, value)
+		}
+		fn remove(&self, key: &str) -> Result<(), anyhow::Error> {
+			self.stored.remove(key)?;
+			self.copy.remove(key)
+		}
+		fn keys(&self) -> Result<Vec<String>, anyhow::Error> {
+			self.copy.keys()
+		}
+		fn replace(&self, key: &str, expected: Option<&str>, value: &str) -> Result<(), anyhow::Error> {
+			self.stored.replace(key, expected, value)?;
+			self.copy.set(key, value)
+		}
+	}
+	#[test]
+	fn changes_caught_when_saving() {
+		let stored = MemoryStorage::default();
+		let first = Cached { stored: stored.clone(), copy: MemoryStorage::default() };
+		let second = Cached { stored: stored.clone(), copy: MemoryStorage::default() };
+		let mut record = Persist::open(first, "peer.abc", || Record { name: String::from("first"), count: 0 }).unwrap();
+		second.copy.set("peer.abc", &stored.get("peer.abc").unwrap().unwrap()).unwrap();
+		let mut other = Persist::<Record, _>::open_existing(second.clone(), "peer.abc").unwrap().unwrap();
+
+		// The second copy hasn't heard about this change, but still can't save over it.
+		record.make_change(|record| record.count = 1).unwrap();
+		assert!(other.make_change(|record| record.count = 2).is_err());
+		assert_eq!(decode::<Record>(&stored.get("peer.abc").unwrap().unwrap()).unwrap().count, 1);
+
+		// Once it's caught up, the change goes on top.
+		second.copy.set("peer.abc", &stored.get("peer.abc").unwrap().unwrap()).unwrap();
+		other.make_change(|record| record.count += 1).unwrap();
+		assert_eq!(decode::<Record>(&stored.get("peer.abc").unwrap().unwrap()).unwrap().count, 2);
+	}
+	#[test]
+	fn memory_storage() {
+		exercise(MemoryStorage::default());
+	}
+	#[test]
+	fn file_storage() {
+		let dir = std::env::temp_dir().join(format!("persist-test-{}", rand::random::<u64>()));
+		exercise(FileStorage::new(&dir).unwrap());
+
+		let storage = FileStorage::new(&dir).unwrap();
+		for key in &["self_peer", "peer.a-b_c", "odd/key%..", ".."] {
+			storage.set(key, "value").unwrap();
+			assert_eq!(storage.get(key).unwrap().as_deref(), Some("value"));
+		}
+		let mut keys = vec!["self_peer", "peer.a-b_c", "odd/key%..", ".."];
+		keys.sort();
+		assert_eq!(storage.keys().unwrap(), keys);
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+	#[test]
+	fn existing_values_load() {
+		// Written the way values have always been written to localStorage.
+		let storage = MemoryStorage::default();
+		storage.set("self_peer", &base64::encode(bincode::serialize(&(String::from("stored"), 7u32)).unwrap())).unwrap();
+		let record = Persist::<Record, _>::open_existing(storage.clone(), "self_peer").unwrap().unwrap();
+		assert_eq!(*record, Record { name: String::from("stored"), count: 7 });
+		// And it's tagged with its version from then on.
+		assert!(storage.get("self_peer").unwrap().unwrap().starts_with("v1.0:"));
+	}
+	#[test]
+	fn migrations() {
+		let storage = MemoryStorage::default();
+		let old = base64::encode(bincode::serialize("old").unwrap());
+		let upgraded = Record { name: String::from("old"), count: 0 };
+		// Untagged, and tagged with the old version:
+		storage.set("peer.a", &old).unwrap();
+		storage.set("peer.b", &format!("v0:{}", old)).unwrap();
+		for key in &["peer.a", "peer.b"] {
+			assert_eq!(*Persist::<Record, _>::open_existing(storage.clone(), key).unwrap().unwrap(), upgraded);
+			assert_eq!(decode_versioned::<Record>(&storage.get(key).unwrap().unwrap()).unwrap(), (upgraded.clone(), false));
+		}
+
+		// Records from the future, or in no layout we know, are left alone.
+		storage.set("peer.c", &format!("v2:{}", old)).unwrap();
+		storage.set("peer.d", &base64::encode([1, 2, 3])).unwrap();
+		for key in &["peer.c", "peer.d"] {
+			let before = storage.get(key).unwrap();
+			assert!(Persist::<Record, _>::open_existing(storage.clone(), key).is_err());
+			assert_eq!(storage.get(key).unwrap(), before);
+		}
+	}
+}
@@ -2,14 +2,26 @@ use rand::{
 	SeedableRng,
 	rngs::StdRng
 };
-use anyhow::{ Context, anyhow };
+use anyhow::anyhow;
 
+// Pages and the service worker both have crypto on their global object, but only pages have a window.
+#[cfg(target_arch = "wasm32")]
 fn fill_slice_with_random(dest: &mut [u8]) -> Result<(), anyhow::Error> {
-	let window = web_sys::window().context("No Window")?;
-	let crypto = window.crypto().map_err(|_| anyhow!("Failed to get crypto off of window."))?;
+	use anyhow::Context;
+	use wasm_bindgen::{JsCast, JsValue};
+	let crypto = js_sys::Reflect::get(&js_sys::global(), &JsValue::from("crypto"))
+		.ok()
+		.and_then(|crypto| crypto.dyn_into::<web_sys::Crypto>().ok())
+		.context("crypto isn't available here.")?;
 	crypto.get_random_values_with_u8_array(dest).map_err(|_| anyhow!("Failed to get random bytes."))?;
 	Ok(())
 }
+// Native builds (tests and the push service stand-in) don't have crypto.getRandomValues.
+#[cfg(not(target_arch = "wasm32"))]
+fn fill_slice_with_random(dest: &mut [u8]) -> Result<(), anyhow::Error> {
+	use rand::RngCore;
+	rand::rngs::OsRng.try_fill_bytes(dest).map_err(|_| anyhow!("Failed to get random bytes."))
+}
 
 fn get_crypto_seed() -> Result<[u8; 32], anyhow::Error> {
 	let mut seed = [0; 32];
@@ -23,12 +35,13 @@ pub fn get_salt() -> Result<[u8; 16], anyhow::Error> {
 	Ok(salt)
 }
 
-pub fn get_rng() -> StdRng {
-	let seed = get_crypto_seed().unwrap_or_else(|e| {
-		eprintln!("Using default seed for random number generator because something went wrong with window.crypto.getRandomValues! {}", e);
-
-		[42, 165, 163, 27, 236, 71, 63, 27, 75, 254, 24, 180, 80, 8, 105, 120, 14, 55, 14, 236, 68, 38, 100, 45, 194, 36, 110, 30, 128, 83, 190, 19]
-	});
-	StdRng::from_seed(seed)
+pub fn get_nonce() -> Result<[u8; 12], anyhow::Error> {
+	let mut nonce = [0; 12];
+	fill_slice_with_random(&mut nonce)?;
+	Ok(nonce)
 }
 
+// Keys are made with this, so there's no falling back to anything predictable when crypto isn't available.
+pub fn get_rng() -> Result<StdRng, anyhow::Error> {
+	Ok(StdRng::from_seed(get_crypto_seed()?))
+}
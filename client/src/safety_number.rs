@@ -0,0 +1,100 @@
+use sha2::{Digest, Sha256, Sha512};
+
+use super::crypto;
+
+// Both sides must compute the same thing, so the keys are always hashed in sorted order.
+fn sorted_keys(a: &crypto::PublicKey, b: &crypto::PublicKey) -> Vec<u8> {
+	let a = a.compress();
+	let b = b.compress();
+	let (first, second) = if a.as_bytes() <= b.as_bytes() { (a, b) } else { (b, a) };
+	let mut keys = Vec::new();
+	keys.extend_from_slice(first.as_bytes());
+	keys.extend_from_slice(second.as_bytes());
+	keys
+}
+
+const ITERATIONS: usize = 1024;
+
+// 12 groups of 5 digits, meant to be read aloud or compared side by side.
+pub fn digits(a: &crypto::PublicKey, b: &crypto::PublicKey) -> String {
+	let keys = sorted_keys(a, b);
+	let mut hash = Sha512::new().chain("web3.0-test safety number".as_bytes()).chain([0]).chain(&keys).finalize();
+	for _ in 0..ITERATIONS {
+		hash = Sha512::new().chain(hash).chain(&keys).finalize();
+	}
+	hash.chunks(5).take(12).map(|chunk| {
+		let value = chunk.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64);
+		format!("{:05}", value % 100000)
+	}).collect::<Vec<_>>().join(" ")
+}
+
+// Emoji (and the name for each one) from the list used by Matrix's short authentication strings.
+const EMOJI: [(&str, &str); 64] = [
+	("🐶", "Dog"), ("🐱", "Cat"), ("🦁", "Lion"), ("🐎", "Horse"),
+	("🦄", "Unicorn"), ("🐷", "Pig"), ("🐘", "Elephant"), ("🐰", "Rabbit"),
+	("🐼", "Panda"), ("🐓", "Rooster"), ("🐧", "Penguin"), ("🐢", "Turtle"),
+	("🐟", "Fish"), ("🐙", "Octopus"), ("🦋", "Butterfly"), ("🌷", "Flower"),
+	("🌳", "Tree"), ("🌵", "Cactus"), ("🍄", "Mushroom"), ("🌏", "Globe"),
+	("🌙", "Moon"), ("☁️", "Cloud"), ("🔥", "Fire"), ("🍌", "Banana"),
+	("🍎", "Apple"), ("🍓", "Strawberry"), ("🌽", "Corn"), ("🍕", "Pizza"),
+	("🎂", "Cake"), ("❤️", "Heart"), ("😀", "Smiley"), ("🤖", "Robot"),
+	("🎩", "Hat"), ("👓", "Glasses"), ("🔧", "Spanner"), ("🎅", "Santa"),
+	("👍", "Thumbs Up"), ("☂️", "Umbrella"), ("⌛", "Hourglass"), ("⏰", "Clock"),
+	("🎁", "Gift"), ("💡", "Light Bulb"), ("📕", "Book"), ("✏️", "Pencil"),
+	("📎", "Paperclip"), ("✂️", "Scissors"), ("🔒", "Lock"), ("🔑", "Key"),
+	("🔨", "Hammer"), ("☎️", "Telephone"), ("🏁", "Flag"), ("🚂", "Train"),
+	("🚲", "Bicycle"), ("✈️", "Aeroplane"), ("🚀", "Rocket"), ("🏆", "Trophy"),
+	("⚽", "Ball"), ("🎸", "Guitar"), ("🎺", "Trumpet"), ("🔔", "Bell"),
+	("⚓", "Anchor"), ("🎧", "Headphones"), ("📁", "Folder"), ("📌", "Pin")
+];
+const EMOJI_COUNT: usize = 7;
+
+fn emoji_indices(a: &crypto::PublicKey, b: &crypto::PublicKey) -> Vec<usize> {
+	let hash = Sha256::new().chain("web3.0-test safety emoji".as_bytes()).chain([0]).chain(sorted_keys(a, b)).finalize();
+	// Take 6 bits at a time off the front of the hash.
+	let bits = hash.iter().take(6).fold(0u64, |acc, b| (acc << 8) | *b as u64);
+	(0..EMOJI_COUNT).map(|i| ((bits >> (42 - 6 * i)) & 0b111111) as usize).collect()
+}
+pub fn emoji(a: &crypto::PublicKey, b: &crypto::PublicKey) -> String {
+	emoji_indices(a, b).into_iter().map(|i| EMOJI[i].0).collect::<Vec<_>>().join(" ")
+}
+pub fn words(a: &crypto::PublicKey, b: &crypto::PublicKey) -> String {
+	emoji_indices(a, b).into_iter().map(|i| EMOJI[i].1).collect::<Vec<_>>().join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn symmetric() {
+		let a = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng())).public_key();
+		let b = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng())).public_key();
+		assert_eq!(digits(&a, &b), digits(&b, &a));
+		assert_eq!(emoji(&a, &b), emoji(&b, &a));
+		assert_eq!(words(&a, &b), words(&b, &a));
+	}
+	#[test]
+	fn digit_groups() {
+		let a = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng())).public_key();
+		let b = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng())).public_key();
+		let number = digits(&a, &b);
+		let groups = number.split(' ').collect::<Vec<_>>();
+		assert_eq!(groups.len(), 12);
+		assert!(groups.iter().all(|group| group.len() == 5 && group.chars().all(|c| c.is_ascii_digit())));
+	}
+	#[test]
+	fn different_peers() {
+		let a = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng())).public_key();
+		let b = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng())).public_key();
+		let c = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng())).public_key();
+		assert_ne!(digits(&a, &b), digits(&a, &c));
+	}
+	#[test]
+	fn emoji_count() {
+		let a = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng())).public_key();
+		let b = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng())).public_key();
+		assert_eq!(emoji(&a, &b).split(' ').count(), EMOJI_COUNT);
+		assert_eq!(words(&a, &b).split(", ").count(), EMOJI_COUNT);
+	}
+}
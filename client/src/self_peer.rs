@@ -1,58 +1,552 @@
 use wasm_bindgen::prelude::*;
-use url::Url;
-use base64;
-use p256::{
-	ecdsa::{
-		SigningKey,
-		signature::RandomizedSigner
-	}
-};
 use anyhow::{ Context, anyhow };
 use std::{
 	convert::TryFrom,
-	fmt::Debug
+	collections::HashMap,
+	fmt::Debug,
+	ops::Range
 };
 use serde::{ Serialize, Deserialize };
+use zeroize::{Zeroize, Zeroizing};
 
 use shared::*;
 
 use super::signaling::{SignalingFormat, SignalingMessage};
-use super::persist::Persist;
+use super::persist::{self, Persist, Backend, Storage, Versioned, Migration, read_layout};
+use super::idb::IndexedDb;
 use super::crypto;
 use super::rand::get_rng;
 use super::web_push;
-use super::peer::Peer;
+use super::jwt;
+use super::peer::{self, Peer};
+use super::passphrase;
+use super::succession::Succession;
+use super::device::DeviceCertificate;
+use super::backup::Backup;
+use super::archive;
+use super::maintenance;
+use super::safety_number;
+use super::authorizations::RUNNING_LOW;
+
+// How the identity's secret key is kept in storage.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+enum StoredKey {
+	Plain(crypto::SecretKey),
+	// The public key is kept in the clear so that we can still identify ourselves while locked.
+	Locked {
+		public_key: crypto::PublicKey,
+		sealed: passphrase::Sealed
+	}
+}
+impl StoredKey {
+	fn lock(secret_key: &crypto::SecretKey, passphrase: &str) -> Result<Self, anyhow::Error> {
+		let public_key = secret_key.public_key();
+		let mut bytes = Zeroizing::new([0; 32]);
+		let mut field_bytes = secret_key.to_bytes();
+		bytes.copy_from_slice(&field_bytes);
+		field_bytes.as_mut_slice().zeroize();
+		let sealed = passphrase::Sealed::seal(bytes.as_ref(), passphrase, public_key.as_bytes())?;
+		Ok(StoredKey::Locked { public_key, sealed })
+	}
+	fn public_key(&self) -> crypto::PublicKey {
+		match self {
+			StoredKey::Plain(secret_key) => p256::EncodedPoint::from_secret_key(secret_key, false).into(),
+			StoredKey::Locked { public_key, .. } => *public_key
+		}
+	}
+}
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SelfPeerData {
-	secret_key: crypto::SecretKey,
+	secret_key: StoredKey,
 	info: Option<web_push::PushInfo>,
-	subscriber: Option<String>
+	subscriber: Option<String>,
+	// Our most recent key rotation, kept so that it can be announced to peers that were offline.
+	succession: Option<Succession>,
+	// Present when this browser is a device of some other root identity.
+	certificate: Option<DeviceCertificate>,
+	// Applied to everything we push so that message sizes don't give away what kind of message it is.
+	padding: web_push::Padding,
+	// Used both for the authorizations we hand out and for checking peers' authorizations before pushing to them.
+	auth_lifetime: web_push::AuthLifetime,
+	// The expiration of the newest authorization we've issued to each peer, by peer id.
+	issued: HashMap<String, u32>
+}
+// Earlier layouts of SelfPeerData, as tuples of their fields (bincode lays both out the same way).
+type LayoutV0 = (crypto::SecretKey, Option<web_push::LegacyPushInfo>, Option<String>);
+type LayoutV1 = (StoredKey, Option<web_push::LegacyPushInfo>, Option<String>);
+type LayoutV2 = (LayoutV1, Option<Succession>);
+type LayoutV3 = (LayoutV2, Option<DeviceCertificate>);
+type LayoutV4 = (StoredKey, Option<web_push::PushInfo>, Option<String>, Option<Succession>, Option<DeviceCertificate>);
+type LayoutV5 = (LayoutV4, web_push::Padding);
+type LayoutV6 = (LayoutV5, web_push::AuthLifetime);
+impl Versioned for SelfPeerData {
+	const MIGRATIONS: &'static [Migration] = &[
+		// 0 -> 1: The secret key can be locked with a passphrase.
+		|bytes| {
+			let (secret_key, info, subscriber): LayoutV0 = read_layout(bytes)?;
+			Ok(bincode::serialize(&(StoredKey::Plain(secret_key), info, subscriber))?)
+		},
+		// 1 -> 2: Key rotation.
+		|bytes| Ok(bincode::serialize(&(read_layout::<LayoutV1>(bytes)?, None::<Succession>))?),
+		// 2 -> 3: Device certificates.
+		|bytes| Ok(bincode::serialize(&(read_layout::<LayoutV2>(bytes)?, None::<DeviceCertificate>))?),
+		// 3 -> 4: Push info records its content encoding.
+		|bytes| {
+			let (((secret_key, info, subscriber), succession), certificate): LayoutV3 = read_layout(bytes)?;
+			Ok(bincode::serialize(&(secret_key, info.map(web_push::PushInfo::from), subscriber, succession, certificate))?)
+		},
+		// 4 -> 5: Padding.
+		|bytes| Ok(bincode::serialize(&(read_layout::<LayoutV4>(bytes)?, web_push::Padding::default()))?),
+		// 5 -> 6: Authorization lifetimes.
+		|bytes| Ok(bincode::serialize(&(read_layout::<LayoutV5>(bytes)?, web_push::AuthLifetime::default()))?),
+		// 6 -> 7: Tracking the authorizations we've issued.
+		|bytes| Ok(bincode::serialize(&(read_layout::<LayoutV6>(bytes)?, HashMap::<String, u32>::new()))?)
+	];
 }
 #[wasm_bindgen]
+#[derive(Debug)]
 pub struct SelfPeer {
-	persist: Persist<SelfPeerData>
+	persist: Persist<SelfPeerData>,
+	// Only present while the identity is unlocked (always the case if there's no passphrase).
+	secret_key: Option<crypto::SecretKey>,
+	// Set by set_change_handler.
+	watch: Option<persist::Watch>
+}
+
+// Mint a push authorization that becomes usable `slot` slots from now (see AuthLifetime::expiration).
+fn create_auth(info: &web_push::PushInfo, secret_key: &crypto::SecretKey, lifetime: &web_push::AuthLifetime, slot: u32, now: u32, subscriber: Option<&str>) -> Result<web_push::AuthToken, anyhow::Error> {
+	let mut rng = get_rng()?;
+	let claims = jwt::Claims {
+		aud: jwt::audience(&info.endpoint)?,
+		exp: lifetime.expiration(now, slot)?,
+		sub: subscriber.unwrap_or("https://github.com/evan-brass/web3.0-test").into()
+	};
+	let signature = claims.sign(secret_key, &mut rng)?;
+
+	Ok(web_push::AuthToken {
+		expiration: claims.exp,
+		subscriber: claims.sub,
+		signature
+	})
+}
+
+// Once a peer has less than RUNNING_LOW left on the authorizations we've given it, it's topped back up to this.
+const REFRESH_TARGET: u32 = 48 * 60 * 60;
+// Which slots (see AuthLifetime::expiration) to mint for a peer whose newest authorization from us expires at `issued`, or None if it has enough.
+fn refresh_slots(lifetime: &web_push::AuthLifetime, issued: Option<u32>, now: u32) -> Result<Option<Range<u32>>, anyhow::Error> {
+	let remaining = issued.map(|expiration| lifetime.usable_until(expiration).saturating_sub(now)).unwrap_or(0);
+	if remaining >= RUNNING_LOW {
+		return Ok(None);
+	}
+	let first = lifetime.expiration(now, 0)?;
+	// Carry on from the peer's newest token so that the slots don't overlap.
+	let start = match issued {
+		Some(expiration) if expiration >= first => (expiration - first) / web_push::AUTH_SLOT + 1,
+		_ => 0
+	};
+	let mut end = start + 1;
+	while lifetime.usable_until(first + (end - 1) * web_push::AUTH_SLOT) < now + REFRESH_TARGET {
+		end += 1;
+	}
+	Ok(Some(start..end))
 }
 
 impl SelfPeer {
+	fn package(&self, message: &SignalingFormat) -> Result<String, anyhow::Error> {
+		// Devices attach their certificate to everything they send so that peers can attribute it to the root identity.
+		let mut buffer = if let Some(ref certificate) = self.persist.certificate {
+			Vec::try_from(&SignalingFormat::Certified(certificate.clone(), Box::new(message.clone())))?
+		} else {
+			Vec::try_from(message)?
+		};
+		let rec_sig = crypto::RecoverableSignature::try_sign_recoverable(self.secret_key()?, &buffer)?;
+		buffer.extend_from_slice(&rec_sig.to_bytes());
+		Ok(base64::encode_config(&buffer, base64::URL_SAFE_NO_PAD))
+	}
+	// Peers compare against the identity they know us by, which for a device is its root.
 	fn pk_magnitude(&self) -> p256::Scalar {
-		p256::Scalar::from_bytes_reduced(p256::EncodedPoint::from_secret_key(self.persist.secret_key.as_ref(), true).x())
+		p256::Scalar::from_bytes_reduced(self.identity_key().compress().x().unwrap())
+	}
+	fn identity_key(&self) -> crypto::PublicKey {
+		self.persist.certificate.as_ref()
+			.map(|certificate| certificate.root_key)
+			.unwrap_or_else(|| self.persist.secret_key.public_key())
+	}
+	// A device's certificate names its current key, so a rotated device would no longer be attributed to its root.
+	fn rotate(&mut self, passphrase: Option<String>) -> Result<(), anyhow::Error> {
+		self.refresh()?;
+		if self.persist.certificate.is_some() {
+			return Err(anyhow!("Devices can't rotate their key - have the root certify a new device instead."));
+		}
+		let old_key = self.secret_key()?;
+		let old_public_key = old_key.public_key();
+		let new_key = crypto::SecretKey::from(p256::SecretKey::random(get_rng()?));
+		let succession = Succession::create(old_key, &new_key, get_rng()?);
+		let stored = match (self.has_passphrase(), passphrase) {
+			(true, Some(passphrase)) => StoredKey::lock(&new_key, &passphrase)?,
+			(true, None) => return Err(anyhow!("The passphrase is needed to lock the new key.")),
+			// Rather than leave the new key unprotected when the caller expected otherwise.
+			(false, Some(_)) => return Err(anyhow!("This identity doesn't have a passphrase - set one with set_passphrase after rotating.")),
+			(false, None) => StoredKey::Plain(new_key.clone())
+		};
+		let rotated = self.make_change(|data| {
+			// Someone else rotated first (or certified us) in the meantime: keep their record rather than forking the identity.
+			if data.secret_key.public_key() != old_public_key || data.certificate.is_some() {
+				return false;
+			}
+			data.secret_key = stored.clone();
+			data.info = None;
+			data.issued.clear();
+			data.succession = Some(succession.clone());
+			true
+		})?;
+		if !rotated {
+			return Err(anyhow!("Identity key was rotated elsewhere."));
+		}
+		self.secret_key = Some(new_key);
+		Ok(())
+	}
+	fn secret_key(&self) -> Result<&crypto::SecretKey, anyhow::Error> {
+		self.secret_key.as_ref().ok_or(anyhow!("Identity is locked - unlock it with the passphrase before signing."))
+	}
+	// Every change to our record goes through here.  The change lands on top of whatever other contexts have saved, which can be a rotated or locked key, so the key we hold is brought in line afterwards (even when the save fails, since it may still have refreshed).
+	fn make_change<R>(&mut self, func: impl FnMut(&mut SelfPeerData) -> R) -> Result<R, anyhow::Error> {
+		let result = self.persist.make_change(func);
+		self.sync_secret_key();
+		result
+	}
+	fn refresh(&mut self) -> Result<bool, anyhow::Error> {
+		let changed = self.persist.refresh()?;
+		self.sync_secret_key();
+		Ok(changed)
+	}
+	// The key may have been rotated or locked elsewhere.
+	fn sync_secret_key(&mut self) {
+		self.secret_key = match self.persist.secret_key {
+			StoredKey::Plain(ref secret_key) => Some(secret_key.clone()),
+			StoredKey::Locked { ref public_key, .. } => self.secret_key.take()
+				.filter(|secret_key| secret_key.public_key() == *public_key)
+		};
+	}
+	// Package a signaling message along with the push options its kind calls for.
+	pub fn prepare_signaling(&self, signaling: SignalingMessage, session: &str) -> Result<(String, web_push::PushOptions), anyhow::Error> {
+		let message = SignalingFormat::from(signaling);
+		let options = web_push::PushOptions {
+			padding: self.padding(),
+			..message.push_policy().options(&self.persist.secret_key.public_key(), session)
+		};
+		let str = self.package(&message)?;
+		if str.len() > web_push::MAX_MESSAGE_LEN {
+			return Err(anyhow!("Message didn't fit within {} bytes", web_push::MAX_MESSAGE_LEN));
+		}
+		Ok((str, options))
+	}
+	// Load our identity from the given storage, creating a new one if there isn't one yet.
+	fn open(storage: Backend) -> Result<Self, anyhow::Error> {
+		let persist = match Persist::open_existing(storage.clone(), "self_peer")? {
+			Some(persist) => persist,
+			None => {
+				let secret_key = p256::SecretKey::random(get_rng()?).into();
+				Persist::open(storage, "self_peer", || SelfPeerData {
+					secret_key: StoredKey::Plain(secret_key),
+					info: None,
+					subscriber: None,
+					succession: None,
+					certificate: None,
+					padding: web_push::Padding::default(),
+					auth_lifetime: web_push::AuthLifetime::default(),
+					issued: HashMap::new()
+				})?
+			}
+		};
+		let secret_key = match persist.secret_key {
+			StoredKey::Plain(ref secret_key) => Some(secret_key.clone()),
+			StoredKey::Locked { .. } => None
+		};
+		Ok(Self { persist, secret_key, watch: None })
+	}
+	// Where our identity is kept.  Peers are kept alongside it.
+	pub fn storage(&self) -> &Backend {
+		self.persist.storage()
+	}
+	pub fn padding(&self) -> web_push::Padding {
+		self.persist.padding.clone()
+	}
+	pub fn auth_lifetime(&self) -> web_push::AuthLifetime {
+		self.persist.auth_lifetime.clone()
+	}
+	// Write a backup's identity and peers into storage, and load the identity from there.
+	fn import_backup_into(storage: Backend, backup: &str, passphrase: &str, overwrite: bool) -> Result<Self, anyhow::Error> {
+		let backup = Backup::open(backup, passphrase)?;
+		// Make sure the identity is usable before anything gets written.
+		persist::decode::<SelfPeerData>(&backup.self_peer).context("Backup's identity is corrupted.")?;
+		if !overwrite && storage.get("self_peer")?.is_some() {
+			return Err(anyhow!("An identity already exists - refusing to overwrite it."));
+		}
+		storage.set("self_peer", &backup.self_peer)?;
+		for (key, value) in backup.peers.into_iter().filter(|(key, _)| key.starts_with("peer.")) {
+			if overwrite || storage.get(&key)?.is_none() {
+				storage.set(&key, &value)?;
+			}
+		}
+		peer::rebuild_index(&storage)?;
+		Self::open(storage)
+	}
+	// Authorizations for consecutive slots, as a single authorization-only message.
+	fn mint_authorizations(&self, slots: Range<u32>, now: u32) -> Result<SignalingFormat, anyhow::Error> {
+		let push_info = self.persist.info.as_ref()
+			.ok_or(anyhow!("Can't issue authorizations until push info has been set."))?;
+		let secret_key = self.secret_key()?;
+		let subscriber = self.persist.subscriber.as_deref();
+		let tokens = slots.map(|slot| create_auth(push_info, secret_key, &self.persist.auth_lifetime, slot, now, subscriber))
+			.collect::<Result<Vec<_>, _>>()?;
+		let first = tokens.first().ok_or(anyhow!("No slots to issue authorizations for"))?;
+		Ok(SignalingFormat::JustAuth(
+			first.expiration,
+			first.subscriber.clone(),
+			tokens.iter().map(|token| token.signature).collect()
+		))
 	}
 }
 #[wasm_bindgen]
 impl SelfPeer {
 	#[wasm_bindgen(constructor)]
-	pub fn new() -> Self {
-		Self {
-			persist: Persist::new("self_peer", || SelfPeerData {
-				secret_key: p256::SecretKey::random(get_rng()).into(),
-				info: None,
-				subscriber: None
-			}).unwrap()
-		}
+	pub fn new() -> Result<SelfPeer, JsValue> {
+		Self::open(Backend::default()).to_js_error()
+	}
+	// Like new, but the identity and peers are kept in IndexedDB, which the service worker can also reach.  Resolves to a SelfPeer.
+	pub fn open_indexed_db() -> js_sys::Promise {
+		wasm_bindgen_futures::future_to_promise(async {
+			let storage = IndexedDb::open().await.to_js_error()?;
+			Ok(JsValue::from(SelfPeer::open(Backend::IndexedDb(storage)).to_js_error()?))
+		})
+	}
+	// Resolves once the latest change to our record has been saved, and rejects if it couldn't be.  Changes to localStorage are saved immediately.
+	pub fn saved(&self) -> js_sys::Promise {
+		let saved = self.persist.saved();
+		wasm_bindgen_futures::future_to_promise(async move {
+			saved.await.to_js_error()?;
+			Ok(JsValue::undefined())
+		})
+	}
+	// Resolves once every change so far has been saved.  Changes to localStorage are saved immediately.
+	pub fn flush(&self) -> js_sys::Promise {
+		let storage = self.storage().clone();
+		wasm_bindgen_futures::future_to_promise(async move {
+			if let Backend::IndexedDb(storage) = storage {
+				storage.flush().await.to_js_error()?;
+			}
+			Ok(JsValue::undefined())
+		})
 	}
 	pub fn get_public_key(&self) -> Box<[u8]> {
-		p256::EncodedPoint::from_secret_key(&self.persist.secret_key, false).as_bytes().iter().map(|x| *x).collect::<Vec<_>>().into_boxed_slice()
+		self.persist.secret_key.public_key().as_bytes().to_vec().into_boxed_slice()
+	}
+	// The key that peers know us by: the root identity's key if we're a certified device, otherwise our own.
+	pub fn get_identity_key(&self) -> Box<[u8]> {
+		self.identity_key().as_bytes().to_vec().into_boxed_slice()
+	}
+	// Compare these with the peer in person (or over some other channel you trust) before calling Peer::set_verified.
+	pub fn safety_number(&self, peer: &Peer) -> String {
+		safety_number::digits(&self.identity_key(), &peer.public_key())
+	}
+	pub fn safety_emoji(&self, peer: &Peer) -> String {
+		safety_number::emoji(&self.identity_key(), &peer.public_key())
+	}
+	pub fn safety_words(&self, peer: &Peer) -> String {
+		safety_number::words(&self.identity_key(), &peer.public_key())
+	}
+	// Sign a certificate letting another browser (identified by its get_public_key) act as a device of this identity.
+	pub fn certify_device(&self, device_key: &[u8]) -> Result<Box<[u8]>, JsValue> {
+		if self.persist.certificate.is_some() {
+			return Err(anyhow!("Only a root identity can certify devices.")).to_js_error();
+		}
+		let device_key = p256::EncodedPoint::from_bytes(device_key)
+			.map_err(|_| anyhow!("Device key couldn't be decoded")).to_js_error()?.into();
+		let certificate = DeviceCertificate::issue(self.secret_key().to_js_error()?, device_key, get_rng().to_js_error()?);
+		Ok(certificate.to_bytes().into_boxed_slice())
+	}
+	// Become a device of the root identity that issued this certificate.  Pass None to go back to being our own identity.
+	pub fn set_device_certificate(&mut self, certificate: Option<Vec<u8>>) -> Result<(), JsValue> {
+		let certificate = if let Some(bytes) = certificate {
+			let certificate = DeviceCertificate::from_bytes(&bytes).to_js_error()?;
+			if certificate.device_key.compress() != self.persist.secret_key.public_key().compress() {
+				return Err(anyhow!("Device certificate is for a different key.")).to_js_error();
+			}
+			certificate.verify().to_js_error()?;
+			Some(certificate)
+		} else {
+			None
+		};
+		self.make_change(|data| {
+			data.certificate = certificate.clone();
+		}).to_js_error()
+	}
+	// handler is called with the key of each record that another tab or the service worker changes: "self_peer" for ours (call reload), or "peer." followed by a peer_id.  Peers don't catch up on their own, so call reload on any Peer held for that id.  Pass null to stop.
+	pub fn set_change_handler(&mut self, handler: Option<js_sys::Function>) -> Result<(), JsValue> {
+		// Drop the old watch first, so that it doesn't unset the new handler on IndexedDB.
+		self.watch = None;
+		if let Some(handler) = handler {
+			self.watch = Some(self.storage().watch(handler).to_js_error()?);
+		}
+		Ok(())
+	}
+	// Pick up changes saved by other tabs or the service worker.  Returns whether there were any.
+	pub fn reload(&mut self) -> Result<bool, JsValue> {
+		self.refresh().to_js_error()
+	}
+	pub fn is_locked(&self) -> bool {
+		self.secret_key.is_none()
+	}
+	pub fn has_passphrase(&self) -> bool {
+		match self.persist.secret_key {
+			StoredKey::Locked { .. } => true,
+			StoredKey::Plain(_) => false
+		}
+	}
+	// Fails on the wrong passphrase even if the identity is already unlocked, and on an identity without one.
+	pub fn unlock(&mut self, passphrase: &str) -> Result<(), JsValue> {
+		self.open_key(passphrase).to_js_error()
+	}
+	fn open_key(&mut self, passphrase: &str) -> Result<(), anyhow::Error> {
+		self.refresh()?;
+		match self.persist.secret_key {
+			StoredKey::Locked { ref public_key, ref sealed } => {
+				let bytes = sealed.open(passphrase, public_key.as_bytes())?;
+				let secret_key = p256::SecretKey::from_bytes(bytes.as_slice())
+					.map_err(|_| anyhow!("Unlocked secret key was invalid"))?;
+				self.secret_key = Some(secret_key.into());
+				Ok(())
+			},
+			StoredKey::Plain(_) => Err(anyhow!("This identity doesn't have a passphrase."))
+		}
+	}
+	pub fn lock(&mut self) -> Result<(), JsValue> {
+		if !self.has_passphrase() {
+			return Err(anyhow!("Can't lock an identity that doesn't have a passphrase.")).to_js_error();
+		}
+		self.secret_key = None;
+		Ok(())
+	}
+	// Set, change, or (with None) remove the passphrase.  The identity must be unlocked and the key itself stays the same.  The promise resolves once the re-encrypted key has been saved, and rejects if it couldn't be.
+	pub fn set_passphrase(&mut self, passphrase: Option<String>) -> Result<js_sys::Promise, JsValue> {
+		self.change_passphrase(passphrase.as_deref()).to_js_error()?;
+		Ok(self.saved())
+	}
+	fn change_passphrase(&mut self, passphrase: Option<&str>) -> Result<(), anyhow::Error> {
+		self.refresh()?;
+		let secret_key = self.secret_key()?;
+		let public_key = secret_key.public_key();
+		let stored = if let Some(passphrase) = passphrase {
+			StoredKey::lock(secret_key, passphrase)?
+		} else {
+			StoredKey::Plain(secret_key.clone())
+		};
+		let replaced = self.make_change(|data| {
+			// Don't put the old key back over one rotated in elsewhere since we refreshed.
+			let ours = data.secret_key.public_key() == public_key;
+			if ours {
+				data.secret_key = stored.clone();
+			}
+			ours
+		})?;
+		if !replaced {
+			return Err(anyhow!("Identity key was changed elsewhere - the passphrase wasn't set."));
+		}
+		Ok(())
+	}
+	// An encrypted copy of our identity (and optionally every stored peer) that can be restored with import_backup.
+	pub fn export_backup(&self, passphrase: &str, include_peers: bool) -> Result<String, JsValue> {
+		let self_peer = persist::encode(self.persist.as_ref()).to_js_error()?;
+		let peers = if include_peers {
+			self.storage().keys().to_js_error()?.into_iter()
+				.filter(|key| key.starts_with("peer."))
+				.filter_map(|key| match self.storage().get(&key) {
+					Ok(Some(value)) => Some(Ok((key, value))),
+					Ok(None) => None,
+					Err(e) => Some(Err(e))
+				})
+				.collect::<Result<Vec<_>, _>>().to_js_error()?
+		} else {
+			Vec::new()
+		};
+		Backup { self_peer, peers }.seal(passphrase).to_js_error()
+	}
+	// Restore a backup into localStorage.
+	pub fn import_backup(backup: &str, passphrase: &str, overwrite: bool) -> Result<SelfPeer, JsValue> {
+		Self::import_backup_into(Backend::default(), backup, passphrase, overwrite).to_js_error()
+	}
+	// Restore a backup into IndexedDB (see open_indexed_db).  Resolves to a SelfPeer once everything has been saved.
+	pub fn import_backup_indexed_db(backup: String, passphrase: String, overwrite: bool) -> js_sys::Promise {
+		wasm_bindgen_futures::future_to_promise(async move {
+			let storage = IndexedDb::open().await.to_js_error()?;
+			let self_peer = SelfPeer::import_backup_into(Backend::IndexedDb(storage.clone()), &backup, &passphrase, overwrite).to_js_error()?;
+			storage.flush().await.to_js_error()?;
+			Ok(JsValue::from(self_peer))
+		})
+	}
+	// Every stored peer in one archive signed by our key, and encrypted as well if there's a passphrase.  Unlike export_backup it doesn't include our identity, so it's for sharing peers between browsers of ours.
+	pub fn export_peers(&self, passphrase: Option<String>) -> Result<String, JsValue> {
+		let contents = archive::Contents {
+			created: (js_sys::Date::now() / 1000.0) as u32,
+			records: peer::export_records(self.storage()).to_js_error()?
+		};
+		archive::seal(&contents, self.secret_key().to_js_error()?, self.persist.certificate.as_ref(), passphrase.as_deref(), get_rng().to_js_error()?).to_js_error()
+	}
+	// Merge the peers from an export_peers archive into ours.  Only archives signed by this identity (or by the key it rotated from), or by a device it certified, are accepted.  Returns {added, merged, conflicts: [{peer_id, field}], failed: [{peer_id, error}]}, where conflicts are fields that differed and kept our value, and failed are peers that couldn't be saved (the rest are still imported).
+	pub fn import_peers(&self, archive: &str, passphrase: Option<String>) -> Result<JsValue, JsValue> {
+		let contents = self.open_archive(archive, passphrase.as_deref()).to_js_error()?;
+		let now = (js_sys::Date::now() / 1000.0) as u32;
+		let report = peer::import_records(self.storage(), contents.records, &self.auth_lifetime(), now).to_js_error()?;
+		JsValue::from_serde(&report).to_js_error()
+	}
+	fn open_archive(&self, archive: &str, passphrase: Option<&str>) -> Result<archive::Contents, anyhow::Error> {
+		let (contents, signer) = archive::open(archive, passphrase)?;
+		let trusted = [
+			Some(self.persist.secret_key.public_key()),
+			Some(self.identity_key()),
+			self.persist.succession.as_ref().map(|succession| succession.old_key)
+		];
+		// Devices sign with their own key, so an archive from another device of ours is trusted through its certificate.
+		let signed_by = |key: &crypto::PublicKey| trusted.iter().flatten().any(|trusted| trusted.compress() == key.compress());
+		if !signed_by(&signer.key) && !signer.root_key.as_ref().is_some_and(signed_by) {
+			return Err(anyhow!("Archive wasn't signed by this identity."));
+		}
+		Ok(contents)
+	}
+	// We won't be issuing removed peers any more authorizations.
+	fn forget_removed(&mut self, peer_ids: &[String]) -> Result<(), anyhow::Error> {
+		if peer_ids.iter().any(|peer_id| self.persist.issued.contains_key(peer_id)) {
+			self.make_change(|data| {
+				for peer_id in peer_ids {
+					data.issued.remove(peer_id);
+				}
+			})?;
+		}
+		Ok(())
+	}
+	// Report the storage each peer takes up, drop expired authorizations, and archive or delete peers that have been unreachable for too long.  The policy is like {"max_age": 2419200, "disposal": "Archive"} (undefined for 8 weeks and Archive).  Run it with dry_run first to see what would go: that report has no archive and nothing is written.  Archiving signs with our key, so the identity has to be unlocked, and archived peers are only removed once the archive has been saved and passed to remove_archived.
+	pub fn maintain_peers(&mut self, policy: JsValue, dry_run: bool) -> Result<JsValue, JsValue> {
+		let policy: maintenance::Policy = if policy.is_undefined() || policy.is_null() {
+			maintenance::Policy::default()
+		} else {
+			policy.into_serde().to_js_error()?
+		};
+		let now = (js_sys::Date::now() / 1000.0) as u32;
+		let report = peer::maintain(self.storage(), &policy, &self.auth_lifetime(), now, dry_run, |records| {
+			let contents = archive::Contents { created: now, records };
+			archive::seal(&contents, self.secret_key()?, self.persist.certificate.as_ref(), None, get_rng()?)
+		}).to_js_error()?;
+		self.forget_removed(&report.removed).to_js_error()?;
+		JsValue::from_serde(&report).to_js_error()
+	}
+	// Remove the peers in an archive from maintain_peers, once it's been saved.  Peers that changed since are kept.  Returns the ids of the peers removed.
+	pub fn remove_archived(&mut self, archive: &str) -> Result<js_sys::Array, JsValue> {
+		let contents = self.open_archive(archive, None).to_js_error()?;
+		let removed = peer::remove_archived(self.storage(), &contents.records).to_js_error()?;
+		self.forget_removed(&removed).to_js_error()?;
+		Ok(removed.into_iter().map(JsValue::from).collect())
 	}
 	pub fn am_dominant(&self, other: &Peer) -> bool {
 		let self_magnitude = self.pk_magnitude();
@@ -60,69 +554,135 @@ impl SelfPeer {
 		assert_ne!(self_magnitude, other_magnitude);
 		self_magnitude > other_magnitude
 	}
-	pub fn set_push_info(&mut self, pk_bytes: &[u8], auth_bytes: &[u8], endpoint: String) -> Result<(), JsValue> {
+	pub fn set_push_info(&mut self, pk_bytes: &[u8], auth_bytes: &[u8], endpoint: String, encoding: Option<String>) -> Result<(), JsValue> {
 		let public_key = p256::EncodedPoint::from_bytes(pk_bytes)
 			.map_err(|_| anyhow!("Public key couldn't be decoded")).to_js_error()?.into();
-		let mut auth = [0; 16];
-		auth.copy_from_slice(auth_bytes);
-		self.persist.make_change(|data| {
-			data.info = Some(web_push::PushInfo {
-				public_key,
-				auth,
-				endpoint
-			})
+		// Browsers that don't list their supported encodings only understand aesgcm.
+		let encoding = match encoding {
+			Some(name) => web_push::ContentEncoding::from_name(&name)
+				.ok_or(anyhow!("Unsupported content encoding: {}", name)).to_js_error()?,
+			None => web_push::ContentEncoding::AesGcm
+		};
+		let auth = <[u8; 16]>::try_from(auth_bytes)
+			.map_err(|_| anyhow!("Push auth secret must be 16 bytes")).to_js_error()?;
+		let info = web_push::PushInfo {
+			public_key,
+			auth,
+			endpoint,
+			encoding
+		};
+		self.make_change(|data| {
+			// Authorizations are tied to the subscription, so every peer needs new ones if it changed (rather than being set again on startup).
+			if !matches!(data.info.as_ref(), Some(old_info) if old_info.same_subscription(&info)) {
+				data.issued.clear();
+			}
+			data.info = Some(info.clone());
 		}).to_js_error()
 	}
 	pub fn get_introduction(&self) -> Result<String, JsValue> {
-		fn create_auth(info: &web_push::PushInfo, secret_key: &crypto::SecretKey, from_now: usize, subscriber: Option<&str>) -> Result<web_push::AuthToken, anyhow::Error> {
-			let mut rng = get_rng();
-			let subscriber_str = subscriber.unwrap_or("https://github.com/evan-brass/web3.0-test");
-			let endpoint = Url::parse(&info.endpoint).context("URL parsing for the endpoint failed")?;
-			let audience = endpoint.origin().unicode_serialization();
-			let now = (js_sys::Date::now() / 1000.0) as usize;
-			let expiration = now + from_now * 12 * 60;
-			let body = format!(r#"{{"aud":"{}","exp":{},"sub":"{}"}}"#, audience, expiration, subscriber_str);
-			let body = base64::encode_config(body.as_bytes(), base64::URL_SAFE_NO_PAD);
-
-			let buffer = format!("eyJ0eXAiOiJKV1QiLCJhbGciOiJFUzI1NiJ9.{}", body);
-			let signer = SigningKey::from(secret_key.as_ref());
-			let signature: crypto::Signature = signer.sign_with_rng(&mut rng, buffer.as_bytes()).into();
-			
-			Ok(web_push::AuthToken {
-				expiration: expiration as u32,
-				subscriber: subscriber_str.into(),
-				signature
-			})
-		}
 		if let Some(ref push_info) = self.persist.info {
 			let auth = create_auth(
 				push_info, 
-				&self.persist.secret_key, 
-				1, 
-				self.persist.subscriber.as_ref().map(|s|s.as_str())
+				self.secret_key().to_js_error()?, 
+				&self.persist.auth_lifetime,
+				0,
+				(js_sys::Date::now() / 1000.0) as u32,
+				self.persist.subscriber.as_deref()
 			).to_js_error()?;
 			let message = SignalingFormat::Introduction(push_info.clone(), auth);
-			let mut buffer = Vec::try_from(&message).to_js_error()?;
-
-			let rec_sig = crypto::RecoverableSignature::try_sign_recoverable(&self.persist.secret_key, &buffer).to_js_error()?;
-			buffer.extend_from_slice(&rec_sig.to_bytes());
-
-			Ok(base64::encode_config(&buffer, base64::URL_SAFE_NO_PAD))
+			self.package(&message).to_js_error()
 		} else {
 			Err(anyhow!("Can't create an introduction if self doesn't have push info.")).to_js_error()
 		}
 	}
+	// Replace our identity key with a fresh one.  The passphrase is needed if (and only if) the identity has one, to lock the new key with.  The push subscription is tied to the old key, so push info is cleared and must be set again before the succession can be sent.  The promise resolves once the new key has been saved, and rejects if it couldn't be.
+	pub fn rotate_key(&mut self, passphrase: Option<String>) -> Result<js_sys::Promise, JsValue> {
+		self.rotate(passphrase).to_js_error()?;
+		Ok(self.saved())
+	}
+	// A signaling message announcing our latest key rotation, to be pushed to every known peer.
+	pub fn get_succession(&self) -> Result<String, JsValue> {
+		let succession = self.persist.succession.as_ref()
+			.ok_or(anyhow!("This identity has never rotated its key.")).to_js_error()?;
+		let push_info = self.persist.info.as_ref()
+			.ok_or(anyhow!("Can't announce a succession until push info has been set for the new key.")).to_js_error()?;
+		let auth = create_auth(
+			push_info,
+			self.secret_key().to_js_error()?,
+			&self.persist.auth_lifetime,
+			0,
+			(js_sys::Date::now() / 1000.0) as u32,
+			self.persist.subscriber.as_deref()
+		).to_js_error()?;
+		let message = SignalingFormat::Succession(Box::new(succession.clone()), push_info.clone(), auth);
+		self.package(&message).to_js_error()
+	}
+	pub fn clear_succession(&mut self) -> Result<(), JsValue> {
+		self.make_change(|data| {
+			data.succession = None;
+		}).to_js_error()
+	}
+	// The policy is an object like {"Buckets": [1024, 2048, 4096]}, {"Random": 512}, {"Multiple": 256}, "Maximum" or "None".
+	pub fn get_padding(&self) -> Result<JsValue, JsValue> {
+		JsValue::from_serde(&self.persist.padding).to_js_error()
+	}
+	pub fn set_padding(&mut self, policy: JsValue) -> Result<(), JsValue> {
+		let padding: web_push::Padding = policy.into_serde().to_js_error()?;
+		self.make_change(|data| {
+			data.padding = padding.clone();
+		}).to_js_error()
+	}
+	// The policy is an object like {"lifetime": 86400, "clock_skew": 300}, in seconds.
+	pub fn get_auth_lifetime(&self) -> Result<JsValue, JsValue> {
+		JsValue::from_serde(&self.persist.auth_lifetime).to_js_error()
+	}
+	pub fn set_auth_lifetime(&mut self, policy: JsValue) -> Result<(), JsValue> {
+		let lifetime: web_push::AuthLifetime = policy.into_serde().to_js_error()?;
+		// Refuse a policy that couldn't mint a usable token.
+		lifetime.expiration((js_sys::Date::now() / 1000.0) as u32, 0).to_js_error()?;
+		self.make_change(|data| {
+			data.auth_lifetime = lifetime.clone();
+		}).to_js_error()
+	}
+	// Mint fresh authorizations for every known peer that's running low on them from us.  Meant to be called periodically (a timer or the service worker); each result should be pushed to its peer with Peer.send_signaling.  If a push fails, call forget_issued so that the peer is topped up again on the next run.
+	pub fn refresh_authorizations(&mut self) -> Result<js_sys::Array, JsValue> {
+		let now = (js_sys::Date::now() / 1000.0) as u32;
+		let peer_ids = peer::peer_ids(self.storage()).to_js_error()?;
+		let mut refreshes = Vec::new();
+		for peer_id in peer_ids.iter() {
+			let issued = self.persist.issued.get(peer_id).copied();
+			if let Some(slots) = refresh_slots(&self.persist.auth_lifetime, issued, now).to_js_error()? {
+				let message = self.mint_authorizations(slots, now).to_js_error()?;
+				refreshes.push((peer_id.clone(), message));
+			}
+		}
+		self.make_change(|data| {
+			for (peer_id, message) in refreshes.iter() {
+				if let Some(newest) = message.auths().last() {
+					data.issued.insert(peer_id.clone(), newest.expiration);
+				}
+			}
+			// Forget peers that have been deleted.
+			data.issued.retain(|peer_id, _| peer_ids.contains(peer_id));
+		}).to_js_error()?;
+		Ok(refreshes.into_iter()
+			.map(|(peer_id, message)| JsValue::from(AuthorizationRefresh { peer_id, message }))
+			.collect())
+	}
+	pub fn forget_issued(&mut self, peer_id: String) -> Result<(), JsValue> {
+		self.make_change(|data| {
+			data.issued.remove(&peer_id);
+		}).to_js_error()
+	}
 	pub fn package_signaling(&self, signaling: SignalingMessage, enforce_4k: bool) -> Result<String, JsValue> {
-		let mut buffer = Vec::try_from(&SignalingFormat::from(signaling)).to_js_error()?;
-		let rec_sig = crypto::RecoverableSignature::try_sign_recoverable(&self.persist.secret_key, &buffer).to_js_error()?;
-		buffer.extend_from_slice(&rec_sig.to_bytes());
-		let str = base64::encode_config(buffer, base64::URL_SAFE_NO_PAD);
+		let str = self.package(&SignalingFormat::from(signaling)).to_js_error()?;
 
-		if str.as_bytes().len() > 4096 {
+		// Leave room for the encryption overhead, whichever encoding the recipient uses.
+		if str.len() > web_push::MAX_MESSAGE_LEN {
 			if enforce_4k {
-				return Err(anyhow!("Message didn't fit within 4096 bytes")).to_js_error();
+				return Err(anyhow!("Message didn't fit within {} bytes", web_push::MAX_MESSAGE_LEN)).to_js_error();
 			} else {
-				println!("Message didn't fit within 4096, but enforce_4k wasn't set.");
+				web_sys::console::warn_1(&JsValue::from(format!("Message didn't fit within {} bytes, but enforce_4k wasn't set.", web_push::MAX_MESSAGE_LEN)));
 			}
 		}
 
@@ -130,6 +690,255 @@ impl SelfPeer {
 	}
 }
 
+#[wasm_bindgen]
+pub struct AuthorizationRefresh {
+	peer_id: String,
+	message: SignalingFormat
+}
+#[wasm_bindgen]
+impl AuthorizationRefresh {
+	pub fn peer_id(&self) -> String {
+		self.peer_id.clone()
+	}
+	pub fn message(&self) -> SignalingMessage {
+		SignalingMessage::from(self.message.clone())
+	}
+}
+
 #[cfg(test)]
 mod tests {
-}
\ No newline at end of file
+	use super::*;
+	use super::super::signaling::parse_message;
+
+	fn assert_redacted(formatted: &str, secret_key: &crypto::SecretKey) {
+		let bytes = secret_key.to_bytes();
+		let hex = bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+		assert!(!formatted.to_lowercase().contains(&hex));
+		assert!(!formatted.contains(&base64::encode(bytes)));
+		assert!(!formatted.contains(&base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)));
+		assert!(!formatted.contains(&format!("{:?}", bytes.as_slice())));
+		assert!(formatted.contains("<redacted>"));
+	}
+
+	#[test]
+	fn debug_redacts_secret_key() {
+		let secret_key = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let self_peer = SelfPeer {
+			persist: Persist::detached("self_peer", SelfPeerData {
+				secret_key: StoredKey::Plain(secret_key.clone()),
+				info: None,
+				subscriber: None,
+				succession: None,
+				certificate: None,
+				padding: web_push::Padding::default(),
+				auth_lifetime: web_push::AuthLifetime::default(),
+				issued: HashMap::new()
+			}),
+			secret_key: Some(secret_key.clone()),
+			watch: None
+		};
+		assert_redacted(&format!("{:?}", self_peer), &secret_key);
+		assert_redacted(&format!("{:#?}", self_peer), &secret_key);
+		assert_redacted(&format!("{:?}", self_peer.persist.as_ref()), &secret_key);
+	}
+
+	#[test]
+	fn backup_restores_into_storage() {
+		let source = Backend::Memory(persist::MemoryStorage::default());
+		let original = SelfPeer::open(source.clone()).unwrap();
+		let peer = Peer::open(source.clone(), crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng())).public_key()).unwrap();
+		let key = format!("peer.{}", peer.peer_id());
+		let backup = Backup {
+			self_peer: source.get("self_peer").unwrap().unwrap(),
+			peers: vec![(key.clone(), source.get(&key).unwrap().unwrap())]
+		}.seal("passphrase").unwrap();
+
+		let target = Backend::Memory(persist::MemoryStorage::default());
+		let restored = SelfPeer::import_backup_into(target.clone(), &backup, "passphrase", false).unwrap();
+		assert_eq!(restored.get_public_key(), original.get_public_key());
+		assert_eq!(peer::peer_ids(&target).unwrap(), vec![peer.peer_id()]);
+		// There's an identity there now.
+		assert!(SelfPeer::import_backup_into(target, &backup, "passphrase", false).is_err());
+	}
+	#[test]
+	fn device_dominance() {
+		let mut self_peer = SelfPeer::open(Backend::Memory(persist::MemoryStorage::default())).unwrap();
+		let root = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let certificate = DeviceCertificate::issue(&root, self_peer.secret_key().unwrap().public_key(), rand::thread_rng());
+		self_peer.set_device_certificate(Some(certificate.to_bytes())).unwrap();
+		assert_eq!(self_peer.pk_magnitude(), p256::Scalar::from_bytes_reduced(root.public_key().compress().x().unwrap()));
+	}
+	#[test]
+	fn certified_devices_keep_their_key() {
+		let mut self_peer = SelfPeer::open(Backend::Memory(persist::MemoryStorage::default())).unwrap();
+		let root = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let device_key = self_peer.secret_key().unwrap().public_key();
+		let certificate = DeviceCertificate::issue(&root, device_key, rand::thread_rng());
+		self_peer.set_device_certificate(Some(certificate.to_bytes())).unwrap();
+
+		assert!(self_peer.rotate(None).is_err());
+		assert_eq!(self_peer.secret_key().unwrap().public_key(), device_key);
+		// Our messages are still attributed to the root.
+		let parsed = parse_message(&self_peer.package(&SignalingFormat::JustIce(Vec::new())).unwrap()).unwrap();
+		assert_eq!(parsed.public_key, root.public_key());
+		assert_eq!(parsed.device_key, Some(device_key));
+	}
+	#[test]
+	fn passphrases() {
+		let storage = Backend::Memory(persist::MemoryStorage::default());
+		let mut self_peer = SelfPeer::open(storage.clone()).unwrap();
+		let public_key = self_peer.secret_key().unwrap().public_key();
+		let sign = |self_peer: &SelfPeer| self_peer.package(&SignalingFormat::JustIce(Vec::new()));
+		// Nothing to check a passphrase against yet:
+		assert!(self_peer.open_key("passphrase").is_err());
+
+		self_peer.change_passphrase(Some("passphrase")).unwrap();
+		assert!(self_peer.has_passphrase());
+		// Still unlocked, but the passphrase is checked anyway.
+		assert!(self_peer.open_key("wrong").is_err());
+		self_peer.open_key("passphrase").unwrap();
+		assert!(!self_peer.is_locked());
+
+		self_peer.lock().unwrap();
+		assert!(self_peer.is_locked());
+		assert!(sign(&self_peer).is_err());
+		assert!(self_peer.change_passphrase(Some("another")).is_err());
+		assert!(self_peer.open_key("wrong").is_err());
+		assert!(self_peer.is_locked());
+		self_peer.open_key("passphrase").unwrap();
+		sign(&self_peer).unwrap();
+
+		// A new passphrase locks the same key.
+		self_peer.change_passphrase(Some("another")).unwrap();
+		let mut reopened = SelfPeer::open(storage.clone()).unwrap();
+		assert!(reopened.is_locked());
+		assert!(reopened.open_key("passphrase").is_err());
+		reopened.open_key("another").unwrap();
+		assert_eq!(reopened.secret_key().unwrap().public_key(), public_key);
+		// And removing it stores the same key in the clear.
+		reopened.change_passphrase(None).unwrap();
+		let reopened = SelfPeer::open(storage).unwrap();
+		assert!(!reopened.is_locked() && !reopened.has_passphrase());
+		assert_eq!(reopened.secret_key().unwrap().public_key(), public_key);
+	}
+	#[test]
+	fn rotation_without_a_passphrase() {
+		let mut self_peer = SelfPeer::open(Backend::Memory(persist::MemoryStorage::default())).unwrap();
+		let old_key = self_peer.secret_key().unwrap().public_key();
+		// A passphrase that wouldn't be used to lock anything is refused.
+		assert!(self_peer.rotate(Some(String::from("passphrase"))).is_err());
+		assert_eq!(self_peer.secret_key().unwrap().public_key(), old_key);
+		self_peer.rotate(None).unwrap();
+		let succession = self_peer.persist.succession.clone().unwrap();
+		assert_eq!(succession.old_key, old_key);
+		assert_eq!(succession.new_key, self_peer.secret_key().unwrap().public_key());
+		assert!(!self_peer.has_passphrase());
+	}
+	#[test]
+	fn archives_from_sibling_devices() {
+		let root = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let mut devices = Vec::new();
+		for _ in 0..2 {
+			let mut self_peer = SelfPeer::open(Backend::Memory(persist::MemoryStorage::default())).unwrap();
+			let certificate = DeviceCertificate::issue(&root, self_peer.secret_key().unwrap().public_key(), rand::thread_rng());
+			self_peer.set_device_certificate(Some(certificate.to_bytes())).unwrap();
+			devices.push((self_peer, certificate));
+		}
+		let contents = archive::Contents { created: 1_601_337_600, records: Vec::new() };
+		let (sibling, certificate) = &devices[1];
+		let archive = archive::seal(&contents, sibling.secret_key().unwrap(), Some(certificate), None, rand::thread_rng()).unwrap();
+		assert_eq!(devices[0].0.open_archive(&archive, None).unwrap(), contents);
+
+		// Without the certificate it's just a stranger's key:
+		let archive = archive::seal(&contents, sibling.secret_key().unwrap(), None, None, rand::thread_rng()).unwrap();
+		assert!(devices[0].0.open_archive(&archive, None).is_err());
+		// As is a device of some other root:
+		let stranger = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let certificate = DeviceCertificate::issue(&stranger, sibling.secret_key().unwrap().public_key(), rand::thread_rng());
+		let archive = archive::seal(&contents, sibling.secret_key().unwrap(), Some(&certificate), None, rand::thread_rng()).unwrap();
+		assert!(devices[0].0.open_archive(&archive, None).is_err());
+	}
+	#[test]
+	fn push_info_changes() {
+		let mut self_peer = SelfPeer::open(Backend::Memory(persist::MemoryStorage::default())).unwrap();
+		let public_key = p256::EncodedPoint::from_secret_key(&p256::SecretKey::random(rand::thread_rng()), false);
+		let endpoint = String::from("https://updates.push.services.mozilla.com/wpush/v2/gAAAAABfcDCt");
+		let encoding = Some(String::from("aes128gcm"));
+		self_peer.set_push_info(public_key.as_bytes(), &[7; 16], endpoint.clone(), encoding.clone()).unwrap();
+		self_peer.make_change(|data| data.issued.insert(String::from("peer"), 1_601_337_600)).unwrap();
+
+		// The same subscription, as on every startup:
+		self_peer.set_push_info(public_key.as_bytes(), &[7; 16], endpoint.clone(), encoding.clone()).unwrap();
+		assert_eq!(self_peer.persist.issued.len(), 1);
+		// A new one:
+		self_peer.set_push_info(public_key.as_bytes(), &[8; 16], endpoint, encoding).unwrap();
+		assert!(self_peer.persist.issued.is_empty());
+	}
+	#[test]
+	fn refresh_schedule() {
+		const HOUR: u32 = 60 * 60;
+		let lifetime = web_push::AuthLifetime::default();
+		let now = 1_601_337_600 + HOUR;
+
+		// A new peer gets enough for two days:
+		let slots = refresh_slots(&lifetime, None, now).unwrap().unwrap();
+		assert_eq!(slots.start, 0);
+		let newest = lifetime.expiration(now, slots.end - 1).unwrap();
+		assert!(lifetime.usable_until(newest) >= now + REFRESH_TARGET);
+		assert!(lifetime.usable_until(newest) < now + REFRESH_TARGET + web_push::AUTH_SLOT);
+
+		// Nothing while there's more than a day left:
+		assert_eq!(refresh_slots(&lifetime, Some(newest), now).unwrap(), None);
+		assert_eq!(refresh_slots(&lifetime, Some(newest), lifetime.usable_until(newest) - RUNNING_LOW).unwrap(), None);
+
+		// Then it carries on from the last slot issued:
+		let later = lifetime.usable_until(newest) - RUNNING_LOW + 1;
+		let slots = refresh_slots(&lifetime, Some(newest), later).unwrap().unwrap();
+		assert_eq!(lifetime.expiration(later, slots.start).unwrap(), newest + web_push::AUTH_SLOT);
+		assert!(lifetime.usable_until(lifetime.expiration(later, slots.end - 1).unwrap()) >= later + REFRESH_TARGET);
+
+		// Tokens that ran out long ago are ignored:
+		let much_later = newest + 10 * 24 * HOUR;
+		assert_eq!(refresh_slots(&lifetime, Some(newest), much_later).unwrap().unwrap().start, 0);
+	}
+
+	// self_peer as it was stored at each version, using the struct definitions from history: secret key [1; 32], push info for ".../fixture1" with auth [1; 16], and a subscriber.  From v5 the padding is Multiple(512), from v6 the lifetime is 12 hours with 60 seconds of skew, and v7 has issued an authorization to "fixture".
+	const FIXTURES: [&str; 8] = [
+		"IAAAAAAAAAABAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE7AAAAAAAAAGh0dHBzOi8vdXBkYXRlcy5wdXNoLnNlcnZpY2VzLm1vemlsbGEuY29tL3dwdXNoL3YyL2ZpeHR1cmUxAQEBAQEBAQEBAQEBAQEBAUEAAAAAAAAABCCcMXtjeTXdPaHFT2NJXfsx+X0pPfCFcQMgWVyarLg/3eTGn8F6DHTCDMaSZi8EmJK6N6S6R9LHDNipmYY5H5sBGgAAAAAAAABtYWlsdG86Zml4dHVyZUBleGFtcGxlLmNvbQ==",
+		"AAAAACAAAAAAAAAAAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBOwAAAAAAAABodHRwczovL3VwZGF0ZXMucHVzaC5zZXJ2aWNlcy5tb3ppbGxhLmNvbS93cHVzaC92Mi9maXh0dXJlMQEBAQEBAQEBAQEBAQEBAQFBAAAAAAAAAAQgnDF7Y3k13T2hxU9jSV37Mfl9KT3whXEDIFlcmqy4P93kxp/Begx0wgzGkmYvBJiSujekukfSxwzYqZmGOR+bARoAAAAAAAAAbWFpbHRvOmZpeHR1cmVAZXhhbXBsZS5jb20=",
+		"AAAAACAAAAAAAAAAAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBOwAAAAAAAABodHRwczovL3VwZGF0ZXMucHVzaC5zZXJ2aWNlcy5tb3ppbGxhLmNvbS93cHVzaC92Mi9maXh0dXJlMQEBAQEBAQEBAQEBAQEBAQFBAAAAAAAAAAQgnDF7Y3k13T2hxU9jSV37Mfl9KT3whXEDIFlcmqy4P93kxp/Begx0wgzGkmYvBJiSujekukfSxwzYqZmGOR+bARoAAAAAAAAAbWFpbHRvOmZpeHR1cmVAZXhhbXBsZS5jb20A",
+		"AAAAACAAAAAAAAAAAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBOwAAAAAAAABodHRwczovL3VwZGF0ZXMucHVzaC5zZXJ2aWNlcy5tb3ppbGxhLmNvbS93cHVzaC92Mi9maXh0dXJlMQEBAQEBAQEBAQEBAQEBAQFBAAAAAAAAAAQgnDF7Y3k13T2hxU9jSV37Mfl9KT3whXEDIFlcmqy4P93kxp/Begx0wgzGkmYvBJiSujekukfSxwzYqZmGOR+bARoAAAAAAAAAbWFpbHRvOmZpeHR1cmVAZXhhbXBsZS5jb20AAA==",
+		"AAAAACAAAAAAAAAAAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBOwAAAAAAAABodHRwczovL3VwZGF0ZXMucHVzaC5zZXJ2aWNlcy5tb3ppbGxhLmNvbS93cHVzaC92Mi9maXh0dXJlMQEBAQEBAQEBAQEBAQEBAQFBAAAAAAAAAAQgnDF7Y3k13T2hxU9jSV37Mfl9KT3whXEDIFlcmqy4P93kxp/Begx0wgzGkmYvBJiSujekukfSxwzYqZmGOR+bAQAAAAEaAAAAAAAAAG1haWx0bzpmaXh0dXJlQGV4YW1wbGUuY29tAAA=",
+		"AAAAACAAAAAAAAAAAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBOwAAAAAAAABodHRwczovL3VwZGF0ZXMucHVzaC5zZXJ2aWNlcy5tb3ppbGxhLmNvbS93cHVzaC92Mi9maXh0dXJlMQEBAQEBAQEBAQEBAQEBAQFBAAAAAAAAAAQgnDF7Y3k13T2hxU9jSV37Mfl9KT3whXEDIFlcmqy4P93kxp/Begx0wgzGkmYvBJiSujekukfSxwzYqZmGOR+bAQAAAAEaAAAAAAAAAG1haWx0bzpmaXh0dXJlQGV4YW1wbGUuY29tAAABAAAAAAIAAAAAAAA=",
+		"AAAAACAAAAAAAAAAAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBOwAAAAAAAABodHRwczovL3VwZGF0ZXMucHVzaC5zZXJ2aWNlcy5tb3ppbGxhLmNvbS93cHVzaC92Mi9maXh0dXJlMQEBAQEBAQEBAQEBAQEBAQFBAAAAAAAAAAQgnDF7Y3k13T2hxU9jSV37Mfl9KT3whXEDIFlcmqy4P93kxp/Begx0wgzGkmYvBJiSujekukfSxwzYqZmGOR+bAQAAAAEaAAAAAAAAAG1haWx0bzpmaXh0dXJlQGV4YW1wbGUuY29tAAABAAAAAAIAAAAAAADAqAAAPAAAAA==",
+		"AAAAACAAAAAAAAAAAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBOwAAAAAAAABodHRwczovL3VwZGF0ZXMucHVzaC5zZXJ2aWNlcy5tb3ppbGxhLmNvbS93cHVzaC92Mi9maXh0dXJlMQEBAQEBAQEBAQEBAQEBAQFBAAAAAAAAAAQgnDF7Y3k13T2hxU9jSV37Mfl9KT3whXEDIFlcmqy4P93kxp/Begx0wgzGkmYvBJiSujekukfSxwzYqZmGOR+bAQAAAAEaAAAAAAAAAG1haWx0bzpmaXh0dXJlQGV4YW1wbGUuY29tAAABAAAAAAIAAAAAAADAqAAAPAAAAAEAAAAAAAAABwAAAAAAAABmaXh0dXJlAHlyXw=="
+	];
+	#[test]
+	fn historical_layouts() {
+		let secret_key = crypto::SecretKey::from(p256::SecretKey::from_bytes([1; 32]).unwrap());
+		for (version, fixture) in FIXTURES.iter().enumerate() {
+			let storage = persist::MemoryStorage::default();
+			storage.set("self_peer", fixture).unwrap();
+			let data = Persist::<SelfPeerData, _>::open_existing(storage.clone(), "self_peer").unwrap().unwrap();
+			match data.secret_key {
+				StoredKey::Plain(ref key) => assert_eq!(key.to_bytes(), secret_key.to_bytes()),
+				StoredKey::Locked { .. } => panic!("v{} came back locked", version)
+			}
+			let info = data.info.as_ref().unwrap();
+			assert!(info.endpoint.ends_with("/fixture1"));
+			assert_eq!(info.auth, [1; 16]);
+			assert_eq!(info.encoding, if version < 4 { web_push::ContentEncoding::AesGcm } else { web_push::ContentEncoding::Aes128Gcm });
+			assert_eq!(data.subscriber.as_deref(), Some("mailto:fixture@example.com"));
+			assert!(data.succession.is_none() && data.certificate.is_none());
+			assert_eq!(data.padding, if version < 5 { web_push::Padding::default() } else { web_push::Padding::Multiple(512) });
+			assert_eq!(data.auth_lifetime, if version < 6 {
+				web_push::AuthLifetime::default()
+			} else {
+				web_push::AuthLifetime { lifetime: 12 * 60 * 60, clock_skew: 60 }
+			});
+			assert_eq!(data.issued.get("fixture"), if version < 7 { None } else { Some(&1_601_337_600) });
+			// It's been written back in the current layout.
+			assert!(storage.get("self_peer").unwrap().unwrap().starts_with(&format!("v{}.", SelfPeerData::MIGRATIONS.len())));
+		}
+	}
+}
@@ -1,4 +1,3 @@
-use p256;
 use std::{
 	convert::TryFrom,
 	io::{ Write, Read }
@@ -10,13 +9,16 @@ use flate2::{
 	read::DeflateDecoder
 };
 use anyhow::{ Context, anyhow };
+use sha2::{Digest, Sha256};
 use wasm_bindgen::prelude::*;
 
 use shared::*;
 
 use super::crypto;
 use super::peer::peer_tag;
-use super::web_push::{PushInfo, AuthToken};
+use super::web_push::{PushInfo, AuthToken, ContentEncoding, PushOptions, Urgency, AUTH_SLOT};
+use super::succession::Succession;
+use super::device::{DeviceCertificate, CERTIFICATE_LENGTH};
 
 #[wasm_bindgen]
 pub struct ParsedMessage {
@@ -24,6 +26,9 @@ pub struct ParsedMessage {
 	pub peer_id: String,
 	#[wasm_bindgen(skip)]
 	pub public_key: crypto::PublicKey,
+	// Set when the message was signed by one of the identity's certified devices rather than by the identity itself.
+	#[wasm_bindgen(skip)]
+	pub device_key: Option<crypto::PublicKey>,
 	#[wasm_bindgen(skip)]
 	pub message: SignalingFormat
 }
@@ -32,6 +37,16 @@ impl ParsedMessage {
 	pub fn peer_id(&self) -> String {
 		self.peer_id.clone()
 	}
+	pub fn device_id(&self) -> Option<String> {
+		self.device_key.as_ref().map(peer_tag)
+	}
+	// If this message announces a key rotation, the peer id that the sender was previously known by.
+	pub fn predecessor_id(&self) -> Option<String> {
+		match self.message {
+			SignalingFormat::Succession(ref succession, ..) => Some(peer_tag(&succession.old_key)),
+			_ => None
+		}
+	}
 }
 
 #[wasm_bindgen]
@@ -47,16 +62,35 @@ pub fn parse_message(message: &str) -> Result<ParsedMessage, JsValue> {
 
 	let (message, signature) = message.split_at(message.len() - 64);
 	let signature = crypto::RecoverableSignature::from_bytes(signature).to_js_error()?;
-	let public_key = signature.recover_from_slice(message).to_js_error()?;
-	let peer_id = peer_tag(&public_key);
+	let signer = signature.recover_from_slice(message).to_js_error()?;
 
 	let message = SignalingFormat::try_from(message).to_js_error()?;
 
-	Ok(ParsedMessage { peer_id, public_key, message })
+	// Messages from a certified device are attributed to the root identity that certified it.
+	let (public_key, device_key, message) = match message {
+		SignalingFormat::Certified(certificate, inner) => {
+			if certificate.device_key.compress() != signer.compress() {
+				return Err(anyhow!("Device certificate is for a different key than the one that signed the message")).to_js_error();
+			}
+			certificate.verify().to_js_error()?;
+			(certificate.root_key, Some(signer), *inner)
+		},
+		message => (signer, None, message)
+	};
+	let peer_id = peer_tag(&public_key);
+
+	if let SignalingFormat::Succession(ref succession, ..) = message {
+		if succession.new_key.compress() != public_key.compress() {
+			return Err(anyhow!("Succession wasn't sent by the new key")).to_js_error();
+		}
+		succession.verify().to_js_error()?;
+	}
+
+	Ok(ParsedMessage { peer_id, public_key, device_key, message })
 }
 
-type SDP = String;
-type ICE = String;
+type Sdp = String;
+type Ice = String;
 
 #[wasm_bindgen]
 pub struct SignalingMessage {
@@ -80,7 +114,7 @@ impl SignalingMessage {
 			inner: SignalingFormat::JustIce(Vec::new())
 		}
 	}
-	pub fn add_ice(&mut self, new_ice: ICE) -> bool {
+	pub fn add_ice(&mut self, new_ice: Ice) -> bool {
 		match self.inner {
 			SignalingFormat::JustIce(ref mut ices) |
 			SignalingFormat::SDPOffer(_, ref mut ices) |
@@ -91,7 +125,7 @@ impl SignalingMessage {
 			_ => false
 		}
 	}
-	pub fn set_sdp(&mut self, sdp_kind: &str, new_sdp: SDP) -> bool {
+	pub fn set_sdp(&mut self, sdp_kind: &str, new_sdp: Sdp) -> bool {
 		match self.inner {
 			SignalingFormat::JustIce(ref mut ices) |
 			SignalingFormat::SDPOffer(_, ref mut ices) |
@@ -107,29 +141,35 @@ impl SignalingMessage {
 		}
 	}
 }
-#[derive(Eq, PartialEq, Debug)]
+#[derive(Eq, PartialEq, Debug, Clone)]
 pub enum SignalingFormat {
 	Introduction(PushInfo, AuthToken),
-	SDPOffer(SDP, Vec<ICE>),
-	SDPAnswer(SDP, Vec<ICE>),
-	JustIce(Vec<ICE>),
-	JustAuth(u32, String, Vec<crypto::Signature>)
+	SDPOffer(Sdp, Vec<Ice>),
+	SDPAnswer(Sdp, Vec<Ice>),
+	JustIce(Vec<Ice>),
+	JustAuth(u32, String, Vec<crypto::Signature>),
+	Succession(Box<Succession>, PushInfo, AuthToken),
+	Certified(DeviceCertificate, Box<SignalingFormat>)
 }
 impl SignalingFormat {
 	pub fn info(&self) -> Option<PushInfo> {
 		match self {
-			SignalingFormat::Introduction(intro, ..) => Some(intro.clone()),
+			SignalingFormat::Introduction(intro, ..) |
+			SignalingFormat::Succession(_, intro, _) => Some(intro.clone()),
+			SignalingFormat::Certified(_, inner) => inner.info(),
 			_ => None
 		}
 	}
 	pub fn auths(&self) -> Vec<AuthToken> {
 		match self {
-			SignalingFormat::Introduction(_, token) => vec![token.clone()],
+			SignalingFormat::Introduction(_, token) |
+			SignalingFormat::Succession(_, _, token) => vec![token.clone()],
+			SignalingFormat::Certified(_, inner) => inner.auths(),
 			SignalingFormat::JustAuth(expiration, subscriber, signatures) => {
 				signatures.iter().enumerate().map(|(i, sig)| AuthToken {
-					expiration: expiration + 12 * 60 * i as u32,
+					expiration: expiration + AUTH_SLOT * i as u32,
 					subscriber: subscriber.clone(),
-					signature: sig.clone()
+					signature: *sig
 				}).collect()
 			},
 			_ => Vec::new()
@@ -139,18 +179,132 @@ impl SignalingFormat {
 		match self {
 			SignalingFormat::SDPOffer(sdp, ..) => Some(("offer", sdp.clone())),
 			SignalingFormat::SDPAnswer(sdp, ..) => Some(("answer", sdp.clone())),
+			SignalingFormat::Certified(_, inner) => inner.sdp(),
 			_ => None
 		}
 	}
-	pub fn ices(&self) -> Vec<ICE> {
+	pub fn ices(&self) -> Vec<Ice> {
 		match self {
 			SignalingFormat::SDPOffer(_, ices) |
 			SignalingFormat::SDPAnswer(_, ices) |
 			SignalingFormat::JustIce(ices) => ices.clone(),
+			SignalingFormat::Certified(_, inner) => inner.ices(),
 			_ => Vec::new()
 		}
 	}
 }
+// How long a message is worth delivering, how urgently, and what it replaces in the push service's queue.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct PushPolicy {
+	// Seconds
+	pub ttl: usize,
+	pub urgency: Urgency,
+	// Messages of the same kind in the same session replace each other.  None for kinds where every message counts.
+	pub topic: Option<&'static str>
+}
+impl PushPolicy {
+	pub fn options(&self, sender: &crypto::PublicKey, session: &str) -> PushOptions {
+		PushOptions {
+			ttl: self.ttl,
+			urgency: Some(self.urgency),
+			topic: self.topic.map(|kind| topic(sender, session, kind)),
+			..PushOptions::default()
+		}
+	}
+}
+// Topics are visible to the push service, so they're hashed rather than saying who's talking about what.
+fn topic(sender: &crypto::PublicKey, session: &str, kind: &str) -> String {
+	let hash = Sha256::new()
+		.chain("web3.0-test topic".as_bytes()).chain([0])
+		.chain(sender.compress().as_bytes())
+		.chain(session.as_bytes()).chain([0])
+		.chain(kind.as_bytes())
+		.finalize();
+	// 24 bytes is 32 base64 characters: the most a topic can hold.
+	base64::encode_config(&hash[..24], base64::URL_SAFE_NO_PAD)
+}
+impl SignalingFormat {
+	pub fn push_policy(&self) -> PushPolicy {
+		const MINUTE: usize = 60;
+		const HOUR: usize = 60 * MINUTE;
+		match self {
+			// Connection setup goes stale quickly, and someone is waiting on it.  A newer offer or answer supersedes an older one, but each ICE message only carries the candidates found since the one before, so they can't replace each other.
+			SignalingFormat::SDPOffer(..) |
+			SignalingFormat::SDPAnswer(..) => PushPolicy { ttl: 5 * MINUTE, urgency: Urgency::High, topic: Some("sdp") },
+			SignalingFormat::JustIce(..) => PushPolicy { ttl: 5 * MINUTE, urgency: Urgency::High, topic: None },
+			SignalingFormat::Introduction(..) => PushPolicy { ttl: 24 * HOUR, urgency: Urgency::Normal, topic: Some("intro") },
+			// Each refresh carries different slots, so a queued one mustn't be replaced by the next or the peer is left with a gap in its authorizations.
+			SignalingFormat::JustAuth(..) => PushPolicy { ttl: 24 * HOUR, urgency: Urgency::Low, topic: None },
+			// Peers that miss a succession lose track of us, so it gets the longest lifetime.
+			SignalingFormat::Succession(..) => PushPolicy { ttl: 28 * 24 * HOUR, urgency: Urgency::Normal, topic: Some("succession") },
+			SignalingFormat::Certified(_, inner) => inner.push_policy()
+		}
+	}
+}
+fn write_introduction(info: &PushInfo, auth: &AuthToken, ret: &mut Vec<u8>, compressor: &mut DeflateEncoder<Vec<u8>>) -> Result<(), anyhow::Error> {
+	ret.extend_from_slice(info.public_key.compress().as_bytes());
+	ret.extend_from_slice(&info.auth);
+	ret.extend_from_slice(auth.signature.as_ref().as_ref());
+	compressor.write_u32::<BigEndian>(auth.expiration).context("Compression Error")?;
+	compressor.write_all(info.endpoint.as_bytes()).context("Compression Error")?;
+	compressor.write_u8(0).context("Compression Error")?;
+	compressor.write_all(auth.subscriber.as_bytes()).context("Compression Error")?;
+	// Only non-legacy encodings are written so that older introductions keep the same layout.
+	if info.encoding != ContentEncoding::AesGcm {
+		compressor.write_u8(0).context("Compression Error")?;
+		compressor.write_all(info.encoding.name().as_bytes()).context("Compression Error")?;
+	}
+	Ok(())
+}
+fn decompress(buffer: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+	let mut decompressed = Vec::<u8>::new();
+	let mut decoder = DeflateDecoder::new(buffer);
+	decoder.read_to_end(&mut decompressed).context("Decompression Error")?;
+	Ok(decompressed)
+}
+fn read_introduction(buffer: &[u8]) -> Result<(PushInfo, AuthToken), anyhow::Error> {
+	if buffer.len() < 112 {
+		return Err(anyhow!("Message too short - uncompressed data"));
+	}
+	let (public_key, buffer) = buffer.split_at(33);
+	let public_key = crypto::PublicKey::from(p256::EncodedPoint::from_bytes(public_key).map_err(|_| anyhow!("Public key invalid"))?);
+	let (auth, buffer) = buffer.split_at(16);
+	let auth = {
+		let mut temp = [0; 16];
+		temp.copy_from_slice(auth);
+		temp
+	};
+	let (signature, buffer) = buffer.split_at(64);
+	let signature = p256::ecdsa::Signature::try_from(signature).map_err(|_| anyhow!("Signature was malformed"))?.into();
+	let decompressed = decompress(buffer)?;
+
+	if decompressed.len() < 5 {
+		return Err(anyhow!("Message too short - compressed data"));
+	}
+	let (expiration, decompressed) = decompressed.split_at(4);
+	let expiration = BigEndian::read_u32(expiration);
+	let null_pos = decompressed.iter().position(|b| *b == 0).ok_or(anyhow!("Missing null byte between endpoint and subscriber"))?;
+	let (endpoint, rest) = decompressed.split_at(null_pos);
+	let endpoint = String::from_utf8(endpoint.to_vec()).context("Endpoint not UTF-8 formatted")?;
+	let rest = &rest[1..];
+	let (subscriber, encoding) = match rest.iter().position(|b| *b == 0) {
+		Some(null_pos) => {
+			let name = std::str::from_utf8(&rest[null_pos + 1..]).context("Encoding not UTF-8 formatted")?;
+			(&rest[..null_pos], ContentEncoding::from_name(name).ok_or(anyhow!("Unknown content encoding"))?)
+		},
+		None => (rest, ContentEncoding::AesGcm)
+	};
+	let subscriber = String::from_utf8(subscriber.to_vec()).context("Subscriber not UTF-8 formatted")?;
+
+	Ok((
+		PushInfo {
+			public_key, auth, endpoint, encoding
+		},
+		AuthToken {
+			signature, expiration, subscriber
+		}
+	))
+}
 impl TryFrom<&SignalingFormat> for Vec<u8> {
 	type Error = anyhow::Error;
 	fn try_from(msg: &SignalingFormat) -> Result<Self, Self::Error> {
@@ -159,13 +313,7 @@ impl TryFrom<&SignalingFormat> for Vec<u8> {
 		match msg {
 			SignalingFormat::Introduction(info, auth) => {
 				ret.push(1);
-				ret.extend_from_slice(info.public_key.compress().as_bytes());
-				ret.extend_from_slice(&info.auth);
-				ret.extend_from_slice(auth.signature.as_ref().as_ref());
-				compressor.write_u32::<BigEndian>(auth.expiration).context("Compression Error")?;
-				compressor.write_all(info.endpoint.as_bytes()).context("Compression Error")?;
-				compressor.write_u8(0).context("Compression Error")?;
-				compressor.write_all(auth.subscriber.as_bytes()).context("Compression Error")?;
+				write_introduction(info, auth, &mut ret, &mut compressor)?;
 			},
 			SignalingFormat::SDPOffer(sdp, ices) | SignalingFormat::SDPAnswer(sdp, ices) => {
 				if let SignalingFormat::SDPOffer(..) = msg {
@@ -187,9 +335,33 @@ impl TryFrom<&SignalingFormat> for Vec<u8> {
 					compressor.write_u8(0).context("Compression Error")?;
 				}
 			},
-			SignalingFormat::JustAuth(_exp, _sub, _sigs) => {
+			SignalingFormat::JustAuth(expiration, subscriber, signatures) => {
 				ret.push(5);
-				unimplemented!("Having two variable length things is hard: subscriber string length and variable number of signatures.")
+				// Counting the signatures up front leaves the subscriber as the only variable length field, and it goes last.
+				if signatures.is_empty() || signatures.len() > u8::MAX as usize {
+					return Err(anyhow!("Authorization messages carry between 1 and 255 signatures"));
+				}
+				ret.push(signatures.len() as u8);
+				for signature in signatures {
+					ret.extend_from_slice(signature.as_ref().as_ref());
+				}
+				compressor.write_u32::<BigEndian>(*expiration).context("Compression Error")?;
+				compressor.write_all(subscriber.as_bytes()).context("Compression Error")?;
+			},
+			SignalingFormat::Succession(succession, info, auth) => {
+				ret.push(6);
+				ret.extend_from_slice(succession.old_key.compress().as_bytes());
+				ret.extend_from_slice(succession.new_key.compress().as_bytes());
+				ret.extend_from_slice(succession.old_signature.as_ref().as_ref());
+				ret.extend_from_slice(succession.new_signature.as_ref().as_ref());
+				write_introduction(info, auth, &mut ret, &mut compressor)?;
+			},
+			SignalingFormat::Certified(certificate, inner) => {
+				// The wrapped message has its own compression, so there's nothing to add after it.
+				ret.push(7);
+				ret.extend_from_slice(&certificate.to_bytes());
+				ret.extend_from_slice(&Vec::try_from(inner.as_ref())?);
+				return Ok(ret);
 			}
 		}
 		let compressed_data = compressor.finish().context("Compression Error")?;
@@ -203,54 +375,17 @@ impl TryFrom<&[u8]> for SignalingFormat {
 	type Error = anyhow::Error;
 	fn try_from(buffer: &[u8]) -> Result<Self, Self::Error> {
 		let (header, buffer) = buffer.split_first().ok_or(anyhow!("Message too short - no header"))?;
-		fn decompress(buffer: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
-			let mut decompressed = Vec::<u8>::new();
-			let mut decoder = DeflateDecoder::new(buffer);
-			decoder.read_to_end(&mut decompressed).context("Decompression Error")?;
-			Ok(decompressed)
-		}
 		match header {
 			1 => {
-				if buffer.len() < 112 {
-					return Err(anyhow!("Message too short - uncompressed data"));
-				}
-				let (public_key, buffer) = buffer.split_at(33);
-				let public_key = crypto::PublicKey::from(p256::EncodedPoint::from_bytes(public_key).map_err(|_| anyhow!("Public key invalid"))?);
-				let (auth, buffer) = buffer.split_at(16);
-				let auth = {
-					let mut temp = [0; 16];
-					temp.copy_from_slice(auth);
-					temp
-				};
-				let (signature, buffer) = buffer.split_at(64);
-				let signature = p256::ecdsa::Signature::try_from(signature).map_err(|_| anyhow!("Signature was malformed"))?.into();
-				let decompressed = decompress(buffer)?;
-
-				if decompressed.len() < 5 {
-					return Err(anyhow!("Message too short - compressed data"));
-				}
-				let (expiration, decompressed) = decompressed.split_at(4);
-				let expiration = BigEndian::read_u32(expiration);
-				let null_pos = decompressed.iter().position(|b| *b == 0).ok_or(anyhow!("Missing null byte between endpoint and subscriber"))?;
-				let (endpoint, subscriber) = decompressed.split_at(null_pos);
-				let endpoint = String::from_utf8(endpoint.to_vec()).context("Endpoint not UTF-8 formatted")?;
-				let subscriber = String::from_utf8(subscriber[1..].to_vec()).context("Subscriber not UTF-8 formatted")?;
-
-				Ok(SignalingFormat::Introduction(
-					PushInfo {
-						public_key, auth, endpoint
-					},
-					AuthToken {
-						signature, expiration, subscriber
-					}
-				))
+				let (info, auth) = read_introduction(buffer)?;
+				Ok(SignalingFormat::Introduction(info, auth))
 			},
 			2 | 3 => {
 				let decompressed = decompress(buffer)?;
 				let mut strings = decompressed.split(|x| *x == 0).map(|bytes| String::from_utf8(bytes.to_vec()));
 				let sdp = strings.next().ok_or(anyhow!("No SDP - too few strings"))?.map_err(|_| anyhow!("SDP not UTF-8 formatted"))?;
 				let ices = strings.filter_map(|x| {
-					x.ok().filter(|s| s.len() > 0)
+					x.ok().filter(|s| !s.is_empty())
 				}).collect();
 				if *header == 2 {
 					Ok(SignalingFormat::SDPOffer(sdp, ices))
@@ -261,12 +396,58 @@ impl TryFrom<&[u8]> for SignalingFormat {
 			4 => {
 				let decompressed = decompress(buffer)?;
 				let ices = decompressed.split(|x| *x == 0).filter_map(|bytes| {
-					String::from_utf8(bytes.to_vec()).ok().filter(|s|s.len() > 0)
+					String::from_utf8(bytes.to_vec()).ok().filter(|s| !s.is_empty())
 				}).collect();
 				Ok(SignalingFormat::JustIce(ices))
 			},
 			5 => {
-				todo!("Implemented JustAuth")
+				let (count, buffer) = buffer.split_first().ok_or(anyhow!("Message too short - authorization count"))?;
+				let count = *count as usize;
+				if count == 0 || buffer.len() < count * 64 {
+					return Err(anyhow!("Message too short - authorization signatures"));
+				}
+				let (signatures, buffer) = buffer.split_at(count * 64);
+				let signatures = signatures.chunks(64).map(|signature| {
+					Ok(p256::ecdsa::Signature::try_from(signature).map_err(|_| anyhow!("Signature was malformed"))?.into())
+				}).collect::<Result<Vec<crypto::Signature>, anyhow::Error>>()?;
+				let decompressed = decompress(buffer)?;
+				if decompressed.len() < 4 {
+					return Err(anyhow!("Message too short - compressed data"));
+				}
+				let (expiration, subscriber) = decompressed.split_at(4);
+				let subscriber = String::from_utf8(subscriber.to_vec()).context("Subscriber not UTF-8 formatted")?;
+				Ok(SignalingFormat::JustAuth(BigEndian::read_u32(expiration), subscriber, signatures))
+			},
+			6 => {
+				if buffer.len() < 33 + 33 + 64 + 64 {
+					return Err(anyhow!("Message too short - succession"));
+				}
+				let (old_key, buffer) = buffer.split_at(33);
+				let old_key = crypto::PublicKey::from_compressed(old_key).context("Old key invalid")?;
+				let (new_key, buffer) = buffer.split_at(33);
+				let new_key = crypto::PublicKey::from_compressed(new_key).context("New key invalid")?;
+				let (old_signature, buffer) = buffer.split_at(64);
+				let old_signature = p256::ecdsa::Signature::try_from(old_signature).map_err(|_| anyhow!("Old key's signature was malformed"))?.into();
+				let (new_signature, buffer) = buffer.split_at(64);
+				let new_signature = p256::ecdsa::Signature::try_from(new_signature).map_err(|_| anyhow!("New key's signature was malformed"))?.into();
+				let (info, auth) = read_introduction(buffer)?;
+				Ok(SignalingFormat::Succession(
+					Box::new(Succession { old_key, new_key, old_signature, new_signature }),
+					info,
+					auth
+				))
+			},
+			7 => {
+				if buffer.len() < CERTIFICATE_LENGTH {
+					return Err(anyhow!("Message too short - device certificate"));
+				}
+				let (certificate, buffer) = buffer.split_at(CERTIFICATE_LENGTH);
+				let certificate = DeviceCertificate::from_bytes(certificate)?;
+				let inner = SignalingFormat::try_from(buffer)?;
+				if let SignalingFormat::Certified(..) = inner {
+					return Err(anyhow!("Device certificates can't be nested"));
+				}
+				Ok(SignalingFormat::Certified(certificate, Box::new(inner)))
 			},
 			_ => Err(anyhow!("Unrecognized header."))
 		}
@@ -282,7 +463,7 @@ mod test_encoding {
 	fn intro_to_from() {
 		let sk = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
 		let signature = crypto::Signature::from(
-			p256::ecdsa::SigningKey::from(sk.as_ref()).sign_with_rng(rand::thread_rng(), "Hello World!".as_bytes())
+			sk.signing_key().sign_with_rng(rand::thread_rng(), "Hello World!".as_bytes())
 		);
 
 		let intro = SignalingFormat::Introduction(
@@ -290,6 +471,7 @@ mod test_encoding {
 				public_key: p256::EncodedPoint::from_secret_key(&sk, true).into(),
 				auth: [1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16],
 				endpoint: String::from("https://fcm.googleapis.com/fcm/send/c7KtKcy5AHA:APA91bG0yt50A_m7lsb_EPs3NSdwqSE7S2y8D-Yp38baVaIYdRE-Sw9EYNzOOgb95XUVSlyFwYVgybc0fwZapSeyB0TBWKAN-uinEuQlpl58T6jWRDr3IymyRxWdwSkIlHDbSoYpXD9w"),
+				encoding: ContentEncoding::AesGcm
 			},
 			AuthToken {
 				signature,
@@ -303,6 +485,91 @@ mod test_encoding {
 		assert_eq!(intro, recovered_intro);
 	}
 	#[test]
+	fn aes128gcm_intro_to_from() {
+		let sk = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let signature = crypto::Signature::from(
+			sk.signing_key().sign_with_rng(rand::thread_rng(), "Hello World!".as_bytes())
+		);
+
+		let intro = SignalingFormat::Introduction(
+			PushInfo {
+				public_key: p256::EncodedPoint::from_secret_key(&sk, true).into(),
+				auth: [1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16],
+				endpoint: String::from("https://updates.push.services.mozilla.com/wpush/v2/gAAAAABfcDCt"),
+				encoding: ContentEncoding::Aes128Gcm
+			},
+			AuthToken {
+				signature,
+				expiration: 1601336440,
+				subscriber: String::from("mailto:no-reply@example.com")
+			}
+		);
+
+		let bytes = Vec::<u8>::try_from(&intro).expect("Failed to serialize introduction");
+		let recovered_intro = SignalingFormat::try_from(&bytes[..]).expect("Failed to recover encoded introduction.");
+		assert_eq!(intro, recovered_intro);
+	}
+	#[test]
+	fn succession_to_from() {
+		let old = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let new = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let signature = crypto::Signature::from(
+			new.signing_key().sign_with_rng(rand::thread_rng(), "Hello World!".as_bytes())
+		);
+
+		let succession = SignalingFormat::Succession(
+			Box::new(Succession::create(&old, &new, rand::thread_rng())),
+			PushInfo {
+				public_key: p256::EncodedPoint::from_secret_key(&new, true).into(),
+				auth: [1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16],
+				endpoint: String::from("https://updates.push.services.mozilla.com/wpush/v2/gAAAAABfcDCt"),
+				encoding: ContentEncoding::AesGcm
+			},
+			AuthToken {
+				signature,
+				expiration: 1601336440,
+				subscriber: String::from("mailto:no-reply@example.com")
+			}
+		);
+
+		let bytes = Vec::<u8>::try_from(&succession).expect("Failed to serialize succession");
+		let recovered_succession = SignalingFormat::try_from(&bytes[..]).expect("Failed to recover encoded succession.");
+		assert_eq!(succession, recovered_succession);
+	}
+	#[test]
+	fn auth_to_from() {
+		let sk = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let signatures = (0..4).map(|i| crypto::Signature::from(
+			sk.signing_key().sign_with_rng(rand::thread_rng(), &[i])
+		)).collect();
+
+		let auth = SignalingFormat::JustAuth(1601337600, String::from("mailto:no-reply@example.com"), signatures);
+		let bytes = Vec::<u8>::try_from(&auth).expect("Failed to serialize authorizations");
+		let recovered_auth = SignalingFormat::try_from(&bytes[..]).expect("Failed to recover encoded authorizations.");
+		assert_eq!(auth, recovered_auth);
+
+		let tokens = recovered_auth.auths();
+		assert_eq!(tokens.len(), 4);
+		assert_eq!(tokens[3].expiration, 1601337600 + 3 * AUTH_SLOT);
+		assert!(Vec::<u8>::try_from(&SignalingFormat::JustAuth(0, String::new(), Vec::new())).is_err());
+	}
+	#[test]
+	fn certified_to_from() {
+		let root = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let device = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+
+		let certified = SignalingFormat::Certified(
+			DeviceCertificate::issue(&root, device.public_key(), rand::thread_rng()),
+			Box::new(SignalingFormat::JustIce(vec![
+				String::from(r#"{"candidate":"candidate:3031090232 1 udp 2113937151 443211da-69fc-4300-a6f3-d8d8e5ded476.local 53358 typ host generation 0 ufrag ohUt network-cost 999","sdpMid":"0","sdpMLineIndex":0}"#)
+			]))
+		);
+
+		let bytes = Vec::<u8>::try_from(&certified).expect("Failed to serialize certified message");
+		let recovered_certified = SignalingFormat::try_from(&bytes[..]).expect("Failed to recover encoded certified message.");
+		assert_eq!(certified, recovered_certified);
+	}
+	#[test]
 	fn offer_to_from() {
 		let offer = SignalingFormat::SDPOffer(
 			String::from(r#"{"type":"offer","sdp":"v=0\r\no=- 98574467085887535 2 IN IP4 127.0.0.1\r\ns=-\r\nt=0 0\r\na=group:BUNDLE 0\r\na=msid-semantic: WMS\r\nm=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\nc=IN IP4 0.0.0.0\r\na=ice-ufrag:ohUt\r\na=ice-pwd:ccZoAfoV2tRCn1vTkY7Q0hSc\r\na=ice-options:trickle\r\na=fingerprint:sha-256 69:6C:35:5E:7F:3F:C1:0C:BE:68:51:C5:5A:D8:2A:94:EC:40:C0:D4:AB:27:45:08:C9:7B:E2:83:8A:0D:AE:40\r\na=setup:actpass\r\na=mid:0\r\na=sctp-port:5000\r\na=max-message-size:262144\r\n"}"#), 
@@ -343,4 +610,33 @@ mod test_encoding {
 		let recovered_just_ice = SignalingFormat::try_from(&bytes[..]).expect("Offer deserialization failed.");
 		assert_eq!(just_ice, recovered_just_ice);
 	}
+	#[test]
+	fn replaceable_kinds() {
+		let sender = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng())).public_key();
+		let offer = SignalingFormat::SDPOffer(String::from("offer"), Vec::new()).push_policy().options(&sender, "session");
+		let answer = SignalingFormat::SDPAnswer(String::from("answer"), Vec::new()).push_policy().options(&sender, "session");
+		let other_session = SignalingFormat::SDPOffer(String::from("offer"), Vec::new()).push_policy().options(&sender, "other session");
+
+		assert_eq!(offer.topic, answer.topic);
+		assert_ne!(offer.topic, other_session.topic);
+		assert_eq!(offer.urgency, Some(Urgency::High));
+		assert_eq!(offer.topic.unwrap().len(), 32);
+
+		let ice = SignalingFormat::JustIce(Vec::new()).push_policy().options(&sender, "session");
+		assert_eq!(ice.topic, None);
+
+		let auth = SignalingFormat::JustAuth(0, String::new(), Vec::new()).push_policy().options(&sender, "session");
+		assert_eq!(auth.topic, None);
+	}
+	#[test]
+	fn certified_uses_inner() {
+		let root = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let device = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let inner = SignalingFormat::JustIce(Vec::new());
+		let certified = SignalingFormat::Certified(
+			DeviceCertificate::issue(&root, device.public_key(), rand::thread_rng()),
+			Box::new(inner.clone())
+		);
+		assert_eq!(certified.push_policy(), inner.push_policy());
+	}
 }
@@ -0,0 +1,71 @@
+use serde::{ Serialize, Deserialize };
+use anyhow::anyhow;
+use p256::ecdsa::signature::{RandomizedSigner, Verifier};
+use rand::{CryptoRng, RngCore};
+
+use super::crypto;
+
+// A statement, signed by both keys, that the identity previously known by old_key is now known by new_key.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Succession {
+	pub old_key: crypto::PublicKey,
+	pub new_key: crypto::PublicKey,
+	pub old_signature: crypto::Signature,
+	pub new_signature: crypto::Signature
+}
+impl Succession {
+	fn statement(old_key: &crypto::PublicKey, new_key: &crypto::PublicKey) -> Vec<u8> {
+		let mut statement = Vec::new();
+		statement.extend_from_slice("web3.0-test succession".as_bytes());
+		statement.push(0);
+		statement.extend_from_slice(old_key.compress().as_bytes());
+		statement.extend_from_slice(new_key.compress().as_bytes());
+		statement
+	}
+	pub fn create(old: &crypto::SecretKey, new: &crypto::SecretKey, mut rng: impl CryptoRng + RngCore) -> Self {
+		let old_key = old.public_key();
+		let new_key = new.public_key();
+		let statement = Self::statement(&old_key, &new_key);
+		let old_signature = old.signing_key().sign_with_rng(&mut rng, &statement).into();
+		let new_signature = new.signing_key().sign_with_rng(&mut rng, &statement).into();
+		Self { old_key, new_key, old_signature, new_signature }
+	}
+	pub fn verify(&self) -> Result<(), anyhow::Error> {
+		let statement = Self::statement(&self.old_key, &self.new_key);
+		self.old_key.verifying_key()?.verify(&statement, &self.old_signature)
+			.map_err(|_| anyhow!("Succession wasn't signed by the old key"))?;
+		self.new_key.verifying_key()?.verify(&statement, &self.new_signature)
+			.map_err(|_| anyhow!("Succession wasn't signed by the new key"))?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn create_verify() {
+		let old = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let new = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let succession = Succession::create(&old, &new, rand::thread_rng());
+		assert!(succession.verify().is_ok());
+	}
+	#[test]
+	fn swapped_keys() {
+		let old = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let new = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let mut succession = Succession::create(&old, &new, rand::thread_rng());
+		std::mem::swap(&mut succession.old_key, &mut succession.new_key);
+		assert!(succession.verify().is_err());
+	}
+	#[test]
+	fn foreign_new_key() {
+		let old = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let new = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let mut succession = Succession::create(&old, &new, rand::thread_rng());
+		let other = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		succession.new_key = other.public_key();
+		assert!(succession.verify().is_err());
+	}
+}
@@ -1,27 +1,115 @@
 use anyhow::{ Context, anyhow };
-use p256::ecdsa::{VerifyingKey, signature::Verifier};
 use url::Url;
 use serde::{Serialize, Deserialize};
+use std::io::Write;
 use web_sys;
-use byteorder::{WriteBytesExt, BigEndian, LittleEndian};
+use byteorder::{ByteOrder, WriteBytesExt, BigEndian};
 use js_sys::Uint8Array;
 use p256::{
 	EncodedPoint,
-	ecdh::EphemeralSecret
+	ecdh::SharedSecret,
+	elliptic_curve::ecdh::diffie_hellman
 };
 use hkdf::Hkdf;
 use aes_gcm::Aes128Gcm;
-use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::aead::{Aead, NewAead, generic_array::GenericArray};
 use web_sys::{RequestInit, RequestCache, RequestMode, Headers};
+use zeroize::Zeroizing;
+use rand::{Rng, RngCore};
 
 use super::crypto;
+use super::jwt;
 use super::rand::{get_rng, get_salt};
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
 pub struct PushInfo {
 	pub endpoint: String,
 	pub auth: [u8; 16],
-	pub public_key: crypto::PublicKey
+	pub public_key: crypto::PublicKey,
+	// Which content encoding the subscriber's browser accepts.
+	pub encoding: ContentEncoding
+}
+impl PushInfo {
+	// Authorizations are tied to the endpoint and pushes are encrypted to the keys, so a change to either is a new subscription.  The encoding only changes how we encrypt.
+	pub fn same_subscription(&self, other: &PushInfo) -> bool {
+		self.endpoint == other.endpoint && self.auth == other.auth && self.public_key == other.public_key
+	}
+}
+
+// PushInfo as it was stored before the content encoding was recorded.  Only aesgcm was supported back then.
+#[derive(Serialize, Deserialize)]
+pub struct LegacyPushInfo {
+	endpoint: String,
+	auth: [u8; 16],
+	public_key: crypto::PublicKey
+}
+impl From<LegacyPushInfo> for PushInfo {
+	fn from(legacy: LegacyPushInfo) -> Self {
+		Self {
+			endpoint: legacy.endpoint,
+			auth: legacy.auth,
+			public_key: legacy.public_key,
+			encoding: ContentEncoding::AesGcm
+		}
+	}
+}
+
+const HOUR: u32 = 60 * 60;
+// Push services reject VAPID tokens that expire more than 24 hours out (RFC 8292 section 2).
+pub const MAX_AUTH_LIFETIME: u32 = 24 * HOUR;
+// Expirations are locked to 12 hour increments from the epoch so that a peer's tokens line up and can be consolidated.
+pub const AUTH_SLOT: u32 = 12 * HOUR;
+
+// How long push authorizations last.  The same window is used when minting a token and when checking one before a push: a token is usable while its expiration is more than clock_skew away and no more than lifetime - clock_skew away.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct AuthLifetime {
+	// Seconds.  Anything over MAX_AUTH_LIFETIME is treated as MAX_AUTH_LIFETIME.
+	pub lifetime: u32,
+	// Seconds that our clock, the peer's, and the push service's might disagree by.
+	pub clock_skew: u32
+}
+impl Default for AuthLifetime {
+	fn default() -> Self {
+		Self {
+			lifetime: MAX_AUTH_LIFETIME,
+			clock_skew: 5 * 60
+		}
+	}
+}
+impl AuthLifetime {
+	fn latest(&self, now: u32) -> u32 {
+		now.saturating_add(self.lifetime.min(MAX_AUTH_LIFETIME)).saturating_sub(self.clock_skew)
+	}
+	fn earliest(&self, now: u32) -> u32 {
+		now.saturating_add(self.clock_skew)
+	}
+	// The expiration for a token that becomes usable `slot` slots from now.  Slot 0 is usable immediately and expires on the latest slot boundary the window allows.
+	pub fn expiration(&self, now: u32, slot: u32) -> Result<u32, anyhow::Error> {
+		let latest = self.latest(now);
+		let aligned = latest - latest % AUTH_SLOT;
+		// Lifetimes shorter than a slot can't be aligned.
+		let first = if aligned > self.earliest(now) { aligned } else { latest };
+		if first <= self.earliest(now) {
+			return Err(anyhow!("Auth lifetime is too short for the allowed clock skew"));
+		}
+		first.checked_add(slot.saturating_mul(AUTH_SLOT)).ok_or(anyhow!("Auth expiration overflowed"))
+	}
+	// The span of time (start inclusive, end exclusive) over which a token with this expiration passes check.
+	pub fn usable_from(&self, expiration: u32) -> u32 {
+		expiration.saturating_add(self.clock_skew).saturating_sub(self.lifetime.min(MAX_AUTH_LIFETIME))
+	}
+	pub fn usable_until(&self, expiration: u32) -> u32 {
+		expiration.saturating_sub(self.clock_skew)
+	}
+	pub fn check(&self, expiration: u32, now: u32) -> Result<(), anyhow::Error> {
+		if expiration <= self.earliest(now) {
+			Err(anyhow!("Auth has expired"))
+		} else if expiration > self.latest(now) {
+			Err(anyhow!("Auth isn't usable yet - it expires too far in the future"))
+		} else {
+			Ok(())
+		}
+	}
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
@@ -31,36 +119,56 @@ pub struct AuthToken {
 	pub signature: crypto::Signature
 }
 impl AuthToken {
-	pub fn fill_and_check(&self, info: &PushInfo, expected_signer: &crypto::PublicKey) -> Result<String, anyhow::Error> {
-		// verify expiration:
-		let now = (js_sys::Date::now() / 1000.0) as usize;
-		let exp = self.expiration as usize;
-		if exp < now || exp > now + 24 * 60 {
-			return Err(anyhow!("Not within the auth's valid window"));
-		}
-
-		let audience = Url::parse(&info.endpoint).context("Endpoint URL parsing failed.")?.origin().unicode_serialization();
-		let body = format!(r#"{{"aud":"{}","exp":{},"sub":"{}"}}"#, audience, self.expiration, self.subscriber);
-		let body = base64::encode_config(body.as_bytes(), base64::URL_SAFE_NO_PAD);
-
-		let buffer = format!("eyJ0eXAiOiJKV1QiLCJhbGciOiJFUzI1NiJ9.{}", body);
+	pub fn claims(&self, info: &PushInfo) -> Result<jwt::Claims, anyhow::Error> {
+		Ok(jwt::Claims {
+			aud: jwt::audience(&info.endpoint)?,
+			exp: self.expiration,
+			sub: self.subscriber.clone()
+		})
+	}
+	pub fn fill_and_check(&self, info: &PushInfo, expected_signer: &crypto::PublicKey, lifetime: &AuthLifetime, now: u32) -> Result<String, anyhow::Error> {
+		lifetime.check(self.expiration, now)?;
 
-		let verifier = VerifyingKey::from_encoded_point(
-			expected_signer
-		).map_err(|_| anyhow!("Expected Signer couldn't be turned into a verify key."))?;
-		if verifier.verify(buffer.as_bytes(), &self.signature).is_ok() {
-			Ok(buffer + "." + &base64::encode_config(self.signature.as_ref(), base64::URL_SAFE_NO_PAD))
-		} else {
-			Err(anyhow!("Auth token is invalid for the expected signer."))
+		let claims = self.claims(info)?;
+		claims.verify(&self.signature, expected_signer)
+			.map_err(|_| anyhow!("Auth token is invalid for the expected signer."))?;
+		claims.token(&self.signature)
+	}
+}
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub enum ContentEncoding {
+	// The draft encoding, which goes with the Crypto-Key and Encryption headers.
+	AesGcm,
+	// RFC 8291 / RFC 8188
+	Aes128Gcm
+}
+impl ContentEncoding {
+	pub fn name(&self) -> &'static str {
+		match self {
+			ContentEncoding::AesGcm => "aesgcm",
+			ContentEncoding::Aes128Gcm => "aes128gcm"
+		}
+	}
+	pub fn from_name(name: &str) -> Option<Self> {
+		match name {
+			"aesgcm" => Some(ContentEncoding::AesGcm),
+			"aes128gcm" => Some(ContentEncoding::Aes128Gcm),
+			_ => None
 		}
 	}
 }
-fn make_info(content_type: &str, client_public: &crypto::PublicKey, server_public: &EphemeralSecret) -> Result<Vec<u8>, anyhow::Error> {
-	let client_decompressed: EncodedPoint = Option::from(client_public.decompress()).ok_or(anyhow!("Failed to decompress the client public key"))?;
-	let cp_encoded = client_decompressed.as_bytes();
-	let sp_encoded: EncodedPoint = Option::from(server_public.public_key().decompress()).ok_or(anyhow!("Failed to decompress the server_public key"))?;
-	let sp_encoded = sp_encoded.as_bytes();
-	// println!("{:?} {:?}", cp_encoded, sp_encoded);
+
+fn decompress_key(key: &crypto::PublicKey) -> Result<EncodedPoint, anyhow::Error> {
+	key.decompress().ok_or(anyhow!("Failed to decompress the client public key"))
+}
+fn ecdh(secret: &p256::SecretKey, public: &EncodedPoint) -> Result<SharedSecret, anyhow::Error> {
+	let public = p256::PublicKey::from_sec1_bytes(public.as_bytes()).map_err(|_| anyhow!("Public key isn't on the curve"))?;
+	Ok(diffie_hellman(secret.secret_scalar(), public.as_affine()))
+}
+
+fn make_info(content_type: &str, client_public: &EncodedPoint, server_public: &EncodedPoint) -> Result<Vec<u8>, anyhow::Error> {
+	let cp_encoded = client_public.as_bytes();
+	let sp_encoded = server_public.as_bytes();
 	let mut info = Vec::new();
 	info.extend_from_slice("Content-Encoding: ".as_bytes());
 	info.extend_from_slice(content_type.as_bytes());
@@ -74,90 +182,802 @@ fn make_info(content_type: &str, client_public: &crypto::PublicKey, server_publi
 	Ok(info)
 }
 
-pub fn push(recipient: &PushInfo, application_server_pk: &crypto::PublicKey, auth: &AuthToken, message: &[u8], pad_mod: Option<usize>, ttl: usize) -> Result<(String, web_sys::RequestInit), anyhow::Error> {
-	// Padding:
-	let pad_len = pad_mod.map_or(0, |pad_mod| {
-		let remainder = message.len() % pad_mod;
-		if remainder != 0 {
-			pad_mod - remainder
-		} else {
-			0
-		}
-	});
+type ContentKeys = (Zeroizing<[u8; 16]>, Zeroizing<[u8; 12]>);
+
+// Both public keys are uncompressed.  The shared secret is the same on both ends.
+fn aesgcm_keys(auth: &[u8; 16], shared_secret: &SharedSecret, client_public: &EncodedPoint, server_public: &EncodedPoint, salt: &[u8; 16]) -> Result<ContentKeys, anyhow::Error> {
+	// Pseudo Random Key:
+	let mut prk = Zeroizing::new([0; 32]);
+	Hkdf::<sha2::Sha256>::new(Some(auth), shared_secret.as_bytes())
+		.expand("Content-Encoding: auth\0".as_bytes(), prk.as_mut())
+		.map_err(|_| anyhow!("Failed to expand shared secret into PRK"))?;
+	let hkdf = Hkdf::<sha2::Sha256>::new(Some(salt), prk.as_ref());
+
+	// Encryption Key:
+	let mut encryption_key = Zeroizing::new([0; 16]);
+	hkdf.expand(&make_info("aesgcm", client_public, server_public)?, encryption_key.as_mut())
+		.map_err(|_| anyhow!("Failed to expand PRK into encryption key"))?;
+
+	// Nonce:
+	let mut nonce = Zeroizing::new([0; 12]);
+	hkdf.expand(&make_info("nonce", client_public, server_public)?, nonce.as_mut())
+		.map_err(|_| anyhow!("Failed to expand PRK into nonce"))?;
+
+	Ok((encryption_key, nonce))
+}
+fn aes128gcm_keys(auth: &[u8; 16], shared_secret: &SharedSecret, client_public: &EncodedPoint, server_public: &EncodedPoint, salt: &[u8; 16]) -> Result<ContentKeys, anyhow::Error> {
+	// Input Keying Material:
+	let mut key_info = Vec::new();
+	key_info.extend_from_slice("WebPush: info\0".as_bytes());
+	key_info.extend_from_slice(client_public.as_bytes());
+	key_info.extend_from_slice(server_public.as_bytes());
+	let mut ikm = Zeroizing::new([0; 32]);
+	Hkdf::<sha2::Sha256>::new(Some(auth), shared_secret.as_bytes())
+		.expand(&key_info, ikm.as_mut())
+		.map_err(|_| anyhow!("Failed to expand shared secret into IKM"))?;
+
+	// Content Encryption Key and Nonce:
+	let hkdf = Hkdf::<sha2::Sha256>::new(Some(salt), ikm.as_ref());
+	let mut encryption_key = Zeroizing::new([0; 16]);
+	hkdf.expand("Content-Encoding: aes128gcm\0".as_bytes(), encryption_key.as_mut())
+		.map_err(|_| anyhow!("Failed to expand IKM into encryption key"))?;
+	let mut nonce = Zeroizing::new([0; 12]);
+	hkdf.expand("Content-Encoding: nonce\0".as_bytes(), nonce.as_mut())
+		.map_err(|_| anyhow!("Failed to expand IKM into nonce"))?;
+
+	Ok((encryption_key, nonce))
+}
+
+fn encrypt_aesgcm(recipient: &PushInfo, server_secret: &p256::SecretKey, salt: &[u8; 16], message: &[u8], pad_len: usize) -> Result<Vec<u8>, anyhow::Error> {
+	let server_public = EncodedPoint::from_secret_key(server_secret, false);
+	let client_public = decompress_key(&recipient.public_key)?;
+	let shared_secret = ecdh(server_secret, &client_public)?;
+	let (encryption_key, nonce) = aesgcm_keys(&recipient.auth, &shared_secret, &client_public, &server_public, salt)?;
+
+	// Padding: a two byte length followed by that many zeros.  The length is big-endian like every other integer on the wire (draft-ietf-httpbis-encryption-encoding-03 section 2), so browsers would throw away anything padded past 255 bytes if it weren't.
 	let mut data = Vec::with_capacity(2 + pad_len + message.len());
-	data.write_u16::<LittleEndian>(pad_len as u16).context("Failed to set padding")?;
-	for _ in 0..pad_len {
-		data.push(0);
-	}
+	data.write_u16::<BigEndian>(pad_len as u16).context("Failed to set padding")?;
+	data.resize(2 + pad_len, 0);
+	data.extend_from_slice(message);
+
+	// Encrypt the message, borrowing the key so that no copy of it outlives the Zeroizing:
+	Aes128Gcm::new(GenericArray::from_slice(encryption_key.as_ref()))
+		.encrypt(GenericArray::from_slice(nonce.as_ref()), data.as_ref())
+		.map_err(|_| anyhow!("Encryption failed"))
+}
+
+// We always send a single record, so the record size just needs to be big enough to hold the whole message.
+const RECORD_SIZE: u32 = 4096;
+
+fn encrypt_aes128gcm(recipient: &PushInfo, server_secret: &p256::SecretKey, salt: &[u8; 16], message: &[u8], pad_len: usize) -> Result<Vec<u8>, anyhow::Error> {
+	let server_public = EncodedPoint::from_secret_key(server_secret, false);
+	let client_public = decompress_key(&recipient.public_key)?;
+	let shared_secret = ecdh(server_secret, &client_public)?;
+	let (encryption_key, nonce) = aes128gcm_keys(&recipient.auth, &shared_secret, &client_public, &server_public, salt)?;
+
+	// Padding: a delimiter marking this as the last record, followed by zeros.
+	let mut data = Vec::with_capacity(message.len() + 1 + pad_len);
 	data.extend_from_slice(message);
+	data.push(2);
+	data.resize(message.len() + 1 + pad_len, 0);
+
+	let encrypted = Aes128Gcm::new(GenericArray::from_slice(encryption_key.as_ref()))
+		.encrypt(GenericArray::from_slice(nonce.as_ref()), data.as_ref())
+		.map_err(|_| anyhow!("Encryption failed"))?;
+
+	// Header: salt, record size, and the key id (our public key).
+	let mut body = Vec::with_capacity(16 + 4 + 1 + server_public.as_bytes().len() + encrypted.len());
+	body.extend_from_slice(salt);
+	body.write_u32::<BigEndian>(RECORD_SIZE).context("Failed to write record size")?;
+	body.push(server_public.as_bytes().len() as u8);
+	body.extend_from_slice(server_public.as_bytes());
+	body.extend_from_slice(&encrypted);
+	Ok(body)
+}
+
+// Find a header (case insensitively) and then a parameter within it: "dh=...; p256ecdsa=..."
+fn header_param<'a>(headers: &'a [(String, String)], header: &str, param: &str) -> Option<&'a str> {
+	headers.iter()
+		.filter(|(name, _)| name.eq_ignore_ascii_case(header))
+		.flat_map(|(_, value)| value.split([';', ',']))
+		.filter_map(|pair| {
+			let mut parts = pair.trim().splitn(2, '=');
+			match (parts.next(), parts.next()) {
+				(Some(key), Some(value)) if key.trim().eq_ignore_ascii_case(param) => Some(value.trim().trim_matches('"')),
+				_ => None
+			}
+		})
+		.next()
+}
+fn decode_param(headers: &[(String, String)], header: &str, param: &str) -> Result<Vec<u8>, anyhow::Error> {
+	let value = header_param(headers, header, param).ok_or(anyhow!("Missing {} in the {} header", param, header))?;
+	base64::decode_config(value, base64::URL_SAFE_NO_PAD).context("Header parameter wasn't base64url encoded")
+}
+
+fn decrypt_aesgcm(secret: &p256::SecretKey, auth: &[u8; 16], headers: &[(String, String)], body: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+	let server_public = EncodedPoint::from_bytes(decode_param(headers, "crypto-key", "dh")?)
+		.map_err(|_| anyhow!("Sender public key invalid"))?;
+	let server_public = server_public.decompress().ok_or(anyhow!("Failed to decompress the sender public key"))?;
+	let salt = decode_param(headers, "encryption", "salt")?;
+	if salt.len() != 16 {
+		return Err(anyhow!("Salt must be 16 bytes"));
+	}
+	let mut salt_arr = [0; 16];
+	salt_arr.copy_from_slice(&salt);
+
+	let client_public = EncodedPoint::from_secret_key(secret, false);
+	let shared_secret = ecdh(secret, &server_public)?;
+	let (encryption_key, nonce) = aesgcm_keys(auth, &shared_secret, &client_public, &server_public, &salt_arr)?;
 
+	let mut data = Aes128Gcm::new(GenericArray::from_slice(encryption_key.as_ref()))
+		.decrypt(GenericArray::from_slice(nonce.as_ref()), body)
+		.map_err(|_| anyhow!("Decryption failed"))?;
+
+	if data.len() < 2 {
+		return Err(anyhow!("Message too short - padding length"));
+	}
+	let pad_len = BigEndian::read_u16(&data[..2]) as usize;
+	if data.len() < 2 + pad_len {
+		return Err(anyhow!("Padding longer than the message"));
+	}
+	if data[2..2 + pad_len].iter().any(|b| *b != 0) {
+		return Err(anyhow!("Padding wasn't zeros"));
+	}
+	Ok(data.split_off(2 + pad_len))
+}
+
+fn decrypt_aes128gcm(secret: &p256::SecretKey, auth: &[u8; 16], body: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+	if body.len() < 21 {
+		return Err(anyhow!("Message too short - header"));
+	}
+	let (salt, body) = body.split_at(16);
+	let mut salt_arr = [0; 16];
+	salt_arr.copy_from_slice(salt);
+	let (record_size, body) = body.split_at(4);
+	let record_size = BigEndian::read_u32(record_size) as usize;
+	let (id_len, body) = body.split_at(1);
+	let id_len = id_len[0] as usize;
+	if body.len() < id_len {
+		return Err(anyhow!("Message too short - key id"));
+	}
+	let (server_public, body) = body.split_at(id_len);
+	let server_public = EncodedPoint::from_bytes(server_public).map_err(|_| anyhow!("Sender public key invalid"))?;
+	// The smallest record holds a delimiter and the tag.
+	if record_size < 18 {
+		return Err(anyhow!("Record size too small"));
+	}
+
+	let client_public = EncodedPoint::from_secret_key(secret, false);
+	let shared_secret = ecdh(secret, &server_public)?;
+	let (encryption_key, nonce) = aes128gcm_keys(auth, &shared_secret, &client_public, &server_public, &salt_arr)?;
+	let cipher = Aes128Gcm::new(GenericArray::from_slice(encryption_key.as_ref()));
+
+	let mut message = Vec::new();
+	let records: Vec<&[u8]> = body.chunks(record_size).collect();
+	if records.is_empty() {
+		return Err(anyhow!("Message has no records"));
+	}
+	for (seq, record) in records.iter().enumerate() {
+		// Each record's nonce is the base nonce XORed with its sequence number.
+		let mut record_nonce = nonce.clone();
+		for (b, s) in record_nonce[4..].iter_mut().zip((seq as u64).to_be_bytes().iter()) {
+			*b ^= s;
+		}
+		let data = Zeroizing::new(cipher.decrypt(GenericArray::from_slice(record_nonce.as_ref()), *record)
+			.map_err(|_| anyhow!("Decryption failed"))?);
+
+		// Strip the padding and check the delimiter:
+		let delimiter_pos = data.iter().rposition(|b| *b != 0).ok_or(anyhow!("Record missing a delimiter"))?;
+		let last = seq == records.len() - 1;
+		match (data[delimiter_pos], last) {
+			(2, true) | (1, false) => message.extend_from_slice(&data[..delimiter_pos]),
+			_ => return Err(anyhow!("Record had the wrong delimiter"))
+		}
+	}
+	Ok(message)
+}
+
+// Decrypt a push message using the subscription's private key and auth secret.  Headers only need to include content-encoding, and for aesgcm, crypto-key and encryption.
+pub fn decrypt(secret: &p256::SecretKey, auth: &[u8; 16], headers: &[(String, String)], body: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+	let encoding = headers.iter()
+		.find(|(name, _)| name.eq_ignore_ascii_case("content-encoding"))
+		.ok_or(anyhow!("Missing content-encoding header"))?;
+	match ContentEncoding::from_name(encoding.1.trim()) {
+		Some(ContentEncoding::AesGcm) => decrypt_aesgcm(secret, auth, headers, body),
+		Some(ContentEncoding::Aes128Gcm) => decrypt_aes128gcm(secret, auth, body),
+		None => Err(anyhow!("Unsupported content encoding: {}", encoding.1))
+	}
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum AuthorizationForm {
+	// "Authorization: WebPush <jwt>" with the key in "Crypto-Key: p256ecdsa=", from the drafts that went with aesgcm.
+	WebPush,
+	// RFC 8292: "Authorization: vapid t=<jwt>, k=<key>"
+	Vapid
+}
+impl AuthorizationForm {
+	// The form that push services pair with each encoding.
+	pub fn for_encoding(encoding: ContentEncoding) -> Self {
+		match encoding {
+			ContentEncoding::AesGcm => AuthorizationForm::WebPush,
+			ContentEncoding::Aes128Gcm => AuthorizationForm::Vapid
+		}
+	}
+}
+
+// RFC 8030 section 5.3
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Urgency {
+	VeryLow,
+	Low,
+	Normal,
+	High
+}
+impl Urgency {
+	pub fn name(&self) -> &'static str {
+		match self {
+			Urgency::VeryLow => "very-low",
+			Urgency::Low => "low",
+			Urgency::Normal => "normal",
+			Urgency::High => "high"
+		}
+	}
+}
+
+// Push services won't take a bigger body than this.
+pub const MAX_BODY_LEN: usize = 4096;
+// What encryption adds to a message: the aesgcm padding length and tag, or the aes128gcm header, delimiter and tag.
+const fn overhead(encoding: ContentEncoding) -> usize {
+	match encoding {
+		ContentEncoding::AesGcm => 2 + 16,
+		ContentEncoding::Aes128Gcm => 86 + 1 + 16
+	}
+}
+// The longest message that fits whatever the recipient's encoding is (aes128gcm adds the most).
+pub const MAX_MESSAGE_LEN: usize = MAX_BODY_LEN - overhead(ContentEncoding::Aes128Gcm);
+
+// How to hide the size of what we're sending.  Sizes are of the encrypted body.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Padding {
+	None,
+	// Up to a multiple of this many bytes.
+	Multiple(usize),
+	// Up to the smallest of these sizes that fits, or the maximum if none do.
+	Buckets(Vec<usize>),
+	Maximum,
+	// A random amount, up to this many bytes.
+	Random(usize)
+}
+impl Default for Padding {
+	// Enough to make introductions, ICE batches and offers look alike.
+	fn default() -> Self {
+		Padding::Buckets(vec![1024, 2048, 4096])
+	}
+}
+impl Padding {
+	// How much padding to add to a body that would otherwise be `unpadded` bytes long.  Never pads beyond MAX_BODY_LEN.
+	pub fn pad_len(&self, unpadded: usize, rng: &mut impl RngCore) -> usize {
+		let room = MAX_BODY_LEN.saturating_sub(unpadded);
+		let pad_len = match self {
+			Padding::None => 0,
+			Padding::Multiple(0) => 0,
+			Padding::Multiple(multiple) => (multiple - unpadded % multiple) % multiple,
+			Padding::Buckets(sizes) => sizes.iter()
+				.filter(|size| **size >= unpadded)
+				.min()
+				.unwrap_or(&MAX_BODY_LEN)
+				.saturating_sub(unpadded),
+			Padding::Maximum => room,
+			// Uniform, where taking a random u32 modulo the range would favour the low end.
+			Padding::Random(max) => rng.gen_range(0, max.min(&room) + 1)
+		};
+		pad_len.min(room)
+	}
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PushOptions {
+	pub padding: Padding,
+	// Seconds
+	pub ttl: usize,
+	// None picks the form that goes with the recipient's encoding.
+	pub authorization: Option<AuthorizationForm>,
+	// None leaves it up to the push service, which treats it as normal.
+	pub urgency: Option<Urgency>,
+	// A queued message with the same topic is replaced by this one.  At most 32 characters from the base64url alphabet.
+	pub topic: Option<String>
+}
+
+// A push request that doesn't depend on any particular HTTP client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PushRequest {
+	pub url: String,
+	pub method: String,
+	pub headers: Vec<(String, String)>,
+	pub body: Vec<u8>
+}
+impl PushRequest {
+	pub fn header(&self, name: &str) -> Option<&str> {
+		self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+	}
+	// Adapter for fetch in the browser:
+	pub fn to_request_init(&self) -> Result<RequestInit, anyhow::Error> {
+		let headers = Headers::new().and_then(|headers| {
+			for (name, value) in &self.headers {
+				headers.set(name, value)?;
+			}
+			Ok(headers)
+		}).map_err(|_| anyhow!("Failed while setting headers"))?;
+
+		let mut init = RequestInit::new();
+		init.method(&self.method)
+			.headers(&headers)
+			.body(Some(&Uint8Array::from(self.body.as_ref())))
+			.cache(RequestCache::NoStore)
+			.mode(RequestMode::Cors)
+			.referrer("no-referrer");
+		Ok(init)
+	}
+	// Adapter for native code: an HTTP/1.1 request that can be written straight to a connection.
+	pub fn write_http1<W: Write>(&self, mut out: W) -> Result<(), anyhow::Error> {
+		let url = Url::parse(&self.url).context("Endpoint URL parsing failed.")?;
+		let host = url.host_str().ok_or(anyhow!("Endpoint has no host"))?;
+		let path = match url.query() {
+			Some(query) => format!("{}?{}", url.path(), query),
+			None => url.path().to_string()
+		};
+		write!(out, "{} {} HTTP/1.1\r\n", self.method, path)?;
+		match url.port() {
+			Some(port) => write!(out, "host: {}:{}\r\n", host, port)?,
+			None => write!(out, "host: {}\r\n", host)?
+		}
+		for (name, value) in &self.headers {
+			write!(out, "{}: {}\r\n", name, value)?;
+		}
+		write!(out, "content-length: {}\r\n\r\n", self.body.len())?;
+		out.write_all(&self.body)?;
+		Ok(())
+	}
+}
+
+#[allow(clippy::too_many_arguments)]
+fn build_request(recipient: &PushInfo, application_server_pk: &crypto::PublicKey, jwt: &str, server_secret: &p256::SecretKey, salt: &[u8; 16], message: &[u8], options: &PushOptions, rng: &mut impl RngCore) -> Result<PushRequest, anyhow::Error> {
 	// Check size:
-	if data.len() > 4096 {
+	let unpadded = overhead(recipient.encoding) + message.len();
+	if unpadded > MAX_BODY_LEN {
 		return Err(anyhow!("Message too large"));
 	}
+	let pad_len = options.padding.pad_len(unpadded, rng);
 
-	// Fill and check the auth token:
-	let jwt = auth.fill_and_check(recipient, application_server_pk)?;
+	let body = match recipient.encoding {
+		ContentEncoding::AesGcm => encrypt_aesgcm(recipient, server_secret, salt, message, pad_len)?,
+		ContentEncoding::Aes128Gcm => encrypt_aes128gcm(recipient, server_secret, salt, message, pad_len)?
+	};
+
+	// Headers:
+	let application_server_pk = base64::encode_config(application_server_pk.as_bytes(), base64::URL_SAFE_NO_PAD);
+	let authorization = options.authorization.unwrap_or_else(|| AuthorizationForm::for_encoding(recipient.encoding));
+	let mut headers = vec![
+		(String::from("authorization"), match authorization {
+			AuthorizationForm::WebPush => format!("WebPush {}", jwt),
+			AuthorizationForm::Vapid => format!("vapid t={}, k={}", jwt, application_server_pk)
+		}),
+		(String::from("ttl"), options.ttl.to_string()),
+		(String::from("content-type"), String::from("application/octet-stream")),
+		(String::from("content-encoding"), String::from(recipient.encoding.name()))
+	];
+	// The aesgcm sender key goes in crypto-key, as does the signing key for the older authorization form.
+	let mut crypto_key = Vec::new();
+	if recipient.encoding == ContentEncoding::AesGcm {
+		let server_public = EncodedPoint::from_secret_key(server_secret, false);
+		crypto_key.push(format!("dh={}", base64::encode_config(server_public.as_bytes(), base64::URL_SAFE_NO_PAD)));
+		headers.push((String::from("encryption"), format!("salt={}", base64::encode_config(salt, base64::URL_SAFE_NO_PAD))));
+	}
+	if authorization == AuthorizationForm::WebPush {
+		crypto_key.push(format!("p256ecdsa={}", application_server_pk));
+	}
+	if !crypto_key.is_empty() {
+		headers.push((String::from("crypto-key"), crypto_key.join("; ")));
+	}
+	if let Some(urgency) = options.urgency {
+		headers.push((String::from("urgency"), String::from(urgency.name())));
+	}
+	if let Some(ref topic) = options.topic {
+		if topic.is_empty() || topic.len() > 32 || !topic.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_') {
+			return Err(anyhow!("Topic must be 1 to 32 base64url characters"));
+		}
+		headers.push((String::from("topic"), topic.clone()));
+	}
 
-	// ECDH:
-	let ephemeral_key = EphemeralSecret::random(get_rng());
-	let shared_secret = ephemeral_key.diffie_hellman(recipient.public_key.as_ref()).context("Diffie Helman failed")?;
+	Ok(PushRequest {
+		url: recipient.endpoint.clone(),
+		method: String::from("POST"),
+		headers,
+		body
+	})
+}
 
-	// Salt:
+pub fn push(recipient: &PushInfo, application_server_pk: &crypto::PublicKey, auth: &AuthToken, lifetime: &AuthLifetime, now: u32, message: &[u8], options: &PushOptions) -> Result<PushRequest, anyhow::Error> {
+	// Fill and check the auth token:
+	let jwt = auth.fill_and_check(recipient, application_server_pk, lifetime, now)?;
+	push_with_jwt(recipient, application_server_pk, &jwt, message, options)
+}
+// For senders that hold the application server key themselves instead of a peer's authorization.
+pub fn push_with_jwt(recipient: &PushInfo, application_server_pk: &crypto::PublicKey, jwt: &str, message: &[u8], options: &PushOptions) -> Result<PushRequest, anyhow::Error> {
+	// Encrypt the message using a one-off key:
+	let mut rng = get_rng()?;
+	let server_secret = p256::SecretKey::random(&mut rng);
 	let salt = get_salt()?;
+	build_request(recipient, application_server_pk, jwt, &server_secret, &salt, message, options, &mut rng)
+}
 
-	// Pseudo Random Key:
-	let mut prk = [0; 32];
-	Hkdf::<sha2::Sha256>::new(Some(&recipient.auth), shared_secret.as_bytes())
-		.expand("Content-Encoding: auth\0".as_bytes(), &mut prk)
-		.map_err(|_| anyhow!("Failed to expand shared secret into PRK"))?;
+#[cfg(test)]
+mod tests {
+	use super::*;
 
-	// Encryption Key:
-	let mut encryption_key = [0; 16];
-	let ek_info = make_info("aesgcm", &recipient.public_key, &ephemeral_key)?;
-	Hkdf::<sha2::Sha256>::new(Some(&salt), &prk)
-		.expand(&ek_info, &mut encryption_key)
-		.map_err(|_| anyhow!("Failed to expand PRK into encryption key"))?;
+	fn decode(encoded: &str) -> Vec<u8> {
+		base64::decode_config(encoded, base64::URL_SAFE_NO_PAD).unwrap()
+	}
 
-	// Nonce:
-	let mut nonce = [0; 12];
-	let nonce_info = make_info("nonce", &recipient.public_key, &ephemeral_key)?;
-	Hkdf::<sha2::Sha256>::new(Some(&salt), &prk)
-		.expand(&nonce_info, &mut nonce)
-		.map_err(|_| anyhow!("Failed to expand PRK into nonce"))?;
+	// Example from RFC 8291 section 5.
+	#[test]
+	fn aes128gcm_rfc8291_example() {
+		let recipient = PushInfo {
+			endpoint: String::from("https://push.example.net/push/JzLQ3raZJfFBR0aqvOMsLrt54w4rJUsV"),
+			auth: {
+				let mut auth = [0; 16];
+				auth.copy_from_slice(&decode("BTBZMqHH6r4Tts7J_aSIgg"));
+				auth
+			},
+			public_key: EncodedPoint::from_bytes(decode("BCVxsr7N_eNgVRqvHtD0zTZsEc6-VV-JvLexhqUzORcxaOzi6-AYWXvTBHm4bjyPjs7Vd8pZGH6SRpkNtoIAiw4")).unwrap().into(),
+			encoding: ContentEncoding::Aes128Gcm
+		};
+		let server_secret = p256::SecretKey::from_bytes(decode("yfWPiYE-n46HLnH0KqZOF1fJJU3MYrct3AELtAQ-oRw")).unwrap();
+		let mut salt = [0; 16];
+		salt.copy_from_slice(&decode("DGv6ra1nlYgDCS1FRnbzlw"));
 
-	// Encrypt the message:
-	let encrypted = Aes128Gcm::new(&encryption_key.into())
-		.encrypt(&nonce.into(), data.as_ref())
-		.map_err(|_| anyhow!("Encryption failed"))?;
+		let body = encrypt_aes128gcm(&recipient, &server_secret, &salt, "When I grow up, I want to be a watermelon".as_bytes(), 0).unwrap();
 
-	// Headers:
-	let headers = Headers::new().and_then(|headers| 
-		headers.set("authorization", &format!("WebPush {}", jwt)).and(
-			headers.set("crypto-key", &format!(
-				"dh={}; p256ecdsa={}",
-				base64::encode_config(ephemeral_key.public_key().as_bytes(), base64::URL_SAFE_NO_PAD),
-				base64::encode_config(application_server_pk.as_bytes(), base64::URL_SAFE_NO_PAD)
-			))
-		).and(
-			headers.set("encryption", &format!("salt={}", base64::encode_config(&salt, base64::URL_SAFE_NO_PAD)))
-		).and(
-			headers.set("ttl", &ttl.to_string())
-		).and(
-			headers.set("content-type", "application/octet-stream")
-		).and(
-			headers.set("content-encoding", "aesgcm")
-		).map(|_| headers)
-	).map_err(|_| anyhow!("Failed while setting headers"))?;
-
-	// Build a request
-	let mut init = RequestInit::new();
-	init.method("POST")
-		.headers(&headers)
-		.body(Some(&Uint8Array::from(encrypted.as_ref())))
-		.cache(RequestCache::NoStore)
-		.mode(RequestMode::Cors)
-		.referrer("no-referrer");
-
-	Ok((recipient.endpoint.clone(), init))
-}
\ No newline at end of file
+		let mut expected = decode("DGv6ra1nlYgDCS1FRnbzlwAAEABBBP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A8");
+		expected.extend_from_slice(&decode("8pfeW0KbunFT06SuDKoJH9Ql87S1QUrdirN6GcG7sFz1y1sqLgVi1VhjVkHsUoEsbI_0LpXMuGvnzQ"));
+		assert_eq!(body, expected);
+	}
+	// Example from draft-ietf-webpush-encryption-04 appendix A.
+	#[test]
+	fn aesgcm_draft_example() {
+		let recipient = PushInfo {
+			endpoint: String::from("https://push.example.net/push/JzLQ3raZJfFBR0aqvOMsLrt54w4rJUsV"),
+			auth: {
+				let mut auth = [0; 16];
+				auth.copy_from_slice(&decode("R29vIGdvbyBnJyBqb29iIQ"));
+				auth
+			},
+			public_key: EncodedPoint::from_bytes(decode("BCEkBjzL8Z3C-oi2Q7oE5t2Np-p7osjGLg93qUP0wvqRT21EEWyf0cQDQcakQMqz4hQKYOQ3il2nNZct4HgAUQU")).unwrap().into(),
+			encoding: ContentEncoding::AesGcm
+		};
+		let server_secret = p256::SecretKey::from_bytes(decode("nCScek-QpEjmOOlT-rQ38nZzvdPlqa00Zy0i6m2OJvY")).unwrap();
+		let mut salt = [0; 16];
+		salt.copy_from_slice(&decode("lngarbyKfMoi9Z75xYXmkg"));
+
+		let body = encrypt_aesgcm(&recipient, &server_secret, &salt, "I am the walrus".as_bytes(), 0).unwrap();
+		assert_eq!(body, decode("6nqAQUME8hNqw5J3kl8cpVVJylXKYqZOeseZG8UueKpA"));
+
+		// The example isn't padded, so check the pad length's byte order with the same keys.
+		let body = encrypt_aesgcm(&recipient, &server_secret, &salt, "I am the walrus".as_bytes(), 300).unwrap();
+		let client_public = decompress_key(&recipient.public_key).unwrap();
+		let server_public = EncodedPoint::from_secret_key(&server_secret, false);
+		let shared_secret = ecdh(&server_secret, &client_public).unwrap();
+		let (key, nonce) = aesgcm_keys(&recipient.auth, &shared_secret, &client_public, &server_public, &salt).unwrap();
+		let data = Aes128Gcm::new(GenericArray::from_slice(key.as_ref())).decrypt(GenericArray::from_slice(nonce.as_ref()), body.as_ref()).unwrap();
+		assert_eq!(data[..2], [0x01, 0x2c]);
+	}
+	#[test]
+	fn aes128gcm_padding() {
+		let client_secret = p256::SecretKey::random(rand::thread_rng());
+		let recipient = PushInfo {
+			endpoint: String::from("https://push.example.net/push/JzLQ3raZJfFBR0aqvOMsLrt54w4rJUsV"),
+			auth: [7; 16],
+			public_key: EncodedPoint::from_secret_key(&client_secret, false).into(),
+			encoding: ContentEncoding::Aes128Gcm
+		};
+		let server_secret = p256::SecretKey::random(rand::thread_rng());
+		let message = "Hello World!".as_bytes();
+
+		let unpadded = encrypt_aes128gcm(&recipient, &server_secret, &[1; 16], message, 0).unwrap();
+		let padded = encrypt_aes128gcm(&recipient, &server_secret, &[1; 16], message, 100).unwrap();
+		// Header (86) + message + delimiter + padding + tag (16)
+		assert_eq!(unpadded.len(), 86 + message.len() + 1 + 16);
+		assert_eq!(padded.len(), unpadded.len() + 100);
+	}
+
+	fn subscription() -> (p256::SecretKey, PushInfo) {
+		let client_secret = p256::SecretKey::random(rand::thread_rng());
+		let info = PushInfo {
+			endpoint: String::from("https://push.example.net/push/JzLQ3raZJfFBR0aqvOMsLrt54w4rJUsV"),
+			auth: [7; 16],
+			public_key: EncodedPoint::from_secret_key(&client_secret, true).into(),
+			encoding: ContentEncoding::AesGcm
+		};
+		(client_secret, info)
+	}
+	fn aesgcm_headers(server_secret: &p256::SecretKey, salt: &[u8; 16]) -> Vec<(String, String)> {
+		vec![
+			(String::from("Content-Encoding"), String::from("aesgcm")),
+			(String::from("Crypto-Key"), format!(
+				"dh={}; p256ecdsa=BDd3_hVL9fZi9Ybo2UUzA284WG5FZR30_95YeZJsiApwXKpNcF1rRPF3foIiBHXRdJI2Qhumhf6_LFTeZaNndIo",
+				base64::encode_config(EncodedPoint::from_secret_key(server_secret, false).as_bytes(), base64::URL_SAFE_NO_PAD)
+			)),
+			(String::from("Encryption"), format!("salt={}", base64::encode_config(salt, base64::URL_SAFE_NO_PAD)))
+		]
+	}
+
+	#[test]
+	fn aes128gcm_rfc8291_decrypt() {
+		let secret = p256::SecretKey::from_bytes(decode("q1dXpw3UpT5VOmu_cf_v6ih07Aems3njxI-JWgLcM94")).unwrap();
+		let mut auth = [0; 16];
+		auth.copy_from_slice(&decode("BTBZMqHH6r4Tts7J_aSIgg"));
+		let mut body = decode("DGv6ra1nlYgDCS1FRnbzlwAAEABBBP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A8");
+		body.extend_from_slice(&decode("8pfeW0KbunFT06SuDKoJH9Ql87S1QUrdirN6GcG7sFz1y1sqLgVi1VhjVkHsUoEsbI_0LpXMuGvnzQ"));
+		let headers = vec![(String::from("content-encoding"), String::from("aes128gcm"))];
+
+		let message = decrypt(&secret, &auth, &headers, &body).unwrap();
+		assert_eq!(message, "When I grow up, I want to be a watermelon".as_bytes());
+	}
+	#[test]
+	fn aes128gcm_round_trip() {
+		let (client_secret, mut info) = subscription();
+		info.encoding = ContentEncoding::Aes128Gcm;
+		let server_secret = p256::SecretKey::random(rand::thread_rng());
+		let headers = vec![(String::from("content-encoding"), String::from("aes128gcm"))];
+		let message = "Hello World!".as_bytes();
+
+		for pad_len in &[0, 1, 100] {
+			let body = encrypt_aes128gcm(&info, &server_secret, &[3; 16], message, *pad_len).unwrap();
+			assert_eq!(decrypt(&client_secret, &info.auth, &headers, &body).unwrap(), message);
+		}
+	}
+	#[test]
+	fn aesgcm_round_trip() {
+		let (client_secret, info) = subscription();
+		let server_secret = p256::SecretKey::random(rand::thread_rng());
+		let headers = aesgcm_headers(&server_secret, &[3; 16]);
+		let message = "Hello World!".as_bytes();
+
+		// 300 is past 255, where the pad length takes both bytes.
+		for pad_len in &[0, 1, 300] {
+			let body = encrypt_aesgcm(&info, &server_secret, &[3; 16], message, *pad_len).unwrap();
+			assert_eq!(body.len(), 2 + pad_len + message.len() + 16);
+			assert_eq!(decrypt(&client_secret, &info.auth, &headers, &body).unwrap(), message);
+		}
+	}
+	#[test]
+	fn aesgcm_bad_padding() {
+		let (client_secret, info) = subscription();
+		let server_secret = p256::SecretKey::random(rand::thread_rng());
+		let headers = aesgcm_headers(&server_secret, &[3; 16]);
+
+		// A pad length that claims more bytes than there are:
+		let body = encrypt_aesgcm(&info, &server_secret, &[3; 16], &[], 0).unwrap();
+		assert!(decrypt(&client_secret, &info.auth, &headers, &body).is_ok());
+		let client_public = decompress_key(&info.public_key).unwrap();
+		let server_public = EncodedPoint::from_secret_key(&server_secret, false);
+		let shared_secret = ecdh(&server_secret, &client_public).unwrap();
+		let (key, nonce) = aesgcm_keys(&info.auth, &shared_secret, &client_public, &server_public, &[3; 16]).unwrap();
+		let body = Aes128Gcm::new(&(*key).into()).encrypt(&(*nonce).into(), [0u8, 5, 0, 0].as_ref()).unwrap();
+		assert!(decrypt(&client_secret, &info.auth, &headers, &body).is_err());
+	}
+	#[test]
+	fn wrong_auth_fails() {
+		let (client_secret, info) = subscription();
+		let server_secret = p256::SecretKey::random(rand::thread_rng());
+		let headers = aesgcm_headers(&server_secret, &[3; 16]);
+
+		let body = encrypt_aesgcm(&info, &server_secret, &[3; 16], "Hello World!".as_bytes(), 0).unwrap();
+		assert!(decrypt(&client_secret, &[8; 16], &headers, &body).is_err());
+	}
+	#[test]
+	fn aesgcm_request_headers() {
+		let (client_secret, info) = subscription();
+		let server_secret = p256::SecretKey::random(rand::thread_rng());
+		let signer: crypto::PublicKey = EncodedPoint::from_secret_key(&p256::SecretKey::random(rand::thread_rng()), false).into();
+		let message = "Hello World!".as_bytes();
+
+		let request = build_request(&info, &signer, "header.body.signature", &server_secret, &[3; 16], message, &PushOptions { ttl: 60, padding: Padding::None, ..PushOptions::default() }, &mut rand::thread_rng()).unwrap();
+		assert_eq!(request.url, info.endpoint);
+		assert_eq!(request.method, "POST");
+		assert_eq!(request.header("Authorization"), Some("WebPush header.body.signature"));
+		assert_eq!(request.header("ttl"), Some("60"));
+		assert_eq!(request.header("content-type"), Some("application/octet-stream"));
+		assert_eq!(request.header("content-encoding"), Some("aesgcm"));
+		assert_eq!(request.header("crypto-key").unwrap(), format!(
+			"dh={}; p256ecdsa={}",
+			base64::encode_config(EncodedPoint::from_secret_key(&server_secret, false).as_bytes(), base64::URL_SAFE_NO_PAD),
+			base64::encode_config(signer.as_bytes(), base64::URL_SAFE_NO_PAD)
+		));
+		assert_eq!(request.header("encryption"), Some("salt=AwMDAwMDAwMDAwMDAwMDAw"));
+		assert_eq!(request.body.len(), 2 + message.len() + 16);
+		assert_eq!(decrypt(&client_secret, &info.auth, &request.headers, &request.body).unwrap(), message);
+	}
+	#[test]
+	fn aes128gcm_request_headers() {
+		let (client_secret, mut info) = subscription();
+		info.encoding = ContentEncoding::Aes128Gcm;
+		let server_secret = p256::SecretKey::random(rand::thread_rng());
+		let signer: crypto::PublicKey = EncodedPoint::from_secret_key(&p256::SecretKey::random(rand::thread_rng()), false).into();
+		let message = "Hello World!".as_bytes();
+
+		let options = PushOptions { padding: Padding::None, ..PushOptions::default() };
+		let request = build_request(&info, &signer, "header.body.signature", &server_secret, &[3; 16], message, &options, &mut rand::thread_rng()).unwrap();
+		assert_eq!(request.header("content-encoding"), Some("aes128gcm"));
+		assert_eq!(request.header("authorization").unwrap(), format!("vapid t=header.body.signature, k={}", base64::encode_config(signer.as_bytes(), base64::URL_SAFE_NO_PAD)));
+		assert_eq!(request.header("crypto-key"), None);
+		assert_eq!(request.header("encryption"), None);
+		assert_eq!(request.body.len(), 86 + message.len() + 1 + 16);
+		assert_eq!(decrypt(&client_secret, &info.auth, &request.headers, &request.body).unwrap(), message);
+	}
+	#[test]
+	fn oversized_request() {
+		let (_, info) = subscription();
+		let server_secret = p256::SecretKey::random(rand::thread_rng());
+		let signer: crypto::PublicKey = EncodedPoint::from_secret_key(&server_secret, false).into();
+		assert!(build_request(&info, &signer, "jwt", &server_secret, &[3; 16], &[0; 4096], &PushOptions::default(), &mut rand::thread_rng()).is_err());
+	}
+	#[test]
+	fn authorization_forms() {
+		let (_, mut info) = subscription();
+		info.encoding = ContentEncoding::Aes128Gcm;
+		let server_secret = p256::SecretKey::random(rand::thread_rng());
+		let signer: crypto::PublicKey = EncodedPoint::from_secret_key(&server_secret, false).into();
+		let signer_b64 = base64::encode_config(signer.as_bytes(), base64::URL_SAFE_NO_PAD);
+
+		let options = PushOptions { authorization: Some(AuthorizationForm::WebPush), ..PushOptions::default() };
+		let request = build_request(&info, &signer, "jwt", &server_secret, &[3; 16], &[], &options, &mut rand::thread_rng()).unwrap();
+		assert_eq!(request.header("authorization"), Some("WebPush jwt"));
+		assert_eq!(request.header("crypto-key").unwrap(), format!("p256ecdsa={}", signer_b64));
+
+		info.encoding = ContentEncoding::AesGcm;
+		let options = PushOptions { authorization: Some(AuthorizationForm::Vapid), ..PushOptions::default() };
+		let request = build_request(&info, &signer, "jwt", &server_secret, &[3; 16], &[], &options, &mut rand::thread_rng()).unwrap();
+		assert_eq!(request.header("authorization").unwrap(), format!("vapid t=jwt, k={}", signer_b64));
+		assert!(request.header("crypto-key").unwrap().starts_with("dh="));
+		assert!(!request.header("crypto-key").unwrap().contains("p256ecdsa"));
+	}
+	#[test]
+	fn http1_request() {
+		let request = PushRequest {
+			url: String::from("https://push.example.net:8443/push/abc?x=1"),
+			method: String::from("POST"),
+			headers: vec![(String::from("ttl"), String::from("0"))],
+			body: vec![1, 2, 3]
+		};
+		let mut out = Vec::new();
+		request.write_http1(&mut out).unwrap();
+		let mut expected = b"POST /push/abc?x=1 HTTP/1.1\r\nhost: push.example.net:8443\r\nttl: 0\r\ncontent-length: 3\r\n\r\n".to_vec();
+		expected.extend_from_slice(&[1, 2, 3]);
+		assert_eq!(out, expected);
+	}
+	#[test]
+	fn urgency_and_topic() {
+		let (_, info) = subscription();
+		let server_secret = p256::SecretKey::random(rand::thread_rng());
+		let signer: crypto::PublicKey = EncodedPoint::from_secret_key(&server_secret, false).into();
+
+		let request = build_request(&info, &signer, "jwt", &server_secret, &[3; 16], &[], &PushOptions::default(), &mut rand::thread_rng()).unwrap();
+		assert_eq!(request.header("urgency"), None);
+		assert_eq!(request.header("topic"), None);
+
+		let options = PushOptions {
+			urgency: Some(Urgency::VeryLow),
+			topic: Some(String::from("Ab-_09")),
+			..PushOptions::default()
+		};
+		let request = build_request(&info, &signer, "jwt", &server_secret, &[3; 16], &[], &options, &mut rand::thread_rng()).unwrap();
+		assert_eq!(request.header("urgency"), Some("very-low"));
+		assert_eq!(request.header("topic"), Some("Ab-_09"));
+
+		for topic in &["", "has space", "padded==", "abcdefghijklmnopqrstuvwxyz0123456"] {
+			let options = PushOptions { topic: Some(String::from(*topic)), ..PushOptions::default() };
+			assert!(build_request(&info, &signer, "jwt", &server_secret, &[3; 16], &[], &options, &mut rand::thread_rng()).is_err());
+		}
+	}
+	#[test]
+	fn padding_policies() {
+		let mut rng = rand::thread_rng();
+		assert_eq!(Padding::None.pad_len(100, &mut rng), 0);
+		assert_eq!(Padding::Multiple(64).pad_len(100, &mut rng), 28);
+		assert_eq!(Padding::Multiple(64).pad_len(128, &mut rng), 0);
+		assert_eq!(Padding::Buckets(vec![2048, 512]).pad_len(100, &mut rng), 412);
+		assert_eq!(Padding::Buckets(vec![2048, 512]).pad_len(600, &mut rng), 1448);
+		assert_eq!(Padding::Buckets(vec![2048, 512]).pad_len(3000, &mut rng), MAX_BODY_LEN - 3000);
+		assert_eq!(Padding::Maximum.pad_len(100, &mut rng), MAX_BODY_LEN - 100);
+		for _ in 0..100 {
+			assert!(Padding::Random(50).pad_len(100, &mut rng) <= 50);
+			assert!(Padding::Random(5000).pad_len(4000, &mut rng) <= 96);
+		}
+	}
+	#[test]
+	fn padded_body_sizes() {
+		let (client_secret, mut info) = subscription();
+		let server_secret = p256::SecretKey::random(rand::thread_rng());
+		let signer: crypto::PublicKey = EncodedPoint::from_secret_key(&server_secret, false).into();
+		let options = PushOptions { padding: Padding::Buckets(vec![512, 1024]), ..PushOptions::default() };
+
+		for encoding in &[ContentEncoding::AesGcm, ContentEncoding::Aes128Gcm] {
+			info.encoding = *encoding;
+			for (message_len, body_len) in &[(0, 512), (300, 512), (600, 1024), (2000, MAX_BODY_LEN)] {
+				let message = vec![1; *message_len];
+				let request = build_request(&info, &signer, "jwt", &server_secret, &[3; 16], &message, &options, &mut rand::thread_rng()).unwrap();
+				assert_eq!(request.body.len(), *body_len);
+				assert_eq!(decrypt(&client_secret, &info.auth, &request.headers, &request.body).unwrap(), message);
+			}
+			// The largest message that fits whatever the encoding:
+			let options = PushOptions { padding: Padding::Maximum, ..PushOptions::default() };
+			let request = build_request(&info, &signer, "jwt", &server_secret, &[3; 16], &[1; MAX_MESSAGE_LEN], &options, &mut rand::thread_rng()).unwrap();
+			assert_eq!(request.body.len(), MAX_BODY_LEN);
+		}
+	}
+	#[test]
+	fn auth_lifetimes() {
+		let lifetime = AuthLifetime::default();
+		// Just after a slot boundary, and just before one.
+		for now in &[1_601_337_600 + 1, 1_601_337_600 - 1] {
+			let now = *now;
+			let expiration = lifetime.expiration(now, 0).unwrap();
+			assert_eq!(expiration % AUTH_SLOT, 0);
+			assert!(expiration >= now + 12 * HOUR - 5 * 60);
+			assert!(lifetime.check(expiration, now).is_ok());
+			// Later slots only become usable once the earlier ones run out.
+			let next = lifetime.expiration(now, 1).unwrap();
+			assert_eq!(next, expiration + AUTH_SLOT);
+			assert!(lifetime.check(next, now).is_err());
+			assert!(lifetime.check(next, expiration - 5 * 60).is_ok());
+		}
+		let now = 1_601_337_600;
+		assert!(lifetime.check(now + 5 * 60, now).is_err());
+		assert!(lifetime.check(now + 24 * HOUR, now).is_err());
+		assert!(lifetime.check(now - 1, now).is_err());
+
+		// Never more than what push services accept:
+		let long = AuthLifetime { lifetime: 48 * HOUR, clock_skew: 0 };
+		assert!(long.check(now + 24 * HOUR, now).is_ok());
+		assert!(long.check(now + 24 * HOUR + 1, now).is_err());
+		// Shorter than a slot can't be aligned, but still works:
+		let short = AuthLifetime { lifetime: HOUR, clock_skew: 60 };
+		assert_eq!(short.expiration(now + 1, 0).unwrap(), now + 1 + HOUR - 60);
+		let too_short = AuthLifetime { lifetime: 60, clock_skew: 60 };
+		assert!(too_short.expiration(now, 0).is_err());
+
+		// The usable span agrees with check:
+		let expiration = lifetime.expiration(now, 1).unwrap();
+		let (from, until) = (lifetime.usable_from(expiration), lifetime.usable_until(expiration));
+		assert!(lifetime.check(expiration, from - 1).is_err());
+		assert!(lifetime.check(expiration, from).is_ok());
+		assert!(lifetime.check(expiration, until - 1).is_ok());
+		assert!(lifetime.check(expiration, until).is_err());
+	}
+	#[test]
+	fn fill_and_check() {
+		let (_, info) = subscription();
+		let signer = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let lifetime = AuthLifetime::default();
+		let now = 1_601_337_600;
+		let claims = jwt::Claims {
+			aud: jwt::audience(&info.endpoint).unwrap(),
+			exp: lifetime.expiration(now, 0).unwrap(),
+			sub: String::from("mailto:no-reply@example.com")
+		};
+		let auth = AuthToken {
+			signature: claims.sign(&signer, rand::thread_rng()).unwrap(),
+			expiration: claims.exp,
+			subscriber: claims.sub.clone()
+		};
+		let token = auth.fill_and_check(&info, &signer.public_key(), &lifetime, now).unwrap();
+		assert_eq!(token, claims.token(&auth.signature).unwrap());
+		// Hours later it's still good, until it's about to expire.
+		assert!(auth.fill_and_check(&info, &signer.public_key(), &lifetime, now + 6 * HOUR).is_ok());
+		assert!(auth.fill_and_check(&info, &signer.public_key(), &lifetime, claims.exp - 60).is_err());
+		let other = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		assert!(auth.fill_and_check(&info, &other.public_key(), &lifetime, now).is_err());
+	}
+}
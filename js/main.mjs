@@ -4,7 +4,7 @@ import { html, mount, on, ref, apply_expression } from '../extern/js-min/src/tem
 // import './components/tabs.mjs';
 
 import initialize from './initialize.mjs';
-import peer_connection from './peer-connection.mjs';
+import peer_connection, { refresh_authorizations } from './peer-connection.mjs';
 import help from './help.mjs';
 
 function tab_group(on_selected, on_unselected) {
@@ -154,6 +154,20 @@ async function run() {
 				}),
 				step([0], "Create Self Peer", async (step_el) => {
 					const self_peer = new SelfPeer();
+					// Other tabs and the service worker can change our record (rotating or locking the key, new push info), and the peers' (received push info and authorizations).
+					self_peer.set_change_handler(key => {
+						if (key === 'self_peer') {
+							self_peer.reload();
+						} else if (key.startsWith('peer.')) {
+							const peer = signaling_index.get(key.slice('peer.'.length));
+							try {
+								if (peer) peer.reload();
+							} catch (e) {
+								// It was deleted elsewhere.
+								console.warn(e);
+							}
+						}
+					});
 					log.innerText += `Self peer created.  Public key is: ${self_peer.get_public_key()
 						.reduce((v, x) => v + x.toString(16).padStart(2, '0'), '')
 					}\n`
@@ -217,12 +231,18 @@ async function run() {
 							self_peer.set_push_info(
 								new Uint8Array(subscription.getKey('p256dh')),
 								new Uint8Array(subscription.getKey('auth')),
-								subscription.endpoint
+								subscription.endpoint,
+								(PushManager.supportedContentEncodings || []).includes('aes128gcm') ? 'aes128gcm' : 'aesgcm'
 							);
 							log.innerText += `Push subscription information saved to the self peer.`;
 							break;
 						}
 					}
+				}),
+				step([2, 3], "Authorization Refresh", async (step_el, self_peer) => {
+					// Keep every peer able to reach us.  Nothing is sent unless a peer is running low.
+					refresh_authorizations(self_peer).catch(console.error);
+					setInterval(() => refresh_authorizations(self_peer).catch(console.error), 60 * 60 * 1000);
 				})
 			];
 			// Setup:
@@ -352,11 +372,8 @@ async function run() {
 	// 	}
 	// }
 
-	// let keys = Peer.get_all_peer_keys();
-	// for (const key of keys) {
-	// 	if (key.substr(0, 5) == "peer.") {
-	// 		add_peer(Peer.new_from_key(key));
-	// 	}
+	// for (const peer_id of Peer.list(self)) {
+	// 	add_peer(peer_id);
 	// }
 
 	// const ui = {};
@@ -385,8 +402,8 @@ async function run() {
 	// 		})}>Generate</button>
 	// 	</p>
 	// </aside>`);
-	// async function add_peer(key) {
-	// 	const peer = Peer.new_from_key(key);
+	// async function add_peer(peer_id) {
+	// 	const peer = Peer.find(self, peer_id);
 	// 	if (!peer) {
 	// 		console.warn("Couldn't get a peer from that key.");
 	// 		return;
@@ -1,25 +1,38 @@
-import { SignalingMessage } from '../../wasm/debug/client.js';
+import { SignalingMessage, Peer } from '../../wasm/debug/client.js';
 
-export async function try_push(peer, data) {
-	let request, cors_anywhere_request;
+// Tried in order when a push service can't be reached directly (Chrome's doesn't have CORS headers).
+export const push_proxies = [
+	'https://cors-anywhere.herokuapp.com/'
+];
+export async function try_push(peer, self_peer, data) {
+	let delivery;
 	try {
-		const request_init = peer.prepare_raw(data);
-		request = new Request(request_init.url(), request_init.request_init());
-		cors_anywhere_request = new Request(
-			'https://cors-anywhere.herokuapp.com/' + request_init.url(),
-			request_init.request_init()
-		);
-	} catch(e) { console.error(e) }
-	if (request) {
+		delivery = peer.send(self_peer, data, push_proxies);
+	} catch(e) {
+		console.error(e);
+		return false;
+	}
+	await delivery; // If this fails, let the error bubble
+	return true;
+}
+// Tell every peer we know about that our identity key has been rotated.
+export async function announce_succession(self_peer, peers) {
+	const succession = self_peer.get_succession();
+	const results = await Promise.allSettled(peers.map(peer => try_push(peer, self_peer, succession)));
+	return results.every(({status, value}) => status == 'fulfilled' && value);
+}
+// Push fresh authorizations to every peer that's running low on them from us.  Peers that couldn't be reached get another go on the next run.
+export async function refresh_authorizations(self_peer) {
+	for (const refresh of self_peer.refresh_authorizations()) {
+		const peer_id = refresh.peer_id();
 		try {
-			await fetch(request);
-			return true;
-		} catch {
-			await fetch(cors_anywhere_request); // If this fails, let the error bubble
-			return true;
+			const peer = Peer.find(self_peer, peer_id);
+			await peer.send_signaling(self_peer, refresh.message(), 'authorizations', push_proxies);
+		} catch(e) {
+			console.error(e);
+			self_peer.forget_issued(peer_id);
 		}
 	}
-	return false;
 }
 export default function peer_connection(peer, self_peer) {
 	const pc = new RTCPeerConnection({
@@ -32,25 +45,36 @@ export default function peer_connection(peer, self_peer) {
 		iceCandidatePoolSize: 5
 	});
 
+	// Identifies this connection's messages so that newer ones can replace older ones still queued at the push service.
+	const session = Array.from(crypto.getRandomValues(new Uint8Array(8)), b => b.toString(16).padStart(2, '0')).join('');
+	// Each message only carries the candidates found since the last one, so that it stays within a push message however many there are.
 	let signaling = new SignalingMessage();
 	let send_handle = false;
 	const send_delay = 100;
 	function queue_send() {
 		if (!send_handle) {
 			send_handle = setTimeout(async () => {
-				const str = self_peer.package_signaling(signaling, true);
-				await try_push(peer, str);
+				const message = signaling;
 				signaling = new SignalingMessage();
-
 				send_handle = false;
+
+				try {
+					await peer.send_signaling(self_peer, message, session, push_proxies);
+				} catch (e) {
+					console.error(e);
+				}
 			}, send_delay);
 		}
 	}
+	function set_description(kind, sdp) {
+		signaling = new SignalingMessage();
+		signaling.set_sdp(kind, sdp);
+	}
 
 	pc.onnegotiationneeded = async e => {
 		await pc.setLocalDescription(await pc.createOffer());
 		const str = JSON.stringify(pc.localDescription);
-		signaling.set_sdp('offer', str);
+		set_description('offer', str);
 
 		queue_send();
 	};
@@ -77,7 +101,7 @@ export default function peer_connection(peer, self_peer) {
 			await pc.setLocalDescription(await pc.createAnswer());
 
 			const answer_str = JSON.stringify(pc.localDescription);
-			signaling.set_sdp('answer', answer_str);
+			set_description('answer', answer_str);
 
 			queue_send();
 		} else {
@@ -76,7 +76,8 @@ class PeerSelf extends Base {
 				self_peer.set_push_info(
 					new Uint8Array(subscription.getKey('p256dh')),
 					new Uint8Array(subscription.getKey('auth')),
-					subscription.endpoint
+					subscription.endpoint,
+					(PushManager.supportedContentEncodings || []).includes('aes128gcm') ? 'aes128gcm' : 'aesgcm'
 				);
 				break;
 			}
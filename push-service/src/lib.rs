@@ -0,0 +1,559 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use anyhow::{ Context, anyhow };
+use serde::{ Serialize, Deserialize };
+use rand::RngCore;
+
+use client::{crypto, jwt, web_push};
+
+// A stand-in for an RFC 8030 push service, so that signaling can be tested without FCM or autopush.  It hands out subscriptions, checks VAPID on incoming pushes, decrypts them and keeps them until they're fetched or expire.
+
+// The largest body we'll read: a push, or a subscribe request with its JSON.
+const MAX_REQUEST_BODY_LEN: usize = web_push::MAX_BODY_LEN + 1024;
+
+// Returned by Request::read when the body is too large to read, so that the connection can still be answered with a 413.
+#[derive(Debug)]
+pub struct TooLarge;
+impl std::fmt::Display for TooLarge {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "Request body is larger than {} bytes", MAX_REQUEST_BODY_LEN)
+	}
+}
+impl std::error::Error for TooLarge {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+	pub method: String,
+	pub path: String,
+	pub headers: Vec<(String, String)>,
+	pub body: Vec<u8>
+}
+impl Request {
+	pub fn header(&self, name: &str) -> Option<&str> {
+		self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+	}
+	// Read an HTTP/1.1 request.  Returns None if the connection closed before a request started.
+	pub fn read<R: BufRead>(mut input: R) -> Result<Option<Self>, anyhow::Error> {
+		let mut line = String::new();
+		if input.read_line(&mut line)? == 0 {
+			return Ok(None);
+		}
+		let mut parts = line.trim_end().split(' ');
+		let (method, path) = match (parts.next(), parts.next(), parts.next()) {
+			(Some(method), Some(path), Some(_version)) => (method.to_string(), path.to_string()),
+			_ => return Err(anyhow!("Malformed request line"))
+		};
+		let mut headers = Vec::new();
+		loop {
+			line.clear();
+			input.read_line(&mut line)?;
+			let header = line.trim_end();
+			if header.is_empty() {
+				break;
+			}
+			let colon = header.find(':').ok_or(anyhow!("Malformed header"))?;
+			headers.push((header[..colon].trim().to_lowercase(), header[colon + 1..].trim().to_string()));
+		}
+		let mut request = Self { method, path, headers, body: Vec::new() };
+		if let Some(length) = request.header("content-length") {
+			let length = length.parse().context("Malformed content-length")?;
+			// Checked before allocating, since the length is whatever the client claims.
+			if length > MAX_REQUEST_BODY_LEN {
+				return Err(TooLarge.into());
+			}
+			request.body.resize(length, 0);
+			input.read_exact(&mut request.body)?;
+		}
+		Ok(Some(request))
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+	pub status: u16,
+	pub headers: Vec<(String, String)>,
+	pub body: Vec<u8>
+}
+impl Response {
+	fn empty(status: u16) -> Self {
+		Self { status, headers: Vec::new(), body: Vec::new() }
+	}
+	pub fn error(status: u16, message: &str) -> Self {
+		Self {
+			status,
+			headers: vec![(String::from("content-type"), String::from("text/plain"))],
+			body: message.as_bytes().to_vec()
+		}
+	}
+	fn json<T: Serialize>(status: u16, value: &T) -> Self {
+		Self {
+			status,
+			headers: vec![(String::from("content-type"), String::from("application/json"))],
+			body: serde_json::to_vec(value).expect("Responses are always serializable")
+		}
+	}
+	fn reason(&self) -> &'static str {
+		match self.status {
+			200 => "OK",
+			201 => "Created",
+			204 => "No Content",
+			400 => "Bad Request",
+			401 => "Unauthorized",
+			403 => "Forbidden",
+			404 => "Not Found",
+			410 => "Gone",
+			413 => "Payload Too Large",
+			_ => "Unknown"
+		}
+	}
+	pub fn write<W: Write>(&self, mut out: W) -> Result<(), anyhow::Error> {
+		write!(out, "HTTP/1.1 {} {}\r\n", self.status, self.reason())?;
+		// Browser pages talk to us directly, so everything is CORS enabled.
+		write!(out, "access-control-allow-origin: *\r\n")?;
+		write!(out, "access-control-allow-methods: GET, POST, DELETE\r\n")?;
+		write!(out, "access-control-allow-headers: *\r\n")?;
+		write!(out, "access-control-expose-headers: location\r\n")?;
+		for (name, value) in &self.headers {
+			write!(out, "{}: {}\r\n", name, value)?;
+		}
+		write!(out, "content-length: {}\r\nconnection: close\r\n\r\n", self.body.len())?;
+		out.write_all(&self.body)?;
+		Ok(())
+	}
+}
+
+// The shape of PushSubscription.toJSON() in the browser.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SubscriptionInfo {
+	pub endpoint: String,
+	pub keys: SubscriptionKeys
+}
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SubscriptionKeys {
+	pub p256dh: String,
+	pub auth: String
+}
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct SubscribeOptions {
+	application_server_key: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+	pub id: String,
+	// Base64url encoded plaintext
+	pub payload: String,
+	pub ttl: u64,
+	pub urgency: String,
+	pub topic: Option<String>,
+	// Seconds since the epoch
+	pub received: u64
+}
+impl Message {
+	fn expired(&self, now: u64) -> bool {
+		now.saturating_sub(self.received) > self.ttl
+	}
+}
+
+struct Subscription {
+	secret: p256::SecretKey,
+	auth: [u8; 16],
+	// Pushes must be signed by this key if the subscriber gave one.
+	application_server_key: Option<crypto::PublicKey>,
+	messages: Vec<Message>,
+	unsubscribed: bool
+}
+
+fn random_id() -> String {
+	let mut bytes = [0; 16];
+	rand::thread_rng().fill_bytes(&mut bytes);
+	base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+fn decode_key(encoded: &str) -> Result<crypto::PublicKey, anyhow::Error> {
+	let bytes = base64::decode_config(encoded, base64::URL_SAFE_NO_PAD).context("Key wasn't base64url encoded")?;
+	let key: crypto::PublicKey = p256::EncodedPoint::from_bytes(bytes).map_err(|_| anyhow!("Key wasn't an encoded point"))?.into();
+	key.verifying_key()?;
+	Ok(key)
+}
+// Parameters within a header, like "t=..., k=..." or "dh=...; p256ecdsa=..."
+fn param<'a>(value: &'a str, name: &str) -> Option<&'a str> {
+	value.split(&[',', ';'][..])
+		.filter_map(|pair| {
+			let mut parts = pair.trim().splitn(2, '=');
+			match (parts.next(), parts.next()) {
+				(Some(key), Some(value)) if key.trim().eq_ignore_ascii_case(name) => Some(value.trim()),
+				_ => None
+			}
+		})
+		.next()
+}
+
+pub struct Service {
+	// Where we're reachable, which is the audience VAPID tokens have to name.
+	origin: String,
+	subscriptions: HashMap<String, Subscription>
+}
+impl Service {
+	pub fn new(origin: &str) -> Self {
+		Self {
+			origin: origin.trim_end_matches('/').to_string(),
+			subscriptions: HashMap::new()
+		}
+	}
+	pub fn handle(&mut self, request: &Request, now: u64) -> Response {
+		let segments: Vec<&str> = request.path.trim_start_matches('/').split('/').collect();
+		match (request.method.as_str(), segments.as_slice()) {
+			("OPTIONS", _) => Response::empty(204),
+			("POST", ["subscribe"]) => self.subscribe(request),
+			("POST", ["push", id]) => self.push(id, request, now),
+			("DELETE", ["push", id]) => self.unsubscribe(id),
+			("GET", ["messages", id]) => self.messages(id, now),
+			("GET", ["message", id]) => self.message(id, now),
+			("DELETE", ["message", id]) => self.cancel(id, now),
+			_ => Response::error(404, "No such route")
+		}
+	}
+	fn subscribe(&mut self, request: &Request) -> Response {
+		let options: SubscribeOptions = if request.body.is_empty() {
+			SubscribeOptions::default()
+		} else {
+			match serde_json::from_slice(&request.body) {
+				Ok(options) => options,
+				Err(_) => return Response::error(400, "Subscribe options weren't valid JSON")
+			}
+		};
+		let application_server_key = match options.application_server_key.as_deref().map(decode_key) {
+			Some(Ok(key)) => Some(key),
+			Some(Err(_)) => return Response::error(400, "Invalid application server key"),
+			None => None
+		};
+
+		let secret = p256::SecretKey::random(rand::thread_rng());
+		let mut auth = [0; 16];
+		rand::thread_rng().fill_bytes(&mut auth);
+		let id = random_id();
+		let info = SubscriptionInfo {
+			endpoint: format!("{}/push/{}", self.origin, id),
+			keys: SubscriptionKeys {
+				p256dh: base64::encode_config(p256::EncodedPoint::from_secret_key(&secret, false).as_bytes(), base64::URL_SAFE_NO_PAD),
+				auth: base64::encode_config(auth, base64::URL_SAFE_NO_PAD)
+			}
+		};
+		self.subscriptions.insert(id, Subscription {
+			secret,
+			auth,
+			application_server_key,
+			messages: Vec::new(),
+			unsubscribed: false
+		});
+		Response::json(201, &info)
+	}
+	// RFC 8292, or the older WebPush + Crypto-Key form.  Errors are the status to respond with.
+	fn check_vapid(&self, request: &Request, subscription: &Subscription, now: u64) -> Result<(), (u16, &'static str)> {
+		let authorization = request.header("authorization").ok_or((401, "Missing authorization"))?;
+		let (token, key) = if let Some(params) = authorization.strip_prefix("vapid ") {
+			(param(params, "t"), param(params, "k"))
+		} else if let Some(token) = authorization.strip_prefix("WebPush ") {
+			(Some(token.trim()), request.header("crypto-key").and_then(|value| param(value, "p256ecdsa")))
+		} else {
+			return Err((401, "Unsupported authorization scheme"));
+		};
+		let token = token.ok_or((401, "Missing VAPID token"))?;
+		let key = decode_key(key.ok_or((401, "Missing VAPID key"))?).map_err(|_| (401, "Invalid VAPID key"))?;
+
+		let (claims, signature) = jwt::parse(token).map_err(|_| (401, "Malformed VAPID token"))?;
+		claims.verify(&signature, &key).map_err(|_| (401, "VAPID token signature is invalid"))?;
+		if claims.aud != self.origin {
+			return Err((401, "VAPID token is for a different audience"));
+		}
+		let exp = claims.exp as u64;
+		if exp < now || exp > now + web_push::MAX_AUTH_LIFETIME as u64 {
+			return Err((401, "VAPID token is expired or expires too far in the future"));
+		}
+		if let Some(ref expected) = subscription.application_server_key {
+			if expected.compress() != key.compress() {
+				return Err((403, "Signed by a different application server key than the subscription's"));
+			}
+		}
+		Ok(())
+	}
+	fn push(&mut self, id: &str, request: &Request, now: u64) -> Response {
+		let subscription = match self.subscriptions.get(id) {
+			Some(subscription) if subscription.unsubscribed => return Response::error(410, "Subscription is gone"),
+			Some(subscription) => subscription,
+			None => return Response::error(404, "No such subscription")
+		};
+		if let Err((status, message)) = self.check_vapid(request, subscription, now) {
+			return Response::error(status, message);
+		}
+		if request.body.len() > web_push::MAX_BODY_LEN {
+			return Response::error(413, "Payload too large");
+		}
+		let ttl = match request.header("ttl").map(str::parse::<u64>) {
+			Some(Ok(ttl)) => ttl,
+			_ => return Response::error(400, "Missing or invalid TTL")
+		};
+		let urgency = request.header("urgency").unwrap_or("normal").to_string();
+		if !["very-low", "low", "normal", "high"].contains(&urgency.as_str()) {
+			return Response::error(400, "Invalid urgency");
+		}
+		let topic = request.header("topic").map(String::from);
+		let payload = match web_push::decrypt(&subscription.secret, &subscription.auth, &request.headers, &request.body) {
+			Ok(payload) => payload,
+			Err(_) => return Response::error(400, "Payload couldn't be decrypted")
+		};
+
+		let message = Message {
+			id: random_id(),
+			payload: base64::encode_config(&payload, base64::URL_SAFE_NO_PAD),
+			ttl,
+			urgency,
+			topic,
+			received: now
+		};
+		let location = format!("{}/message/{}", self.origin, message.id);
+		let subscription = self.subscriptions.get_mut(id).expect("Subscription was found above");
+		// A message with a topic replaces anything still queued under that topic.
+		if message.topic.is_some() {
+			subscription.messages.retain(|queued| queued.topic != message.topic);
+		}
+		subscription.messages.push(message);
+
+		let mut response = Response::empty(201);
+		response.headers.push((String::from("location"), location));
+		response
+	}
+	fn unsubscribe(&mut self, id: &str) -> Response {
+		match self.subscriptions.get_mut(id) {
+			Some(subscription) => {
+				subscription.unsubscribed = true;
+				subscription.messages.clear();
+				Response::empty(204)
+			},
+			None => Response::error(404, "No such subscription")
+		}
+	}
+	// The message a push's location header points to, while it's still queued.  Fetching it doesn't count as delivering it.
+	fn message(&self, id: &str, now: u64) -> Response {
+		self.subscriptions.values()
+			.flat_map(|subscription| subscription.messages.iter())
+			.find(|message| message.id == id && !message.expired(now))
+			.map(|message| Response::json(200, message))
+			.unwrap_or_else(|| Response::error(404, "No such message"))
+	}
+	// Lets the application server take back a message that hasn't been delivered yet.
+	fn cancel(&mut self, id: &str, now: u64) -> Response {
+		for subscription in self.subscriptions.values_mut() {
+			if let Some(index) = subscription.messages.iter().position(|message| message.id == id && !message.expired(now)) {
+				subscription.messages.remove(index);
+				return Response::empty(204);
+			}
+		}
+		Response::error(404, "No such message")
+	}
+	// Deliver everything that hasn't expired.  Delivered messages are removed, like a user agent acknowledging them.
+	fn messages(&mut self, id: &str, now: u64) -> Response {
+		match self.subscriptions.get_mut(id) {
+			Some(subscription) if subscription.unsubscribed => Response::error(410, "Subscription is gone"),
+			Some(subscription) => {
+				let messages: Vec<Message> = subscription.messages.drain(..)
+					.filter(|message| !message.expired(now))
+					.collect();
+				Response::json(200, &messages)
+			},
+			None => Response::error(404, "No such subscription")
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const ORIGIN: &str = "http://127.0.0.1:8079";
+	const NOW: u64 = 1_601_336_440;
+
+	fn request(method: &str, path: &str, body: &[u8]) -> Request {
+		Request {
+			method: String::from(method),
+			path: String::from(path),
+			headers: Vec::new(),
+			body: body.to_vec()
+		}
+	}
+	fn subscribe(service: &mut Service, application_server: Option<&crypto::SecretKey>, encoding: web_push::ContentEncoding) -> (String, web_push::PushInfo) {
+		let body = application_server.map(|key| format!(
+			r#"{{"applicationServerKey":"{}"}}"#,
+			base64::encode_config(key.public_key().as_bytes(), base64::URL_SAFE_NO_PAD)
+		)).unwrap_or_default();
+		let response = service.handle(&request("POST", "/subscribe", body.as_bytes()), NOW);
+		assert_eq!(response.status, 201);
+		let info: SubscriptionInfo = serde_json::from_slice(&response.body).unwrap();
+		let mut auth = [0; 16];
+		auth.copy_from_slice(&base64::decode_config(&info.keys.auth, base64::URL_SAFE_NO_PAD).unwrap());
+		let path = info.endpoint.trim_start_matches(ORIGIN).to_string();
+		(path, web_push::PushInfo {
+			endpoint: info.endpoint,
+			auth,
+			public_key: decode_key(&info.keys.p256dh).unwrap(),
+			encoding
+		})
+	}
+	fn push_request(info: &web_push::PushInfo, signer: &crypto::SecretKey, exp: u64, message: &[u8], options: &web_push::PushOptions) -> Request {
+		let claims = jwt::Claims {
+			aud: jwt::audience(&info.endpoint).unwrap(),
+			exp: exp as u32,
+			sub: String::from("mailto:no-reply@example.com")
+		};
+		let token = claims.token(&claims.sign(signer, rand::thread_rng()).unwrap()).unwrap();
+		let push = web_push::push_with_jwt(info, &signer.public_key(), &token, message, options).unwrap();
+
+		// Go through the wire format, like a real request would.
+		let mut bytes = Vec::new();
+		push.write_http1(&mut bytes).unwrap();
+		Request::read(bytes.as_slice()).unwrap().unwrap()
+	}
+	fn receive(service: &mut Service, path: &str, now: u64) -> Vec<Message> {
+		let response = service.handle(&request("GET", &path.replace("/push/", "/messages/"), &[]), now);
+		assert_eq!(response.status, 200);
+		serde_json::from_slice(&response.body).unwrap()
+	}
+	fn payload(message: &Message) -> Vec<u8> {
+		base64::decode_config(&message.payload, base64::URL_SAFE_NO_PAD).unwrap()
+	}
+
+	#[test]
+	fn push_and_receive() {
+		let application_server = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		for encoding in &[web_push::ContentEncoding::AesGcm, web_push::ContentEncoding::Aes128Gcm] {
+			let mut service = Service::new(ORIGIN);
+			let (path, info) = subscribe(&mut service, Some(&application_server), *encoding);
+			let options = web_push::PushOptions { ttl: 60, urgency: Some(web_push::Urgency::High), ..web_push::PushOptions::default() };
+			let push = push_request(&info, &application_server, NOW + 60, "Hello World!".as_bytes(), &options);
+
+			let response = service.handle(&push, NOW);
+			assert_eq!(response.status, 201);
+			let messages = receive(&mut service, &path, NOW);
+			assert_eq!(messages.len(), 1);
+			assert_eq!(payload(&messages[0]), "Hello World!".as_bytes());
+			assert_eq!(messages[0].urgency, "high");
+			// Delivered messages aren't delivered again.
+			assert!(receive(&mut service, &path, NOW).is_empty());
+		}
+	}
+	#[test]
+	fn message_resource() {
+		let application_server = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let mut service = Service::new(ORIGIN);
+		let (path, info) = subscribe(&mut service, None, web_push::ContentEncoding::Aes128Gcm);
+		let options = web_push::PushOptions { ttl: 60, ..web_push::PushOptions::default() };
+		let response = service.handle(&push_request(&info, &application_server, NOW + 60, "Hello World!".as_bytes(), &options), NOW);
+		let (_, location) = response.headers.iter().find(|(name, _)| name == "location").unwrap();
+		let location = location.trim_start_matches(ORIGIN);
+
+		let response = service.handle(&request("GET", location, &[]), NOW);
+		assert_eq!(response.status, 200);
+		let message: Message = serde_json::from_slice(&response.body).unwrap();
+		assert_eq!(payload(&message), "Hello World!".as_bytes());
+		// Cancelled before it was delivered:
+		assert_eq!(service.handle(&request("DELETE", location, &[]), NOW).status, 204);
+		assert!(receive(&mut service, &path, NOW).is_empty());
+		assert_eq!(service.handle(&request("GET", location, &[]), NOW).status, 404);
+		assert_eq!(service.handle(&request("DELETE", location, &[]), NOW).status, 404);
+	}
+	#[test]
+	fn vapid_checks() {
+		let application_server = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let mut service = Service::new(ORIGIN);
+		let (_, info) = subscribe(&mut service, Some(&application_server), web_push::ContentEncoding::Aes128Gcm);
+		let options = web_push::PushOptions::default();
+
+		// Some other application server:
+		let other = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let push = push_request(&info, &other, NOW + 60, &[], &options);
+		assert_eq!(service.handle(&push, NOW).status, 403);
+		// Expired:
+		let push = push_request(&info, &application_server, NOW - 1, &[], &options);
+		assert_eq!(service.handle(&push, NOW).status, 401);
+		// Too far in the future:
+		let push = push_request(&info, &application_server, NOW + 25 * 60 * 60, &[], &options);
+		assert_eq!(service.handle(&push, NOW).status, 401);
+		// Signature doesn't match the key:
+		let mut push = push_request(&info, &application_server, NOW + 60, &[], &options);
+		let other = base64::encode_config(other.public_key().as_bytes(), base64::URL_SAFE_NO_PAD);
+		let authorization = push.header("authorization").unwrap().to_string();
+		let authorization = format!("{}k={}", &authorization[..authorization.find("k=").unwrap()], other);
+		push.headers.retain(|(name, _)| name != "authorization");
+		push.headers.push((String::from("authorization"), authorization));
+		assert_eq!(service.handle(&push, NOW).status, 401);
+		// Missing:
+		push.headers.retain(|(name, _)| name != "authorization");
+		assert_eq!(service.handle(&push, NOW).status, 401);
+	}
+	#[test]
+	fn webpush_authorization_form() {
+		let application_server = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let mut service = Service::new(ORIGIN);
+		let (_, info) = subscribe(&mut service, None, web_push::ContentEncoding::AesGcm);
+		let options = web_push::PushOptions { authorization: Some(web_push::AuthorizationForm::WebPush), ..web_push::PushOptions::default() };
+		let push = push_request(&info, &application_server, NOW + 60, &[], &options);
+		assert_eq!(service.handle(&push, NOW).status, 201);
+	}
+	#[test]
+	fn topics_replace() {
+		let application_server = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let mut service = Service::new(ORIGIN);
+		let (path, info) = subscribe(&mut service, None, web_push::ContentEncoding::Aes128Gcm);
+		let options = web_push::PushOptions { ttl: 60, topic: Some(String::from("ice")), ..web_push::PushOptions::default() };
+		for message in &["first", "second"] {
+			let push = push_request(&info, &application_server, NOW + 60, message.as_bytes(), &options);
+			assert_eq!(service.handle(&push, NOW).status, 201);
+		}
+		let push = push_request(&info, &application_server, NOW + 60, "untopical".as_bytes(), &web_push::PushOptions { ttl: 60, ..web_push::PushOptions::default() });
+		assert_eq!(service.handle(&push, NOW).status, 201);
+
+		let payloads: Vec<Vec<u8>> = receive(&mut service, &path, NOW).iter().map(payload).collect();
+		assert_eq!(payloads, vec!["second".as_bytes().to_vec(), "untopical".as_bytes().to_vec()]);
+	}
+	#[test]
+	fn ttl_expiry() {
+		let application_server = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let mut service = Service::new(ORIGIN);
+		let (path, info) = subscribe(&mut service, None, web_push::ContentEncoding::Aes128Gcm);
+		for ttl in &[0, 30, 120] {
+			let options = web_push::PushOptions { ttl: *ttl, ..web_push::PushOptions::default() };
+			let push = push_request(&info, &application_server, NOW + 60, ttl.to_string().as_bytes(), &options);
+			assert_eq!(service.handle(&push, NOW).status, 201);
+		}
+		let payloads: Vec<Vec<u8>> = receive(&mut service, &path, NOW + 60).iter().map(payload).collect();
+		assert_eq!(payloads, vec!["120".as_bytes().to_vec()]);
+	}
+	#[test]
+	fn gone_and_missing() {
+		let application_server = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let mut service = Service::new(ORIGIN);
+		let (path, info) = subscribe(&mut service, None, web_push::ContentEncoding::Aes128Gcm);
+		let push = push_request(&info, &application_server, NOW + 60, &[], &web_push::PushOptions::default());
+
+		assert_eq!(service.handle(&request("DELETE", &path, &[]), NOW).status, 204);
+		assert_eq!(service.handle(&push, NOW).status, 410);
+		assert_eq!(service.handle(&request("POST", "/push/nobody", &push.body), NOW).status, 404);
+	}
+	#[test]
+	fn undecryptable() {
+		let application_server = crypto::SecretKey::from(p256::SecretKey::random(rand::thread_rng()));
+		let mut service = Service::new(ORIGIN);
+		let (_, info) = subscribe(&mut service, None, web_push::ContentEncoding::Aes128Gcm);
+		let mut push = push_request(&info, &application_server, NOW + 60, "Hello World!".as_bytes(), &web_push::PushOptions::default());
+		let last = push.body.len() - 1;
+		push.body[last] ^= 1;
+		assert_eq!(service.handle(&push, NOW).status, 400);
+	}
+	#[test]
+	fn oversized_request() {
+		let head = "POST /push/someone HTTP/1.1\r\ncontent-length: 1000000000000\r\n\r\n";
+		let e = Request::read(head.as_bytes()).unwrap_err();
+		assert!(e.is::<TooLarge>());
+	}
+}
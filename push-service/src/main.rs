@@ -0,0 +1,43 @@
+use std::io::BufReader;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::thread;
+
+use push_service::{Request, Response, Service, TooLarge};
+
+// Usage: push-service [address]
+// Point the page at http://<address>/subscribe instead of PushManager.subscribe, and read messages from /messages/<id>.
+fn main() -> Result<(), anyhow::Error> {
+	let address = std::env::args().nth(1).unwrap_or_else(|| String::from("127.0.0.1:8079"));
+	let listener = TcpListener::bind(&address)?;
+	let service = Arc::new(Mutex::new(Service::new(&format!("http://{}", listener.local_addr()?))));
+	println!("Push service listening on http://{}", listener.local_addr()?);
+
+	for stream in listener.incoming() {
+		let stream = stream?;
+		let service = service.clone();
+		thread::spawn(move || {
+			if let Err(e) = serve(stream, &service) {
+				eprintln!("Connection failed: {}", e);
+			}
+		});
+	}
+	Ok(())
+}
+
+fn serve(stream: TcpStream, service: &Mutex<Service>) -> Result<(), anyhow::Error> {
+	let request = match Request::read(BufReader::new(&stream)) {
+		Ok(Some(request)) => request,
+		Ok(None) => return Ok(()),
+		Err(e) if e.is::<TooLarge>() => {
+			println!("Refused a request: {}", e);
+			return Response::error(413, "Payload too large").write(&stream);
+		},
+		Err(e) => return Err(e)
+	};
+	let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+	let response = service.lock().expect("Service lock was poisoned").handle(&request, now);
+	println!("{} {} -> {}", request.method, request.path, response.status);
+	response.write(&stream)
+}